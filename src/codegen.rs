@@ -1,55 +1,218 @@
-use crate::parser::{BinaryOp, Expr, Func, Value};
+use crate::parser::{
+    map_entries, tuple_items, BinaryOp, Enum, Expr, Key, Num, Pattern, Program, Span, Spanned,
+    UnaryOp, Value,
+};
 use anyhow::{bail, Result};
 use std::{
-    collections::HashMap,
-    fmt::{self, format},
-    path::Display,
-    pin::Pin,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    rc::Rc,
 };
 
 #[derive(Debug)]
 pub struct RelativeOperation {
     pub bytecode_op: ByteCodeOp,
+    /// Source of the operation, for the ones whose errors are reported with a location
+    pub span: Option<Span>,
 }
 
 impl RelativeOperation {
     fn new(bop_type: ByteCodeOp) -> Self {
         RelativeOperation {
             bytecode_op: bop_type,
+            span: None,
+        }
+    }
+
+    fn spanned(bop_type: ByteCodeOp, span: Span) -> Self {
+        RelativeOperation {
+            bytecode_op: bop_type,
+            span: Some(span),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum ByteCodeValue {
+    Int(i64),
     Number(f64),
     Boolean(bool),
     String(String),
-    List(Vec<ByteCodeValue>),
+    /// Shared like the lists of the AST evaluator
+    List(Rc<RefCell<Vec<ByteCodeValue>>>),
+    /// An instance of the named struct, shared like lists
+    Struct(String, Rc<RefCell<BTreeMap<String, ByteCodeValue>>>),
+    /// A variant of an enum with its index in the enum's declaration and its payload
+    Variant {
+        name: String,
+        variant: String,
+        tag: usize,
+        vals: Vec<ByteCodeValue>,
+    },
+    /// Tuples can't be changed, so they are copied instead of shared
+    Tuple(Vec<ByteCodeValue>),
+    /// Shared like lists, ordered by key
+    Map(Rc<RefCell<BTreeMap<Key, ByteCodeValue>>>),
+    Null,
+    /// A function referred to by its name
+    Func(String),
+    /// A local variable that closures capture and that is assigned, shared between the function and its closures
+    Cell(Rc<RefCell<ByteCodeValue>>),
+    /// A closure, the name of the function it was compiled to with the values it captured
+    Closure(String, Rc<Vec<ByteCodeValue>>),
+    Iter(ByteCodeIter),
     Return,
 }
 
+/// State of a running `for` loop, kept in a local slot of the function the loop is in
+#[derive(Debug, Clone, PartialEq)]
+pub enum ByteCodeIter {
+    List(Rc<RefCell<Vec<ByteCodeValue>>>, usize),
+    Range {
+        next: Num,
+        end: Num,
+        inclusive: bool,
+    },
+}
+
+/// An integer equals the float with the same value
+impl PartialEq for ByteCodeValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ByteCodeValue::Boolean(a), ByteCodeValue::Boolean(b)) => a == b,
+            (ByteCodeValue::String(a), ByteCodeValue::String(b)) => a == b,
+            (ByteCodeValue::List(a), ByteCodeValue::List(b)) => a == b,
+            (ByteCodeValue::Struct(a, a_fields), ByteCodeValue::Struct(b, b_fields)) => {
+                a == b && a_fields == b_fields
+            }
+            (
+                ByteCodeValue::Variant {
+                    name: a,
+                    tag: a_tag,
+                    vals: a_vals,
+                    ..
+                },
+                ByteCodeValue::Variant {
+                    name: b,
+                    tag: b_tag,
+                    vals: b_vals,
+                    ..
+                },
+            ) => a == b && a_tag == b_tag && a_vals == b_vals,
+            (ByteCodeValue::Tuple(a), ByteCodeValue::Tuple(b)) => a == b,
+            (ByteCodeValue::Map(a), ByteCodeValue::Map(b)) => a == b,
+            (ByteCodeValue::Null, ByteCodeValue::Null) => true,
+            (ByteCodeValue::Func(a), ByteCodeValue::Func(b)) => a == b,
+            (ByteCodeValue::Cell(a), ByteCodeValue::Cell(b)) => a == b,
+            (ByteCodeValue::Closure(a, a_captures), ByteCodeValue::Closure(b, b_captures)) => {
+                a == b && a_captures == b_captures
+            }
+            (ByteCodeValue::Iter(a), ByteCodeValue::Iter(b)) => a == b,
+            (ByteCodeValue::Return, ByteCodeValue::Return) => true,
+            _ => matches!((self.num(), other.num()), (Some(a), Some(b)) if a == b),
+        }
+    }
+}
+
 impl fmt::Display for ByteCodeValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            ByteCodeValue::Int(v) => write!(f, "{}", v),
             ByteCodeValue::Number(v) => write!(f, "{}", v),
             ByteCodeValue::Boolean(v) => write!(f, "{}", v),
             ByteCodeValue::String(v) => write!(f, "{}", v),
-            ByteCodeValue::List(v) => write!(f, "{:?}", v),
+            ByteCodeValue::List(v) => write!(
+                f,
+                "[{}]",
+                v.borrow()
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ByteCodeValue::Struct(name, fields) => write!(
+                f,
+                "{} {{ {} }}",
+                name,
+                fields
+                    .borrow()
+                    .iter()
+                    .map(|(field, x)| format!("{}: {}", field, x))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ByteCodeValue::Variant {
+                name,
+                variant,
+                vals,
+                ..
+            } if vals.is_empty() => write!(f, "{}::{}", name, variant),
+            ByteCodeValue::Variant {
+                name,
+                variant,
+                vals,
+                ..
+            } => write!(
+                f,
+                "{}::{}({})",
+                name,
+                variant,
+                vals.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ByteCodeValue::Tuple(vals) => write!(f, "({})", tuple_items(vals)),
+            ByteCodeValue::Map(entries) => write!(f, "{{{}}}", map_entries(&entries.borrow())),
+            ByteCodeValue::Null => write!(f, "null"),
+            ByteCodeValue::Func(name) => write!(f, "<function: {}>", name),
+            ByteCodeValue::Cell(val) => write!(f, "{}", val.borrow()),
+            ByteCodeValue::Closure(..) => write!(f, "<closure>"),
+            ByteCodeValue::Iter(_) => write!(f, "Iterator"),
             ByteCodeValue::Return => write!(f, "Return"),
         }
     }
 }
 
-impl From<&Value> for ByteCodeValue {
-    fn from(value: &Value) -> Self {
-        match value {
-            Value::Null => panic!("Wtf converstion from &Val to BopVal failed"),
-            Value::Bool(b) => ByteCodeValue::Boolean(*b),
-            Value::Num(n) => ByteCodeValue::Number(*n),
-            Value::Str(sr) => ByteCodeValue::String(sr.clone()),
-            Value::List(l) => ByteCodeValue::List(l.into_iter().map(|a| a.into()).collect()),
-            Value::Func(fp) => panic!("Wtf converstion from &Val to BopVal failed"),
+impl From<&Key> for ByteCodeValue {
+    fn from(key: &Key) -> Self {
+        match key {
+            Key::Bool(b) => ByteCodeValue::Boolean(*b),
+            Key::Int(n) => ByteCodeValue::Int(*n),
+            Key::Num(n) => ByteCodeValue::Number(*n),
+            Key::Str(sr) => ByteCodeValue::String(sr.clone()),
+        }
+    }
+}
+
+impl ByteCodeValue {
+    /// The map key for the value, `None` if it can't be one
+    pub fn key(&self) -> Option<Key> {
+        match self {
+            ByteCodeValue::Boolean(b) => Key::new(&Value::Bool(*b)),
+            ByteCodeValue::Int(n) => Key::new(&Value::Int(*n)),
+            ByteCodeValue::Number(n) => Key::new(&Value::Num(*n)),
+            ByteCodeValue::String(sr) => Key::new(&Value::Str(sr.clone())),
+            _ => None,
+        }
+    }
+
+    /// The value as a number, `None` if it is none
+    pub fn num(&self) -> Option<Num> {
+        match self {
+            ByteCodeValue::Int(n) => Some(Num::Int(*n)),
+            ByteCodeValue::Number(x) => Some(Num::Float(*x)),
+            _ => None,
+        }
+    }
+}
+
+impl From<Num> for ByteCodeValue {
+    fn from(num: Num) -> Self {
+        match num {
+            Num::Int(n) => ByteCodeValue::Int(n),
+            Num::Float(x) => ByteCodeValue::Number(x),
         }
     }
 }
@@ -59,22 +222,99 @@ pub enum ByteCodeOp {
     Return,
     LocalGet(usize),
     LocalSet(usize),
+    /// Pops a value and pushes a new cell holding it
+    MakeCell,
+    /// Pushes the value in the cell in the local slot
+    CellGet(usize),
+    /// Pops a value and stores it in the cell in the local slot
+    CellSet(usize),
+    /// Pushes the value of the global in the slot
+    GlobalGet(usize),
+    /// Pops a value and stores it in the global's slot
+    GlobalSet(usize),
     Const(ByteCodeValue),
     Add,
     Sub,
     Div,
     Mul,
+    Mod,
+    Neg,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    /// Pops an index and a list and pushes the list's element, or a key and a map and pushes its value
     ListAt,
+    /// Pops the given number of values and pushes a list of them, in the order they were pushed
+    MakeList(usize),
+    /// Pops a value, an index and a list and sets the list's element to the value.
+    /// With a key and a map instead, inserts the value under the key.
+    ListSet,
+    /// Pops the given number of values and pushes a tuple of them, in the order they were pushed
+    MakeTuple(usize),
+    /// Pops a tuple and pushes its items in order, if it has the given number of them
+    UnpackTuple(usize),
+    /// Pops the given number of keys and values, pushed in pairs, and pushes a map of them
+    MakeMap(usize),
+    /// Pops a map and pushes a list of its keys
+    MapKeys,
+    /// Pops a key and a map and pushes whether the map has the key
+    MapContains,
+    /// Pops a key and a map, removes the key and pushes its value
+    MapRemove,
+    /// Pops a value for each field, in the given order, and pushes an instance of the named struct
+    MakeStruct(String, Vec<String>),
+    /// Pops a struct and pushes the value of its field
+    GetField(String),
+    /// Pops the payload of the variant and pushes the variant, tagged with its index in the enum
+    MakeVariant {
+        name: String,
+        variant: String,
+        tag: usize,
+        len: usize,
+    },
+    /// Pops a variant and pushes whether it has the tag
+    IsVariant(usize),
+    /// Pops a variant and pushes the value of its payload at the index
+    VariantField(usize),
+    /// Pops a variant and jumps to the label at the index of its tag
+    JumpTable(Vec<String>),
+    /// Pops a value and a struct and sets the struct's field to the value
+    SetField(String),
+    ListLen,
+    ListPush,
+    ListPop,
+    Concat,
     LowerT,
     GreaterT,
+    LowerEq,
+    GreaterEq,
     Equal,
     NotEq,
+    Not,
     Call(String, usize),
+    /// Pops the arguments and then the function or closure and calls it
+    CallIndirect(usize),
+    /// Pops the captured values and pushes a closure of the named function
+    MakeClosure(String, usize),
     Print,
     Jump(String),
     JumpTrue(String),
     JumpFalse(String),
     Label(String),
+    /// Pops a list and stores an iterator over it in the local slot. Maps are iterated over their keys.
+    IterList(usize),
+    /// Pops the end and start of a range and stores an iterator over it in the local slot, the flag makes it inclusive
+    IterRange(usize, bool),
+    /// Pushes the next item of the iterator in the local slot, or jumps to the label once it is exhausted
+    IterNext(usize, String),
+    /// Stores the height of the value stack in the local slot, when a loop starts
+    SaveStack(usize),
+    /// Drops the values pushed since the height in the local slot was saved, except for the given number
+    /// of values on top. `break` and `continue` leave the expressions around them in the loop body unfinished.
+    Truncate(usize, usize),
     Pop,
     End,
 }
@@ -92,81 +332,462 @@ impl ByteCodeFunction {
     }
 }
 
+/// What stays the same while generating the code of one function
+struct Context<'a> {
+    method_name: &'a str,
+    enums: &'a HashMap<String, Enum>,
+    /// Functions generated for the closures found so far
+    closures: &'a RefCell<Vec<ByteCodeFunction>>,
+    /// Values of the constants and globals. Constants are put in wherever they are used, globals start out with them.
+    values: &'a HashMap<String, Value>,
+    /// Slot of every global
+    globals: &'a HashMap<String, usize>,
+    /// Local variables that are kept in cells, see `shared_vars`
+    cells: &'a HashSet<String>,
+}
+
+impl Context<'_> {
+    /// Index of a variant in its enum's declaration, which is what jump tables are indexed by
+    fn tag(&self, name: &str, variant: &str) -> usize {
+        self.enums[name]
+            .variants
+            .iter()
+            .position(|v| v.name.0 == variant)
+            .expect("variants are resolved before codegen")
+    }
+
+    /// The runtime value of a value that was known at compile time
+    fn constant(&self, value: &Value) -> ByteCodeValue {
+        match value {
+            Value::Null => ByteCodeValue::Null,
+            Value::Bool(b) => ByteCodeValue::Boolean(*b),
+            Value::Int(n) => ByteCodeValue::Int(*n),
+            Value::Num(n) => ByteCodeValue::Number(*n),
+            Value::Str(sr) => ByteCodeValue::String(sr.clone()),
+            Value::List(l) => ByteCodeValue::List(Rc::new(RefCell::new(
+                l.borrow().iter().map(|a| self.constant(a)).collect(),
+            ))),
+            Value::Struct(name, fields) => ByteCodeValue::Struct(
+                name.clone(),
+                Rc::new(RefCell::new(
+                    fields
+                        .borrow()
+                        .iter()
+                        .map(|(field, a)| (field.clone(), self.constant(a)))
+                        .collect(),
+                )),
+            ),
+            Value::Variant(name, variant, vals) => ByteCodeValue::Variant {
+                name: name.clone(),
+                variant: variant.clone(),
+                tag: self.tag(name, variant),
+                vals: vals.iter().map(|a| self.constant(a)).collect(),
+            },
+            Value::Tuple(vals) => {
+                ByteCodeValue::Tuple(vals.iter().map(|a| self.constant(a)).collect())
+            }
+            Value::Map(entries) => ByteCodeValue::Map(Rc::new(RefCell::new(
+                entries
+                    .borrow()
+                    .iter()
+                    .map(|(key, a)| (key.clone(), self.constant(a)))
+                    .collect(),
+            ))),
+            Value::Func(name) => ByteCodeValue::Func(name.clone()),
+            Value::Closure(_) => {
+                unreachable!("closures only exist while evaluating globals, which can't hold them")
+            }
+        }
+    }
+}
+
+/// Names of the variables that closures capture and that are assigned somewhere, in the function or in a closure.
+/// Those are kept in cells, so the function and its closures see each other's assignments. Going by name can
+/// put more variables into cells than needed, which costs a little speed but doesn't change what they hold.
+fn shared_vars(body: &Expr) -> HashSet<String> {
+    fn collect(expr: &Expr, captured: &mut HashSet<String>, assigned: &mut HashSet<String>) {
+        match expr {
+            Expr::Closure(..) => captured.extend(expr.free_vars()),
+            Expr::Assign(name, _, _) => {
+                assigned.insert(name.clone());
+            }
+            _ => {}
+        }
+        for child in expr.children() {
+            collect(&child.0, captured, assigned);
+        }
+    }
+    let (mut captured, mut assigned) = (HashSet::new(), HashSet::new());
+    collect(body, &mut captured, &mut assigned);
+    captured.intersection(&assigned).cloned().collect()
+}
+
+/// Pops a value into the local slot of a newly bound variable, wrapped in a cell if closures share it
+fn bind_local(name: &str, slot: usize, ctx: &Context, operations: &mut Vec<RelativeOperation>) {
+    if ctx.cells.contains(name) {
+        operations.push(RelativeOperation::new(ByteCodeOp::MakeCell));
+    }
+    operations.push(RelativeOperation::new(ByteCodeOp::LocalSet(slot)));
+}
+
+/// Pushes the part of a value that a pattern at `path` looks at, each step going into the payload of a variant
+fn load_path(slot: usize, path: &[usize], operations: &mut Vec<RelativeOperation>) {
+    operations.push(RelativeOperation::new(ByteCodeOp::LocalGet(slot)));
+    for i in path {
+        operations.push(RelativeOperation::new(ByteCodeOp::VariantField(*i)));
+    }
+}
+
+/// Emits the tests of a pattern against the value in `slot`, jumping to `fail` if one of them fails.
+/// Collects the variables the pattern binds with the path to their value.
+fn pattern_tests(
+    pat: &Pattern,
+    slot: usize,
+    path: &mut Vec<usize>,
+    fail: &str,
+    ctx: &Context,
+    bindings: &mut Vec<(String, Vec<usize>)>,
+    operations: &mut Vec<RelativeOperation>,
+) {
+    match pat {
+        Pattern::Wildcard => {}
+        Pattern::Bind(name) => bindings.push((name.clone(), path.clone())),
+        Pattern::Value(lit) => {
+            load_path(slot, path, operations);
+            operations.push(RelativeOperation::new(ByteCodeOp::Const(ctx.constant(lit))));
+            operations.push(RelativeOperation::new(ByteCodeOp::Equal));
+            operations.push(RelativeOperation::new(ByteCodeOp::JumpFalse(
+                fail.to_string(),
+            )));
+        }
+        Pattern::Variant((name, _), (variant, _), pats) => {
+            // The jump table already decided the variant of the matched value itself
+            if !path.is_empty() {
+                load_path(slot, path, operations);
+                operations.push(RelativeOperation::new(ByteCodeOp::IsVariant(
+                    ctx.tag(name, variant),
+                )));
+                operations.push(RelativeOperation::new(ByteCodeOp::JumpFalse(
+                    fail.to_string(),
+                )));
+            }
+            for (i, (pat, _)) in pats.iter().enumerate() {
+                path.push(i);
+                pattern_tests(pat, slot, path, fail, ctx, bindings, operations);
+                path.pop();
+            }
+        }
+    }
+}
+
 fn generate_function_bytecode(
     expr: &Expr,
     mut store_ct: usize,
-    mut label_ctr: usize,
-    method_name: &str,
+    label_ctr: &mut usize,
+    // Label id of the innermost loop and the slot with the stack height it started at
+    loop_id: Option<(usize, usize)>,
+    ctx: &Context,
     mem_store: &mut HashMap<String, usize>,
     operations: &mut Vec<RelativeOperation>,
 ) {
+    let method_name = ctx.method_name;
     match expr {
         Expr::Error => unreachable!(),
         Expr::Value(val) => match val {
-            Value::Null => {}
+            Value::Null => operations.push(RelativeOperation::new(ByteCodeOp::Const(
+                ByteCodeValue::Null,
+            ))),
             Value::Bool(bool) => operations.push(RelativeOperation::new(ByteCodeOp::Const(
                 ByteCodeValue::Boolean(*bool),
             ))),
+            Value::Int(num) => operations.push(RelativeOperation::new(ByteCodeOp::Const(
+                ByteCodeValue::Int(*num),
+            ))),
             Value::Num(num) => operations.push(RelativeOperation::new(ByteCodeOp::Const(
                 ByteCodeValue::Number(*num),
             ))),
             Value::Str(str) => operations.push(RelativeOperation::new(ByteCodeOp::Const(
                 ByteCodeValue::String(str.clone()),
             ))),
-            Value::List(list) => operations.push(RelativeOperation::new(ByteCodeOp::Const(
-                ByteCodeValue::List(list.into_iter().map(|val| val.into()).collect()),
-            ))),
-            Value::Func(fp) => println!("When am I called {:?}", fp),
+            // Lists are built on every evaluation, a constant would be shared by all of them
+            Value::List(list) => {
+                for item in list.borrow().iter() {
+                    generate_function_bytecode(
+                        &Expr::Value(item.clone()),
+                        store_ct,
+                        label_ctr,
+                        loop_id,
+                        ctx,
+                        mem_store,
+                        operations,
+                    );
+                }
+                operations.push(RelativeOperation::new(ByteCodeOp::MakeList(
+                    list.borrow().len(),
+                )));
+            }
+            Value::Struct(name, fields) => {
+                for item in fields.borrow().values() {
+                    generate_function_bytecode(
+                        &Expr::Value(item.clone()),
+                        store_ct,
+                        label_ctr,
+                        loop_id,
+                        ctx,
+                        mem_store,
+                        operations,
+                    );
+                }
+                operations.push(RelativeOperation::new(ByteCodeOp::MakeStruct(
+                    name.clone(),
+                    fields.borrow().keys().cloned().collect(),
+                )));
+            }
+            Value::Tuple(vals) => {
+                for val in vals {
+                    generate_function_bytecode(
+                        &Expr::Value(val.clone()),
+                        store_ct,
+                        label_ctr,
+                        loop_id,
+                        ctx,
+                        mem_store,
+                        operations,
+                    );
+                }
+                operations.push(RelativeOperation::new(ByteCodeOp::MakeTuple(vals.len())));
+            }
+            // Built on every evaluation like lists
+            Value::Map(entries) => {
+                for (key, val) in entries.borrow().iter() {
+                    for val in [Value::from(key), val.clone()] {
+                        generate_function_bytecode(
+                            &Expr::Value(val),
+                            store_ct,
+                            label_ctr,
+                            loop_id,
+                            ctx,
+                            mem_store,
+                            operations,
+                        );
+                    }
+                }
+                operations.push(RelativeOperation::new(ByteCodeOp::MakeMap(
+                    entries.borrow().len(),
+                )));
+            }
+            // Only constants are evaluated to variants
+            Value::Variant(name, variant, vals) => {
+                for val in vals {
+                    generate_function_bytecode(
+                        &Expr::Value(val.clone()),
+                        store_ct,
+                        label_ctr,
+                        loop_id,
+                        ctx,
+                        mem_store,
+                        operations,
+                    );
+                }
+                operations.push(RelativeOperation::new(ByteCodeOp::MakeVariant {
+                    name: name.clone(),
+                    variant: variant.clone(),
+                    tag: ctx.tag(name, variant),
+                    len: vals.len(),
+                }));
+            }
+            Value::Func(_) | Value::Closure(_) => {
+                operations.push(RelativeOperation::new(ByteCodeOp::Const(ctx.constant(val))))
+            }
         },
-        Expr::List(_) => todo!(),
-        Expr::LocalVar(varname) => operations.push(RelativeOperation::new(ByteCodeOp::LocalGet(
-            *mem_store.get(varname).unwrap(),
-        ))),
+        Expr::List(items) => {
+            for item in items {
+                generate_function_bytecode(
+                    &item.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
+            operations.push(RelativeOperation::new(ByteCodeOp::MakeList(items.len())));
+        }
+        Expr::Map(entries) => {
+            for (key, val) in entries {
+                for expr in [key, val] {
+                    generate_function_bytecode(
+                        &expr.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                    );
+                }
+            }
+            // Keys are checked when the map is made, errors point at all of its entries
+            let op = ByteCodeOp::MakeMap(entries.len());
+            operations.push(match (entries.first(), entries.last()) {
+                (Some((first, _)), Some((_, last))) => {
+                    RelativeOperation::spanned(op, first.1.start..last.1.end)
+                }
+                _ => RelativeOperation::new(op),
+            });
+        }
+        // Names that aren't variables, globals or constants are resolved to be functions
+        Expr::LocalVar(varname) => {
+            if let (None, None, Some(val)) = (
+                mem_store.get(varname),
+                ctx.globals.get(varname),
+                ctx.values.get(varname),
+            ) {
+                return generate_function_bytecode(
+                    &Expr::Value(val.clone()),
+                    store_ct,
+                    label_ctr,
+                    loop_id,
+                    ctx,
+                    mem_store,
+                    operations,
+                );
+            }
+            operations.push(RelativeOperation::new(
+                match (mem_store.get(varname), ctx.globals.get(varname)) {
+                    (Some(slot), _) if ctx.cells.contains(varname) => ByteCodeOp::CellGet(*slot),
+                    (Some(slot), _) => ByteCodeOp::LocalGet(*slot),
+                    (None, Some(slot)) => ByteCodeOp::GlobalGet(*slot),
+                    (None, None) => ByteCodeOp::Const(ByteCodeValue::Func(varname.clone())),
+                },
+            ))
+        }
         Expr::Let(variable, expression, other) => {
             generate_function_bytecode(
                 &(**expression).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
-            mem_store.insert(variable.clone(), store_ct);
-            operations.push(RelativeOperation::new(ByteCodeOp::LocalSet(store_ct)));
+            let shadowed = mem_store.insert(variable.clone(), store_ct);
+            bind_local(variable, store_ct, ctx, operations);
             store_ct += 1;
             generate_function_bytecode(
                 &(**other).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
+                mem_store,
+                operations,
+            );
+            // Closures created later capture what is in scope there, not variables of finished blocks
+            match shadowed {
+                Some(slot) => mem_store.insert(variable.clone(), slot),
+                None => mem_store.remove(variable),
+            };
+        }
+        Expr::Tuple(items) => {
+            for item in items {
+                generate_function_bytecode(
+                    &item.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
+            operations.push(RelativeOperation::new(ByteCodeOp::MakeTuple(items.len())));
+        }
+        Expr::Destructure(names, val, body) => {
+            generate_function_bytecode(
+                &val.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+            );
+            operations.push(RelativeOperation::spanned(
+                ByteCodeOp::UnpackTuple(names.len()),
+                val.1.clone(),
+            ));
+            // The last item is on top of the stack
+            let mut shadowed = Vec::new();
+            for (i, (name, _)) in names.iter().enumerate().rev() {
+                shadowed.push((name, mem_store.insert(name.clone(), store_ct + i)));
+                bind_local(name, store_ct + i, ctx, operations);
+            }
+            generate_function_bytecode(
+                &body.0,
+                store_ct + names.len(),
+                label_ctr,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
+            // Restored in reverse so a name bound twice gets back what it had before the `let`
+            for (name, slot) in shadowed.into_iter().rev() {
+                match slot {
+                    Some(slot) => mem_store.insert(name.clone(), slot),
+                    None => mem_store.remove(name),
+                };
+            }
         }
         Expr::Then(this_expr, next_expr) => {
             generate_function_bytecode(
                 &(**this_expr).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
+            // Every expression leaves exactly one value, statements only keep the last one
+            operations.push(RelativeOperation::new(ByteCodeOp::Pop));
             generate_function_bytecode(
                 &(**next_expr).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
         }
+        // `&&` and `||` only evaluate their right side if the left side doesn't already decide the result
+        Expr::Binary(lhs, operation @ (BinaryOp::And | BinaryOp::Or), rhs) => {
+            let label_id = *label_ctr;
+            *label_ctr += 1;
+            let short_label = format!("{}_{}_{}", method_name, "shortcircuit", label_id);
+            let (short_jump, short_value) = match operation {
+                BinaryOp::And => (ByteCodeOp::JumpFalse(short_label.clone()), false),
+                _ => (ByteCodeOp::JumpTrue(short_label.clone()), true),
+            };
+            generate_function_bytecode(
+                &(**lhs).0,
+                store_ct,
+                label_ctr,
+                loop_id,
+                ctx,
+                mem_store,
+                operations,
+            );
+            operations.push(RelativeOperation::new(short_jump));
+            generate_function_bytecode(
+                &(**rhs).0,
+                store_ct,
+                label_ctr,
+                loop_id,
+                ctx,
+                mem_store,
+                operations,
+            );
+            operations.push(RelativeOperation::new(ByteCodeOp::Jump(format!(
+                "{}_{}_{}",
+                method_name, "logicend", label_id
+            ))));
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(short_label)));
+            operations.push(RelativeOperation::new(ByteCodeOp::Const(
+                ByteCodeValue::Boolean(short_value),
+            )));
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
+                "{}_{}_{}",
+                method_name, "logicend", label_id
+            ))));
+        }
         Expr::Binary(lhs, operation, rhs) => {
             generate_function_bytecode(
                 &(**lhs).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
@@ -174,7 +795,8 @@ fn generate_function_bytecode(
                 &(**rhs).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
@@ -183,74 +805,175 @@ fn generate_function_bytecode(
                 BinaryOp::Sub => operations.push(RelativeOperation::new(ByteCodeOp::Sub)),
                 BinaryOp::Mul => operations.push(RelativeOperation::new(ByteCodeOp::Mul)),
                 BinaryOp::Div => operations.push(RelativeOperation::new(ByteCodeOp::Div)),
+                BinaryOp::Mod => operations.push(RelativeOperation::new(ByteCodeOp::Mod)),
                 BinaryOp::Eq => operations.push(RelativeOperation::new(ByteCodeOp::Equal)),
                 BinaryOp::NotEq => operations.push(RelativeOperation::new(ByteCodeOp::NotEq)),
                 BinaryOp::LowerT => operations.push(RelativeOperation::new(ByteCodeOp::LowerT)),
                 BinaryOp::GreaterT => operations.push(RelativeOperation::new(ByteCodeOp::GreaterT)),
-                BinaryOp::ListAt => operations.push(RelativeOperation::new(ByteCodeOp::ListAt)),
+                BinaryOp::LowerEq => operations.push(RelativeOperation::new(ByteCodeOp::LowerEq)),
+                BinaryOp::GreaterEq => {
+                    operations.push(RelativeOperation::new(ByteCodeOp::GreaterEq))
+                }
+                // Indexing errors point at the index
+                BinaryOp::ListAt => operations.push(RelativeOperation::spanned(
+                    ByteCodeOp::ListAt,
+                    rhs.1.clone(),
+                )),
+                BinaryOp::Concat => operations.push(RelativeOperation::new(ByteCodeOp::Concat)),
+                BinaryOp::BitAnd => operations.push(RelativeOperation::new(ByteCodeOp::BitAnd)),
+                BinaryOp::BitOr => operations.push(RelativeOperation::new(ByteCodeOp::BitOr)),
+                BinaryOp::BitXor => operations.push(RelativeOperation::new(ByteCodeOp::BitXor)),
+                BinaryOp::Shl => operations.push(RelativeOperation::new(ByteCodeOp::Shl)),
+                BinaryOp::Shr => operations.push(RelativeOperation::new(ByteCodeOp::Shr)),
+                BinaryOp::And | BinaryOp::Or => unreachable!(),
             }
         }
+        Expr::Unary(operation, expr) => {
+            generate_function_bytecode(
+                &(**expr).0,
+                store_ct,
+                label_ctr,
+                loop_id,
+                ctx,
+                mem_store,
+                operations,
+            );
+            match operation {
+                UnaryOp::Not => operations.push(RelativeOperation::new(ByteCodeOp::Not)),
+                UnaryOp::Neg => operations.push(RelativeOperation::new(ByteCodeOp::Neg)),
+                UnaryOp::BitNot => operations.push(RelativeOperation::new(ByteCodeOp::BitNot)),
+            }
+        }
+        // Functions called by their name are known statically, anything else is called through its value
         Expr::Call(func_name, arguments) => {
-            for arg in arguments.0.iter() {
+            let static_call = match &func_name.0 {
+                Expr::LocalVar(name) if !mem_store.contains_key(name) => Some(name),
+                _ => None,
+            };
+            if static_call.is_none() {
                 generate_function_bytecode(
-                    &arg.0,
+                    &func_name.0,
                     store_ct,
                     label_ctr,
-                    method_name,
+                    loop_id,
+                    ctx,
                     mem_store,
                     operations,
                 );
             }
-            let Expr::LocalVar(funcname_vale) = &func_name.0 else {
-                panic!("Funcname not string");
-            };
+            for arg in arguments.0.iter() {
+                generate_function_bytecode(
+                    &arg.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
 
-            operations.push(RelativeOperation::new(ByteCodeOp::Call(
-                funcname_vale.clone(),
-                arguments.0.len(),
+            // Builtins have their own instructions, user functions can't take their names
+            let call = match static_call.map(String::as_str) {
+                Some("len") => ByteCodeOp::ListLen,
+                Some("push") => ByteCodeOp::ListPush,
+                Some("pop") => ByteCodeOp::ListPop,
+                // Map builtins report their errors at the last argument, which is the key if there is one
+                Some(name @ ("keys" | "contains" | "remove")) => {
+                    let op = match name {
+                        "keys" => ByteCodeOp::MapKeys,
+                        "contains" => ByteCodeOp::MapContains,
+                        _ => ByteCodeOp::MapRemove,
+                    };
+                    let span = arguments
+                        .0
+                        .last()
+                        .map_or(arguments.1.clone(), |arg| arg.1.clone());
+                    operations.push(RelativeOperation::spanned(op, span));
+                    return;
+                }
+                Some(name) => ByteCodeOp::Call(name.to_string(), arguments.0.len()),
+                None => {
+                    operations.push(RelativeOperation::spanned(
+                        ByteCodeOp::CallIndirect(arguments.0.len()),
+                        func_name.1.clone(),
+                    ));
+                    return;
+                }
+            };
+            operations.push(RelativeOperation::new(call));
+        }
+        // Closures become functions of their own, which get the captured variables as their first arguments
+        Expr::Closure(args, body) => {
+            let label_id = *label_ctr;
+            *label_ctr += 1;
+            let name = format!("{}_{}_{}", method_name, "closure", label_id);
+            let captures: Vec<String> = expr
+                .free_vars()
+                .into_iter()
+                .filter(|var| mem_store.contains_key(var))
+                .collect();
+            // Variables in cells are captured as the cell, so assignments are shared
+            for var in &captures {
+                operations.push(RelativeOperation::new(ByteCodeOp::LocalGet(mem_store[var])));
+            }
+            let params: Vec<String> = captures.iter().chain(args).cloned().collect();
+            let ops = generate_function_code(
+                &params,
+                captures.len(),
+                body,
+                &Context {
+                    method_name: &name,
+                    ..*ctx
+                },
+            );
+            ctx.closures
+                .borrow_mut()
+                .push(ByteCodeFunction::new(name.clone(), ops, params.len()));
+            operations.push(RelativeOperation::new(ByteCodeOp::MakeClosure(
+                name,
+                captures.len(),
             )));
         }
         Expr::If(cond, then, els) => {
-            let increased_labelctr = label_ctr + 1;
+            let label_id = *label_ctr;
+            *label_ctr += 1;
             generate_function_bytecode(
                 &(**cond).0,
                 store_ct,
-                increased_labelctr,
-                method_name,
+                label_ctr,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
             operations.push(RelativeOperation::new(ByteCodeOp::JumpFalse(format!(
                 "{}_{}_{}",
-                method_name, "else", label_ctr
+                method_name, "else", label_id
             ))));
             generate_function_bytecode(
                 &(**then).0,
                 store_ct,
-                increased_labelctr,
-                method_name,
+                label_ctr,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
             operations.push(RelativeOperation::new(ByteCodeOp::Jump(format!(
                 "{}_{}_{}",
-                method_name, "ifend", label_ctr
+                method_name, "ifend", label_id
             ))));
             operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
                 "{}_{}_{}",
-                method_name, "else", label_ctr
+                method_name, "else", label_id
             ))));
             generate_function_bytecode(
                 &(**els).0,
                 store_ct,
-                increased_labelctr,
-                method_name,
+                label_ctr,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
             operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
                 "{}_{}_{}",
-                method_name, "ifend", label_ctr
+                method_name, "ifend", label_id
             ))));
         }
         Expr::Print(expr) => {
@@ -258,7 +981,8 @@ fn generate_function_bytecode(
                 &(**expr).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
@@ -269,7 +993,8 @@ fn generate_function_bytecode(
                 &(**expr).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
@@ -280,118 +1005,432 @@ fn generate_function_bytecode(
                 &(**expression).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
-            let variable_local_ct = mem_store.get(ident);
-            operations.push(RelativeOperation::new(ByteCodeOp::LocalSet(
-                *variable_local_ct.unwrap(),
-            )));
+            operations.push(RelativeOperation::new(
+                match (mem_store.get(ident), ctx.globals.get(ident)) {
+                    (Some(slot), _) if ctx.cells.contains(ident) => ByteCodeOp::CellSet(*slot),
+                    (Some(slot), _) => ByteCodeOp::LocalSet(*slot),
+                    (None, Some(slot)) => ByteCodeOp::GlobalSet(*slot),
+                    (None, None) => unreachable!("variables are resolved before codegen"),
+                },
+            ));
             store_ct += 1;
             generate_function_bytecode(
                 &(**next).0,
                 store_ct,
                 label_ctr,
-                method_name,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
         }
         Expr::Loop(cond, body) => {
-            let increased_labelctr = label_ctr + 1;
-            operations.push(RelativeOperation::new(ByteCodeOp::Const(
-                ByteCodeValue::Number(0.0),
-            )));
+            let label_id = *label_ctr;
+            *label_ctr += 1;
+            let height_slot = store_ct;
+            operations.push(RelativeOperation::new(ByteCodeOp::SaveStack(height_slot)));
             operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
                 "{}_{}_{}",
-                method_name, "loopstart", label_ctr
+                method_name, "loopstart", label_id
             ))));
 
             generate_function_bytecode(
                 &(**cond).0,
-                store_ct,
-                increased_labelctr,
-                method_name,
+                store_ct + 1,
+                label_ctr,
+                loop_id,
+                ctx,
                 mem_store,
                 operations,
             );
             operations.push(RelativeOperation::new(ByteCodeOp::JumpFalse(format!(
                 "{}_{}_{}",
-                method_name, "loopend", label_ctr
+                method_name, "loopend", label_id
             ))));
+
+            generate_function_bytecode(
+                &(**body).0,
+                store_ct + 1,
+                label_ctr,
+                Some((label_id, height_slot)),
+                ctx,
+                mem_store,
+                operations,
+            );
             operations.push(RelativeOperation::new(ByteCodeOp::Pop));
+            operations.push(RelativeOperation::new(ByteCodeOp::Jump(format!(
+                "{}_{}_{}",
+                method_name, "loopstart", label_id
+            ))));
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
+                "{}_{}_{}",
+                method_name, "loopend", label_id
+            ))));
+            operations.push(RelativeOperation::new(ByteCodeOp::Const(
+                ByteCodeValue::Null,
+            )));
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
+                "{}_{}_{}",
+                method_name, "loopexit", label_id
+            ))));
+        }
+        // `for` loops work like `loop`, with the iterator, the current item and the stack height in three extra local slots
+        Expr::For(name, items, body) => {
+            let label_id = *label_ctr;
+            *label_ctr += 1;
+            let (iter_slot, item_slot, height_slot) = (store_ct, store_ct + 1, store_ct + 2);
+            let (items, iter_op) = match &items.0 {
+                Expr::Range(start, end, inclusive) => {
+                    generate_function_bytecode(
+                        &(**start).0,
+                        store_ct,
+                        label_ctr,
+                        loop_id,
+                        ctx,
+                        mem_store,
+                        operations,
+                    );
+                    (&end.0, ByteCodeOp::IterRange(iter_slot, *inclusive))
+                }
+                items => (items, ByteCodeOp::IterList(iter_slot)),
+            };
+            generate_function_bytecode(
+                items, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+            );
+            operations.push(RelativeOperation::new(iter_op));
+            operations.push(RelativeOperation::new(ByteCodeOp::SaveStack(height_slot)));
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
+                "{}_{}_{}",
+                method_name, "loopstart", label_id
+            ))));
+            operations.push(RelativeOperation::new(ByteCodeOp::IterNext(
+                iter_slot,
+                format!("{}_{}_{}", method_name, "loopend", label_id),
+            )));
+            bind_local(name, item_slot, ctx, operations);
 
+            let shadowed = mem_store.insert(name.clone(), item_slot);
             generate_function_bytecode(
                 &(**body).0,
-                store_ct,
-                increased_labelctr,
-                method_name,
+                store_ct + 3,
+                label_ctr,
+                Some((label_id, height_slot)),
+                ctx,
                 mem_store,
                 operations,
             );
-            //This is giga cursed because loop can theoretically return soemthing?
-            // operations.push(RelativeOperation::new(ByteCodeOp::Pop));
+            match shadowed {
+                Some(slot) => mem_store.insert(name.clone(), slot),
+                None => mem_store.remove(name),
+            };
+            operations.push(RelativeOperation::new(ByteCodeOp::Pop));
             operations.push(RelativeOperation::new(ByteCodeOp::Jump(format!(
                 "{}_{}_{}",
-                method_name, "loopstart", label_ctr
+                method_name, "loopstart", label_id
             ))));
             operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
                 "{}_{}_{}",
-                method_name, "loopend", label_ctr
+                method_name, "loopend", label_id
+            ))));
+            operations.push(RelativeOperation::new(ByteCodeOp::Const(
+                ByteCodeValue::Null,
+            )));
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
+                "{}_{}_{}",
+                method_name, "loopexit", label_id
+            ))));
+        }
+        Expr::SetIndex(list, index, val) => {
+            for expr in [list, index, val] {
+                generate_function_bytecode(
+                    &expr.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
+            operations.push(RelativeOperation::spanned(
+                ByteCodeOp::ListSet,
+                index.1.clone(),
+            ));
+        }
+        Expr::Struct((name, _), fields) => {
+            for (_, val) in fields {
+                generate_function_bytecode(
+                    &val.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
+            operations.push(RelativeOperation::new(ByteCodeOp::MakeStruct(
+                name.clone(),
+                fields.iter().map(|((field, _), _)| field.clone()).collect(),
+            )));
+        }
+        Expr::Field(obj, (field, field_span)) => {
+            generate_function_bytecode(
+                &obj.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+            );
+            operations.push(RelativeOperation::spanned(
+                ByteCodeOp::GetField(field.clone()),
+                field_span.clone(),
+            ));
+        }
+        Expr::SetField(obj, (field, field_span), val) => {
+            for expr in [obj, val] {
+                generate_function_bytecode(
+                    &expr.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
+            operations.push(RelativeOperation::spanned(
+                ByteCodeOp::SetField(field.clone()),
+                field_span.clone(),
+            ));
+        }
+        Expr::Variant((name, _), (variant, _), vals) => {
+            for val in vals {
+                generate_function_bytecode(
+                    &val.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+                );
+            }
+            operations.push(RelativeOperation::new(ByteCodeOp::MakeVariant {
+                name: name.clone(),
+                variant: variant.clone(),
+                tag: ctx.tag(name, variant),
+                len: vals.len(),
+            }));
+        }
+        // The matched value is kept in a local slot. Matches on enums jump straight to the arms that can
+        // match the variant through a jump table, other matches try their arms one after another.
+        Expr::Match(val, arms) => {
+            let label_id = *label_ctr;
+            *label_ctr += 1;
+            let slot = store_ct;
+            generate_function_bytecode(
+                &val.0, store_ct, label_ctr, loop_id, ctx, mem_store, operations,
+            );
+            operations.push(RelativeOperation::new(ByteCodeOp::LocalSet(slot)));
+            let end_label = format!("{}_{}_{}", method_name, "matchend", label_id);
+            let enum_name = arms.iter().find_map(|((pat, _), _)| match pat {
+                Pattern::Variant((name, _), _, _) => Some(name),
+                _ => None,
+            });
+            // Per case the arms that are tried, all of them if there is no jump table
+            let cases: Vec<Vec<&(Spanned<Pattern>, Spanned<Expr>)>> = match enum_name {
+                Some(name) => {
+                    let case_labels = (0..ctx.enums[name].variants.len())
+                        .map(|tag| format!("{}_{}_{}_{}", method_name, "matchcase", label_id, tag))
+                        .collect();
+                    operations.push(RelativeOperation::new(ByteCodeOp::LocalGet(slot)));
+                    operations.push(RelativeOperation::spanned(
+                        ByteCodeOp::JumpTable(case_labels),
+                        val.1.clone(),
+                    ));
+                    ctx.enums[name]
+                        .variants
+                        .iter()
+                        .map(|v| {
+                            arms.iter()
+                                .filter(|((pat, _), _)| match pat {
+                                    Pattern::Variant(_, (variant, _), _) => *variant == v.name.0,
+                                    _ => true,
+                                })
+                                .collect()
+                        })
+                        .collect()
+                }
+                None => vec![arms.iter().collect()],
+            };
+            for (tag, case) in cases.into_iter().enumerate() {
+                if enum_name.is_some() {
+                    operations.push(RelativeOperation::new(ByteCodeOp::Label(format!(
+                        "{}_{}_{}_{}",
+                        method_name, "matchcase", label_id, tag
+                    ))));
+                }
+                for ((pat, _), body) in case {
+                    let fail = format!("{}_{}_{}", method_name, "matchnext", *label_ctr);
+                    *label_ctr += 1;
+                    let mut bindings = Vec::new();
+                    pattern_tests(
+                        pat,
+                        slot,
+                        &mut Vec::new(),
+                        &fail,
+                        ctx,
+                        &mut bindings,
+                        operations,
+                    );
+                    let mut shadowed = Vec::new();
+                    for (i, (name, path)) in bindings.iter().enumerate() {
+                        load_path(slot, path, operations);
+                        bind_local(name, slot + 1 + i, ctx, operations);
+                        shadowed.push((name, mem_store.insert(name.clone(), slot + 1 + i)));
+                    }
+                    generate_function_bytecode(
+                        &body.0,
+                        slot + 1 + bindings.len(),
+                        label_ctr,
+                        loop_id,
+                        ctx,
+                        mem_store,
+                        operations,
+                    );
+                    for (name, prev) in shadowed.into_iter().rev() {
+                        match prev {
+                            Some(prev) => mem_store.insert(name.clone(), prev),
+                            None => mem_store.remove(name),
+                        };
+                    }
+                    operations.push(RelativeOperation::new(ByteCodeOp::Jump(end_label.clone())));
+                    operations.push(RelativeOperation::new(ByteCodeOp::Label(fail)));
+                }
+            }
+            // Matches are checked to be exhaustive, so running out of arms can't happen
+            operations.push(RelativeOperation::new(ByteCodeOp::Label(end_label)));
+        }
+        Expr::Range(..) => unreachable!("ranges only appear as the items of a for loop"),
+        Expr::Break(val) => {
+            let (label_id, height_slot) = loop_id.expect("break is resolved to be inside a loop");
+            generate_function_bytecode(
+                &(**val).0,
+                store_ct,
+                label_ctr,
+                loop_id,
+                ctx,
+                mem_store,
+                operations,
+            );
+            operations.push(RelativeOperation::new(ByteCodeOp::Truncate(height_slot, 1)));
+            operations.push(RelativeOperation::new(ByteCodeOp::Jump(format!(
+                "{}_{}_{}",
+                method_name, "loopexit", label_id
+            ))));
+        }
+        Expr::Continue => {
+            let (label_id, height_slot) =
+                loop_id.expect("continue is resolved to be inside a loop");
+            operations.push(RelativeOperation::new(ByteCodeOp::Truncate(height_slot, 0)));
+            operations.push(RelativeOperation::new(ByteCodeOp::Jump(format!(
+                "{}_{}_{}",
+                method_name, "loopstart", label_id
             ))));
         }
+        Expr::Path(..) => unreachable!("paths are replaced when modules are merged"),
     }
 }
 
-fn generate_function_code(function: &Func, function_name: &str) -> Vec<RelativeOperation> {
+/// The code of a function. The first `captured` arguments are the variables a closure captured,
+/// they already come in cells where they need one.
+fn generate_function_code(
+    args: &[String],
+    captured: usize,
+    body: &Spanned<Expr>,
+    ctx: &Context,
+) -> Vec<RelativeOperation> {
+    let function_name = ctx.method_name;
     let mut operations = Vec::new();
     let mut mem_store: HashMap<String, usize> = HashMap::new();
-    let label_ctr = 0;
+    let mut label_ctr = 0;
     operations.push(RelativeOperation::new(ByteCodeOp::Label(
         function_name.to_string(),
     )));
 
-    for (i, arg) in function.args.iter().enumerate() {
+    for (i, arg) in args.iter().enumerate() {
         mem_store.insert(arg.clone(), i);
+        if i >= captured && ctx.cells.contains(arg) {
+            operations.push(RelativeOperation::new(ByteCodeOp::LocalGet(i)));
+            bind_local(arg, i, ctx, &mut operations);
+        }
+    }
+
+    // Globals get their values before anything else runs
+    if function_name == "main" {
+        let mut globals: Vec<_> = ctx.globals.iter().collect();
+        globals.sort_by_key(|(_, slot)| **slot);
+        for (name, slot) in globals {
+            generate_function_bytecode(
+                &Expr::Value(ctx.values[name].clone()),
+                args.len(),
+                &mut label_ctr,
+                None,
+                ctx,
+                &mut mem_store,
+                &mut operations,
+            );
+            operations.push(RelativeOperation::new(ByteCodeOp::GlobalSet(*slot)));
+        }
     }
 
     generate_function_bytecode(
-        &function.body.0,
-        function.args.len(),
-        label_ctr,
-        function_name,
+        &body.0,
+        args.len(),
+        &mut label_ctr,
+        None,
+        ctx,
         &mut mem_store,
         &mut operations,
     );
+    // Functions that end without a `return` give back the value of their body
     if function_name == "main" {
         operations.push(RelativeOperation::new(ByteCodeOp::End))
+    } else {
+        operations.push(RelativeOperation::new(ByteCodeOp::Return))
     }
     operations
 }
 
 pub struct Generator {
-    ast: HashMap<String, Func>,
+    ast: Program,
+    /// Values of the constants and globals, evaluated at compile time
+    values: HashMap<String, Value>,
 }
 
 impl Generator {
-    pub fn new(ast: HashMap<String, Func>) -> Self {
-        Generator { ast }
+    pub fn new(ast: Program, values: HashMap<String, Value>) -> Self {
+        Generator { ast, values }
     }
     /// Takes the bastract syntax tree stored in the Generator and prints the generated bytecode
     pub fn generate_bytecod(&self) -> Result<Vec<ByteCodeFunction>> {
-        if self.ast.contains_key("main") {
-            Ok(self
+        if self.ast.funcs.contains_key("main") {
+            let closures = RefCell::new(Vec::new());
+            let mut names: Vec<&String> = (self.ast.globals.iter())
+                .filter(|(_, global)| global.mutable)
+                .map(|(name, _)| name)
+                .collect();
+            names.sort();
+            let globals = names
+                .into_iter()
+                .enumerate()
+                .map(|(slot, name)| (name.clone(), slot))
+                .collect();
+            let mut functions: Vec<ByteCodeFunction> = self
                 .ast
+                .funcs
                 .iter()
                 .map(|func_and_name| {
+                    let cells = shared_vars(&func_and_name.1.body.0);
                     ByteCodeFunction::new(
                         func_and_name.0.clone(),
-                        generate_function_code(func_and_name.1, &func_and_name.0),
+                        generate_function_code(
+                            &func_and_name.1.args,
+                            0,
+                            &func_and_name.1.body,
+                            &Context {
+                                method_name: func_and_name.0,
+                                enums: &self.ast.enums,
+                                closures: &closures,
+                                values: &self.values,
+                                globals: &globals,
+                                cells: &cells,
+                            },
+                        ),
                         func_and_name.1.args.len(),
                     )
                 })
-                .collect())
+                .collect();
+            functions.append(&mut closures.into_inner());
+            Ok(functions)
         } else {
             bail!("No main found")
         }
@@ -0,0 +1,32 @@
+use crate::parser::Span;
+
+/// An error found by one of the passes after parsing. Unlike parser errors it can point at several places at once.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub msg: String,
+    /// Labelled spans, the first one is the primary location
+    pub labels: Vec<(Span, String)>,
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, msg: impl Into<String>) -> Self {
+        Diagnostic {
+            span,
+            msg: msg.into(),
+            labels: Vec::new(),
+            note: None,
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, msg: impl Into<String>) -> Self {
+        self.labels.push((span, msg.into()));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+}
@@ -0,0 +1,183 @@
+use crate::parser::{Enum, Pattern, Value, Variant};
+use std::collections::HashMap;
+
+/// A way a value can be built, which patterns take apart
+enum Ctor<'a> {
+    Variant(&'a str, &'a Variant),
+    Lit(Value),
+}
+
+impl Ctor<'_> {
+    fn arity(&self) -> usize {
+        match self {
+            Ctor::Variant(_, variant) => variant.fields.len(),
+            Ctor::Lit(_) => 0,
+        }
+    }
+
+    /// A pattern matching every value built this way, given patterns for the payload
+    fn pattern(&self, pats: Vec<Pattern>) -> Pattern {
+        match self {
+            Ctor::Variant(name, variant) => Pattern::Variant(
+                (name.to_string(), 0..0),
+                variant.name.clone(),
+                pats.into_iter().map(|pat| (pat, 0..0)).collect(),
+            ),
+            Ctor::Lit(val) => Pattern::Value(val.clone()),
+        }
+    }
+}
+
+/// A row of patterns, `None` matches anything like `_` or a name
+type Row<'a> = Vec<Option<&'a Pattern>>;
+
+/// The rows that can still match once the first value is known to be built by `ctor`, with the first pattern
+/// replaced by the patterns of the payload
+fn specialize<'a>(rows: &[Row<'a>], ctor: &Ctor) -> Vec<Row<'a>> {
+    rows.iter()
+        .filter_map(|row| {
+            let head: Row<'a> = match (row[0], ctor) {
+                (None, ctor) => vec![None; ctor.arity()],
+                (Some(Pattern::Variant(_, (v, _), pats)), Ctor::Variant(_, variant))
+                    if *v == variant.name.0 =>
+                {
+                    pats.iter().map(|(pat, _)| normalize(pat)).collect()
+                }
+                (Some(Pattern::Value(lit)), Ctor::Lit(val)) if lit == val => Vec::new(),
+                _ => return None,
+            };
+            Some(head.into_iter().chain(row[1..].iter().copied()).collect())
+        })
+        .collect()
+}
+
+fn normalize(pat: &Pattern) -> Option<&Pattern> {
+    match pat {
+        Pattern::Wildcard | Pattern::Bind(_) => None,
+        pat => Some(pat),
+    }
+}
+
+/// Values of `width` columns that no row matches, as patterns, if there are any.
+/// Enums and bools have a known set of constructors, every other type is assumed to have infinitely many.
+fn missing(enums: &HashMap<String, Enum>, rows: &[Row], width: usize) -> Option<Vec<Pattern>> {
+    if width == 0 {
+        return rows.is_empty().then(Vec::new);
+    }
+    let heads: Vec<&Pattern> = rows.iter().filter_map(|row| row[0]).collect();
+    let all: Vec<Ctor> = match heads.first() {
+        Some(Pattern::Variant((name, _), _, _)) => enums[name]
+            .variants
+            .iter()
+            .map(|variant| Ctor::Variant(name, variant))
+            .collect(),
+        Some(Pattern::Value(Value::Bool(_))) => {
+            vec![Ctor::Lit(Value::Bool(true)), Ctor::Lit(Value::Bool(false))]
+        }
+        _ => Vec::new(),
+    };
+    let is_used = |ctor: &Ctor| {
+        heads.iter().any(|head| match (head, ctor) {
+            (Pattern::Variant(_, (v, _), _), Ctor::Variant(_, variant)) => *v == variant.name.0,
+            (Pattern::Value(lit), Ctor::Lit(val)) => lit == val,
+            _ => false,
+        })
+    };
+    if !all.is_empty() && all.iter().all(is_used) {
+        // Every constructor is matched somewhere, so one of them has to be missing a payload
+        all.iter().find_map(|ctor| {
+            let mut pats = missing(enums, &specialize(rows, ctor), ctor.arity() + width - 1)?;
+            let rest = pats.split_off(ctor.arity());
+            Some(std::iter::once(ctor.pattern(pats)).chain(rest).collect())
+        })
+    } else {
+        let defaults: Vec<Row> = rows
+            .iter()
+            .filter(|row| row[0].is_none())
+            .map(|row| row[1..].to_vec())
+            .collect();
+        let rest = missing(enums, &defaults, width - 1)?;
+        let head = match all.into_iter().find(|ctor| !is_used(ctor)) {
+            Some(ctor) => {
+                let pats = vec![Pattern::Wildcard; ctor.arity()];
+                ctor.pattern(pats)
+            }
+            None => Pattern::Wildcard,
+        };
+        Some(std::iter::once(head).chain(rest).collect())
+    }
+}
+
+/// A pattern for values that none of the arms of a `match` matches, if there are any.
+/// The patterns have to be resolved, every enum and variant they name must exist.
+pub fn missing_pattern(enums: &HashMap<String, Enum>, arms: &[&Pattern]) -> Option<Pattern> {
+    let rows: Vec<Row> = arms.iter().map(|pat| vec![normalize(pat)]).collect();
+    missing(enums, &rows, 1).map(|mut pats| pats.remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{modules, resolve};
+
+    const ENUMS: &str =
+        "enum Shape { Circle(r), Rect(w, h), Empty }\nenum Boxed { Full(s), Nothing }\n";
+
+    /// The patterns the resolver reports as not covered by the `match` in `main`
+    fn not_covered(main: &str) -> Vec<String> {
+        let src = format!("{}fn main() {{ {} }}", ENUMS, main);
+        let loaded = modules::load_files(&[("main.grsp", &src)]);
+        assert!(loaded.syntax_errs.is_empty(), "{:?}", loaded.syntax_errs);
+        let program = loaded.program.expect("the program loads");
+        resolve::resolve(&program)
+            .into_iter()
+            .map(|diag| {
+                diag.msg
+                    .strip_prefix("Non-exhaustive match: ")
+                    .unwrap_or_else(|| panic!("unexpected error: {}", diag.msg))
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn complete_matches() {
+        for main in [
+            "match Shape::Empty { Shape::Circle(r) => r, Shape::Rect(w, h) => w * h, Shape::Empty => 0 }",
+            "match Shape::Empty { Shape::Circle(_) => 1, s => 0 }",
+            "match Boxed::Nothing { Boxed::Full(Shape::Circle(_)) => 1, Boxed::Full(_) => 2, Boxed::Nothing => 3 }",
+            "match true { true => 1, false => 0 }",
+            "match 3 { 0 => 1, _ => 2 }",
+        ] {
+            assert_eq!(not_covered(main), Vec::<String>::new(), "{}", main);
+        }
+    }
+
+    #[test]
+    fn missing_patterns_are_named() {
+        assert_eq!(
+            not_covered("match Shape::Empty { Shape::Circle(r) => r, Shape::Empty => 0 }"),
+            vec!["'Shape::Rect(_, _)' is not covered"]
+        );
+        assert_eq!(
+            not_covered(
+                "match Boxed::Nothing { Boxed::Full(Shape::Circle(_)) => 1, Boxed::Full(Shape::Rect(_, 1)) => 2, Boxed::Nothing => 3 }"
+            ),
+            vec!["'Boxed::Full(Shape::Empty)' is not covered"]
+        );
+        // Every variant appears, but one of them not with every payload
+        assert_eq!(
+            not_covered(
+                "match Boxed::Nothing { Boxed::Full(Shape::Circle(_)) => 1, Boxed::Full(Shape::Rect(_, 1)) => 2, Boxed::Full(Shape::Empty) => 3, Boxed::Nothing => 4 }"
+            ),
+            vec!["'Boxed::Full(Shape::Rect(_, _))' is not covered"]
+        );
+        assert_eq!(
+            not_covered("match true { true => 1 }"),
+            vec!["'false' is not covered"]
+        );
+        assert_eq!(
+            not_covered("match 3 { 0 => 1, 1 => 2 }"),
+            vec!["'_' is not covered"]
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{
+    diagnostic::Diagnostic,
+    parser::{ast_evaluator, Global, Program, Value, Var},
+};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+/// The constants and globals in an order where every one comes after those its value uses.
+/// If their values depend on each other in a cycle, returns the cycle instead, starting and ending at the same name.
+pub fn order(globals: &HashMap<String, Global>) -> Result<Vec<&String>, Vec<&String>> {
+    fn visit<'a>(
+        name: &'a String,
+        globals: &'a HashMap<String, Global>,
+        path: &mut Vec<&'a String>,
+        out: &mut Vec<&'a String>,
+    ) -> Result<(), Vec<&'a String>> {
+        if out.contains(&name) {
+            return Ok(());
+        }
+        if let Some(pos) = path.iter().position(|n| *n == name) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(name);
+            return Err(cycle);
+        }
+        path.push(name);
+        for used in globals[name].value.0.free_vars() {
+            if let Some((used, _)) = globals.get_key_value(&used) {
+                visit(used, globals, path, out)?;
+            }
+        }
+        path.pop();
+        out.push(name);
+        Ok(())
+    }
+
+    // Sorted so the same program always gives the same order
+    let mut names: Vec<&String> = globals.keys().collect();
+    names.sort();
+    let mut out = Vec::new();
+    for name in names {
+        visit(name, globals, &mut Vec::new(), &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Whether the compiled program can hold the value as a constant, functions only exist at runtime
+fn is_constant(val: &Value) -> bool {
+    match val {
+        Value::Func(_) | Value::Closure(_) => false,
+        Value::List(items) => items.borrow().iter().all(is_constant),
+        Value::Struct(_, fields) => fields.borrow().values().all(is_constant),
+        Value::Map(entries) => entries.borrow().values().all(is_constant),
+        Value::Variant(_, _, vals) | Value::Tuple(vals) => vals.iter().all(is_constant),
+        Value::Null | Value::Bool(_) | Value::Int(_) | Value::Num(_) | Value::Str(_) => true,
+    }
+}
+
+/// Evaluates the values of all constants and globals. The program has to be resolved, so that
+/// the values only use constants that are not part of a cycle and don't call functions.
+pub fn evaluate(program: &Program) -> Result<HashMap<String, Value>, Diagnostic> {
+    let order = order(&program.globals).expect("cycles are rejected by the resolver");
+    let mut stack: Vec<(String, Var)> = Vec::new();
+    for name in order {
+        let value = &program.globals[name].value;
+        // Values that use this one would only fail because it is missing, so stop at the first error
+        let val = ast_evaluator(value, &HashMap::new(), &mut stack).map_err(|e| {
+            Diagnostic::new(
+                e.span.clone(),
+                format!("Can't evaluate '{}': {}", name, e.msg),
+            )
+            .with_label(e.span, e.msg)
+            .with_note("Constants and globals are evaluated at compile time")
+        })?;
+        if !is_constant(&val) {
+            return Err(Diagnostic::new(
+                value.1.clone(),
+                format!("'{}' can't hold a function", name),
+            )
+            .with_label(value.1.clone(), "This contains a function")
+            .with_note("Constants and globals are evaluated at compile time, functions only exist once the program runs"));
+        }
+        stack.push((name.clone(), Rc::new(RefCell::new(val))));
+    }
+    Ok(stack
+        .into_iter()
+        .map(|(name, var)| (name, var.borrow().clone()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{modules, resolve};
+
+    fn load(src: &str) -> Program {
+        let loaded = modules::load_files(&[("main.grsp", src)]);
+        assert!(loaded.syntax_errs.is_empty(), "{:?}", loaded.syntax_errs);
+        loaded.program.expect("the program loads")
+    }
+
+    /// The values of the constants and globals of a program that resolves, or the message of the error
+    fn values(src: &str) -> Result<Vec<(String, String)>, String> {
+        let program = load(src);
+        let errs = resolve::resolve(&program);
+        assert!(errs.is_empty(), "{:?}", errs);
+        let mut values: Vec<_> = evaluate(&program)
+            .map_err(|diag| diag.msg)?
+            .into_iter()
+            .map(|(name, val)| (name, val.to_string()))
+            .collect();
+        values.sort();
+        Ok(values)
+    }
+
+    fn value(name: &str, val: &str) -> (String, String) {
+        (name.to_string(), val.to_string())
+    }
+
+    #[test]
+    fn values_come_after_the_ones_they_use() {
+        let program =
+            load("const C = B * 2\nconst A = 1\nglobal B = A + 1\nconst D = 4\nfn main() { C }");
+        let sorted = order(&program.globals).unwrap();
+        let pos = |name: &str| sorted.iter().position(|n| *n == name).unwrap();
+        assert!(pos("A") < pos("B") && pos("B") < pos("C"));
+        assert_eq!(sorted.len(), 4);
+
+        let program = load("const A = B\nconst B = C + 1\nconst C = A\nfn main() { A }");
+        assert_eq!(
+            order(&program.globals).unwrap_err(),
+            vec!["A", "B", "C", "A"]
+        );
+    }
+
+    #[test]
+    fn cycles_are_reported_by_the_resolver() {
+        let program = load("const A = [B]\nconst B = [A, 1]\nfn main() { 0 }");
+        let errs = resolve::resolve(&program);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].msg, "'A' depends on itself");
+        assert_eq!(
+            errs[0].note.as_deref(),
+            Some("The values form a cycle: A -> B -> A")
+        );
+    }
+
+    #[test]
+    fn values_are_evaluated_at_compile_time() {
+        assert_eq!(
+            values(
+                "const A = 1 + 2\n\
+                 global B = [A, A * 2]\n\
+                 const C = (let c = 0; let inc = || { c = c + 1; c }; inc(); inc(); c * 10 + inc())\n\
+                 const D = {\"a\": A}\n\
+                 fn main() { 0 }"
+            ),
+            Ok(vec![
+                value("A", "3"),
+                value("B", "[3, 6]"),
+                value("C", "23"),
+                value("D", "{a: 3}"),
+            ])
+        );
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        assert_eq!(
+            values("const A = [1, 2][5]\nfn main() { 0 }").unwrap_err(),
+            "Can't evaluate 'A': '5' index out of range for list length 2"
+        );
+        assert_eq!(
+            values("const F = [|x| x]\nfn main() { 0 }").unwrap_err(),
+            "'F' can't hold a function"
+        );
+    }
+
+    #[test]
+    fn initializers_only_use_constants() {
+        let program = load(
+            "global G = 1\nconst A = G + 1\nconst B = f()\nconst C = (A = 2; A)\nfn f() { 1 }\nfn main() { B = 3; 0 }",
+        );
+        let msgs: Vec<String> = resolve::resolve(&program)
+            .into_iter()
+            .map(|diag| diag.msg)
+            .collect();
+        assert_eq!(
+            msgs,
+            vec![
+                "'G' is a global",
+                "'f' is a function",
+                "'A' can't be assigned here",
+                "Can't assign to constant 'B'",
+            ]
+        );
+    }
+}
@@ -1,31 +1,42 @@
-use anyhow::bail;
-use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
-use chumsky::stream::Stream;
+use ariadne::{sources, Color, Config, Fmt, IndexType, Label, Report, ReportKind};
 use codegen::Generator;
+use diagnostic::Diagnostic;
+use modules::Sources;
 use runtime::Runtime;
-use std::{env, fs};
-
-use chumsky::{error::Simple, Parser};
-use parser::{ast_evaluator, funcs_parser, lexer};
+use std::env;
 
 pub mod codegen;
+pub mod diagnostic;
+pub mod exhaustive;
+pub mod globals;
+pub mod modules;
 pub mod parser;
+pub mod resolve;
 pub mod runtime;
+pub mod typecheck;
 
 fn main() {
-    let src = fs::read_to_string(env::args().nth(1).expect("Expected file argument"))
-        .expect("Failed to read file");
-
-    let (tokens, mut errs) = lexer().parse_recovery(src.as_str());
+    let loaded = modules::load(&env::args().nth(1).expect("Expected file argument"));
+    let src = loaded.sources;
 
-    let parse_errs = if let Some(tokens) = tokens {
-        let len = src.chars().count();
-        let (ast, parse_errs) =
-            funcs_parser().parse_recovery(Stream::from_iter(len..len + 1, tokens.into_iter()));
-
-        if let Some(funcs) = ast.filter(|_| errs.len() + parse_errs.len() == 0) {
-            //TODO cloning here is super expensive big nono
-            let generator = Generator::new(funcs.clone());
+    let mut check_errs = loaded.errs;
+    let parse_errs = {
+        let ast = loaded.program;
+        let mut values = None;
+        if let Some(program) = &ast {
+            check_errs = resolve::resolve(program);
+            if check_errs.is_empty() {
+                check_errs = typecheck::check(program);
+            }
+            if check_errs.is_empty() {
+                match globals::evaluate(program) {
+                    Ok(vals) => values = Some(vals),
+                    Err(diag) => check_errs.push(diag),
+                }
+            }
+        }
+        if let (Some(program), Some(values)) = (ast, values) {
+            let generator = Generator::new(program, values);
             let bytecode = generator.generate_bytecod().unwrap();
             // // bytecode.iter().for_each(|op| {
             // //     println!("Name: {}", op.name);
@@ -35,10 +46,22 @@ fn main() {
             // // });
             let mut runtime = Runtime::new(bytecode);
             // // println!("Execution in VM starts");
-            if let Ok(result) = runtime.execute_program() {
-                println!("Runtime Execution returned: {}", result);
-            } else {
-                panic!("Runtime Execution failed");
+            match runtime.execute_program() {
+                Ok(result) => println!("Runtime Execution returned: {}", result),
+                Err(e) => {
+                    match &e.span {
+                        Some(span) => print_diagnostic(
+                            &Diagnostic::new(
+                                span.clone(),
+                                format!("Runtime Execution failed: {}", e),
+                            )
+                            .with_label(span.clone(), e.to_string()),
+                            &src,
+                        ),
+                        None => eprintln!("Runtime Execution failed: {}", e),
+                    }
+                    std::process::exit(1);
+                }
             }
             // This should not be in the final output this is the AST inline interpreter
             // println!("Ast interpreter starts");
@@ -52,78 +75,115 @@ fn main() {
             // }
         }
 
-        parse_errs
-    } else {
-        Vec::new()
+        loaded.syntax_errs
     };
 
-    errs.into_iter()
-        .map(|e| e.map(|c| c.to_string()))
-        .chain(parse_errs.into_iter().map(|e| e.map(|tok| tok.to_string())))
-        .for_each(|e| {
-            let report = Report::build(ReportKind::Error, (), e.span().start);
+    let failed = !parse_errs.is_empty() || !check_errs.is_empty();
+    parse_errs.into_iter().for_each(|e| {
+        let (path, span) = src.locate(&e.span());
+        let report = Report::build(ReportKind::Error, path.clone(), span.start)
+            .with_config(Config::default().with_index_type(IndexType::Byte));
 
-            let report = match e.reason() {
-                chumsky::error::SimpleReason::Unclosed { span, delimiter } => report
-                    .with_message(format!(
-                        "Unclosed delimiter {}",
-                        delimiter.fg(Color::Yellow)
-                    ))
-                    .with_label(
-                        Label::new(span.clone())
-                            .with_message(format!(
-                                "Unclosed delimiter {}",
-                                delimiter.fg(Color::Yellow)
-                            ))
-                            .with_color(Color::Yellow),
-                    )
-                    .with_label(
-                        Label::new(e.span())
-                            .with_message(format!(
-                                "Must be closed before this {}",
-                                e.found()
-                                    .unwrap_or(&"end of file".to_string())
-                                    .fg(Color::Red)
-                            ))
-                            .with_color(Color::Red),
-                    ),
-                chumsky::error::SimpleReason::Unexpected => report
-                    .with_message(format!(
-                        "{}, expected {}",
-                        if e.found().is_some() {
-                            "Unexpected token in input"
-                        } else {
-                            "Unexpected end of input"
-                        },
-                        if e.expected().len() == 0 {
-                            "something else".to_string()
-                        } else {
-                            e.expected()
-                                .map(|expected| match expected {
-                                    Some(expected) => expected.to_string(),
-                                    None => "end of input".to_string(),
-                                })
-                                .collect::<Vec<_>>()
-                                .join(", ")
-                        }
-                    ))
-                    .with_label(
-                        Label::new(e.span())
-                            .with_message(format!(
-                                "Unexpected token {}",
-                                e.found()
-                                    .unwrap_or(&"end of file".to_string())
-                                    .fg(Color::Red)
-                            ))
-                            .with_color(Color::Red),
-                    ),
-                chumsky::error::SimpleReason::Custom(msg) => report.with_message(msg).with_label(
-                    Label::new(e.span())
-                        .with_message(format!("{}", msg.fg(Color::Red)))
+        let report = match e.reason() {
+            chumsky::error::SimpleReason::Unclosed {
+                span: delim_span,
+                delimiter,
+            } => report
+                .with_message(format!(
+                    "Unclosed delimiter {}",
+                    delimiter.fg(Color::Yellow)
+                ))
+                .with_label(
+                    Label::new(src.locate(delim_span))
+                        .with_message(format!(
+                            "Unclosed delimiter {}",
+                            delimiter.fg(Color::Yellow)
+                        ))
+                        .with_color(Color::Yellow),
+                )
+                .with_label(
+                    Label::new((path.clone(), span.clone()))
+                        .with_message(format!(
+                            "Must be closed before this {}",
+                            e.found()
+                                .unwrap_or(&"end of file".to_string())
+                                .fg(Color::Red)
+                        ))
                         .with_color(Color::Red),
                 ),
-            };
+            chumsky::error::SimpleReason::Unexpected => report
+                .with_message(format!(
+                    "{}, expected {}",
+                    if e.found().is_some() {
+                        "Unexpected token in input"
+                    } else {
+                        "Unexpected end of input"
+                    },
+                    if e.expected().len() == 0 {
+                        "something else".to_string()
+                    } else {
+                        e.expected()
+                            .map(|expected| match expected {
+                                Some(expected) => expected.to_string(),
+                                None => "end of input".to_string(),
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    }
+                ))
+                .with_label(
+                    Label::new((path.clone(), span.clone()))
+                        .with_message(format!(
+                            "Unexpected token {}",
+                            e.found()
+                                .unwrap_or(&"end of file".to_string())
+                                .fg(Color::Red)
+                        ))
+                        .with_color(Color::Red),
+                ),
+            chumsky::error::SimpleReason::Custom(msg) => report.with_message(msg).with_label(
+                Label::new((path, span))
+                    .with_message(format!("{}", msg.fg(Color::Red)))
+                    .with_color(Color::Red),
+            ),
+        };
+
+        report.finish().print(cache(&src)).unwrap();
+    });
+
+    check_errs
+        .into_iter()
+        .for_each(|diag| print_diagnostic(&diag, &src));
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// The sources of all files, for reports that point into any of them
+fn cache(src: &Sources) -> impl ariadne::Cache<String> {
+    let files: Vec<_> = src
+        .files
+        .iter()
+        .map(|f| (f.path.clone(), f.src.clone()))
+        .collect();
+    sources(files)
+}
 
-            report.finish().print(Source::from(&src)).unwrap();
-        });
+fn print_diagnostic(diag: &Diagnostic, src: &Sources) {
+    let (path, span) = src.locate(&diag.span);
+    let mut report = Report::build(ReportKind::Error, path, span.start)
+        .with_config(Config::default().with_index_type(IndexType::Byte))
+        .with_message(&diag.msg);
+    for (i, (span, msg)) in diag.labels.iter().enumerate() {
+        let color = if i == 0 { Color::Red } else { Color::Yellow };
+        report = report.with_label(
+            Label::new(src.locate(span))
+                .with_message(msg.as_str().fg(color))
+                .with_color(color),
+        );
+    }
+    if let Some(note) = &diag.note {
+        report = report.with_note(note);
+    }
+    report.finish().print(cache(src)).unwrap();
 }
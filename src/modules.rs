@@ -0,0 +1,598 @@
+use crate::{
+    diagnostic::Diagnostic,
+    parser::{funcs_parser, lex_from, Expr, Module, Pattern, Program, Span, Spanned},
+    resolve::closest,
+};
+use chumsky::{error::Simple, stream::Stream, Parser};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// A file of the program. Its spans are offset by `base`, so every byte of the program has its own position
+/// and spans don't need to remember which file they are in.
+pub struct SourceFile {
+    pub path: String,
+    pub base: usize,
+    pub src: String,
+}
+
+#[derive(Default)]
+pub struct Sources {
+    pub files: Vec<SourceFile>,
+}
+
+impl Sources {
+    fn add(&mut self, path: String, src: String) -> usize {
+        // Leave a gap so the end of one file is not the start of the next
+        let base = self.files.last().map_or(0, |f| f.base + f.src.len() + 1);
+        self.files.push(SourceFile { path, base, src });
+        base
+    }
+
+    /// The path of the file a span is in, and the span within that file
+    pub fn locate(&self, span: &Span) -> (String, Span) {
+        let file = self
+            .files
+            .iter()
+            .rev()
+            .find(|f| f.base <= span.start)
+            .expect("spans start at 0");
+        (
+            file.path.clone(),
+            span.start - file.base..span.end - file.base,
+        )
+    }
+}
+
+/// What a module declares, for checking the names other modules use
+#[derive(Default)]
+struct Exports {
+    /// Functions, constants and globals, which can be used as `module::name`
+    values: HashSet<String>,
+    items: HashSet<String>,
+}
+
+struct LoadedModule {
+    /// The file name without extension, empty for the main file whose names are not prefixed
+    name: String,
+    module: Module,
+    /// The modules this one imports, by name
+    imports: HashMap<String, usize>,
+}
+
+struct Loader {
+    sources: Sources,
+    /// In the order they finished loading, a module comes after everything it imports
+    modules: Vec<LoadedModule>,
+    by_path: HashMap<PathBuf, usize>,
+    /// The paths of the imported modules, by name
+    names: HashMap<String, String>,
+    /// The files currently being loaded, each imported by the one before it
+    loading: Vec<(PathBuf, String)>,
+    syntax_errs: Vec<Simple<String>>,
+    errs: Vec<Diagnostic>,
+}
+
+impl Loader {
+    /// Loads a file and everything it imports, returns its index in `modules` unless it could not be loaded
+    fn load(&mut self, path: &Path, import: Option<&Spanned<String>>) -> Option<usize> {
+        let shown = path.display().to_string();
+        let src = match (fs::read_to_string(path), import) {
+            (Ok(src), _) => src,
+            (Err(e), Some((_, span))) => {
+                self.errs.push(
+                    Diagnostic::new(span.clone(), format!("Can't import '{}': {}", shown, e))
+                        .with_label(span.clone(), "Imported here"),
+                );
+                return None;
+            }
+            (Err(e), None) => panic!("Failed to read file: {}", e),
+        };
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+
+        if let Some(pos) = self.loading.iter().position(|(p, _)| *p == canonical) {
+            let (_, span) = import.expect("the main file is loaded first");
+            let cycle: Vec<&str> = self.loading[pos..]
+                .iter()
+                .map(|(_, shown)| shown.as_str())
+                .chain([shown.as_str()])
+                .collect();
+            self.errs.push(
+                Diagnostic::new(span.clone(), "Import cycle")
+                    .with_label(
+                        span.clone(),
+                        "This import leads back to a file that imports it",
+                    )
+                    .with_note(format!("The imports form a cycle: {}", cycle.join(" -> "))),
+            );
+            return None;
+        }
+        if let Some(&idx) = self.by_path.get(&canonical) {
+            return Some(idx);
+        }
+
+        let name = match import {
+            None => String::new(),
+            Some((_, span)) => {
+                let name = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let is_ident = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                    && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+                if !is_ident {
+                    self.errs.push(
+                        Diagnostic::new(
+                            span.clone(),
+                            format!(
+                                "Can't import '{}', '{}' is not a valid module name",
+                                shown, name
+                            ),
+                        )
+                        .with_label(span.clone(), "Imported here")
+                        .with_note("The file name without extension is used as the module name"),
+                    );
+                    return None;
+                }
+                if let Some(other) = self.names.get(&name) {
+                    self.errs.push(
+                        Diagnostic::new(
+                            span.clone(),
+                            format!("Module name '{}' is already taken", name),
+                        )
+                        .with_label(span.clone(), "Imported here")
+                        .with_note(format!("'{}' is already imported as '{}'", other, name)),
+                    );
+                    return None;
+                }
+                self.names.insert(name.clone(), shown.clone());
+                name
+            }
+        };
+
+        let base = self.sources.add(shown.clone(), src);
+        let src = &self.sources.files.last().unwrap().src;
+        let (tokens, lex_errs) = lex_from(src, base);
+        let end = base + src.len();
+        let (module, parse_errs) =
+            funcs_parser().parse_recovery(Stream::from_iter(end..end, tokens.into_iter()));
+        let failed = !lex_errs.is_empty() || !parse_errs.is_empty();
+        self.syntax_errs.extend(
+            lex_errs
+                .into_iter()
+                .map(|e| e.map(|c| c.to_string()))
+                .chain(parse_errs.into_iter().map(|e| e.map(|tok| tok.to_string()))),
+        );
+        let module = module.filter(|_| !failed)?;
+
+        self.loading.push((canonical.clone(), shown.clone()));
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut imports = HashMap::new();
+        for import in &module.imports {
+            if let Some(idx) = self.load(&dir.join(&import.0), Some(import)) {
+                imports.insert(self.modules[idx].name.clone(), idx);
+            }
+        }
+        self.loading.pop();
+
+        self.modules.push(LoadedModule {
+            name,
+            module,
+            imports,
+        });
+        self.by_path.insert(canonical, self.modules.len() - 1);
+        Some(self.modules.len() - 1)
+    }
+}
+
+/// The names a module can refer to
+struct Scope<'a> {
+    /// Items usable without a module name, the ones the module declares and the ones it uses,
+    /// with their names in the merged program
+    items: HashMap<String, String>,
+    /// The imported modules, by name
+    modules: HashMap<&'a str, &'a Exports>,
+    errs: &'a mut Vec<Diagnostic>,
+}
+
+impl Scope<'_> {
+    fn rename(&self, name: &mut String) {
+        if let Some(global) = self.items.get(name) {
+            *name = global.clone();
+        }
+    }
+
+    /// The merged name of `module::name` if `module` is an imported module rather than an enum
+    fn module_value(
+        &mut self,
+        (module, _): &Spanned<String>,
+        (name, span): &Spanned<String>,
+    ) -> Option<String> {
+        if self.items.contains_key(module) {
+            return None;
+        }
+        let exports = self.modules.get(module.as_str())?;
+        if !exports.values.contains(name) {
+            let mut diag = Diagnostic::new(
+                span.clone(),
+                format!("Module '{}' has no function or constant '{}'", module, name),
+            )
+            .with_label(span.clone(), "Unknown name");
+            if let Some(suggestion) =
+                closest(name, exports.values.iter().map(String::as_str).collect())
+            {
+                diag = diag.with_note(format!("Did you mean '{}'?", suggestion));
+            }
+            self.errs.push(diag);
+        }
+        Some(format!("{}::{}", module, name))
+    }
+
+    /// Replaces `a::b` by a variant or by a function or constant of another module
+    fn path(&mut self, expr: Expr, span: &Span) -> Expr {
+        match expr {
+            Expr::Path(a, b) => match self.module_value(&a, &b) {
+                Some(func) => Expr::LocalVar(func),
+                None => Expr::Variant(a, b, Vec::new()),
+            },
+            Expr::Variant(a, b, args) => match self.module_value(&a, &b) {
+                Some(func) => {
+                    let callee = (Expr::LocalVar(func), a.1.start..b.1.end);
+                    Expr::Call(Box::new(callee), (args, b.1.end..span.end))
+                }
+                None => Expr::Variant(a, b, args),
+            },
+            expr => expr,
+        }
+    }
+
+    fn pattern(&self, pat: &mut Pattern) {
+        if let Pattern::Variant((name, _), _, pats) = pat {
+            self.rename(name);
+            pats.iter_mut().for_each(|(pat, _)| self.pattern(pat));
+        }
+    }
+
+    /// Gives every item the expression refers to its name in the merged program.
+    /// Variables in `bound` shadow items, like in [`Expr::free_vars`].
+    fn qualify(&mut self, expr: &mut Spanned<Expr>, bound: &mut Vec<String>) {
+        let depth = bound.len();
+        let old = std::mem::replace(&mut expr.0, Expr::Error);
+        expr.0 = self.path(old, &expr.1);
+        match &mut expr.0 {
+            Expr::LocalVar(name) if !bound.contains(name) => self.rename(name),
+            Expr::Assign(name, val, body) => {
+                if !bound.contains(name) {
+                    self.rename(name);
+                }
+                self.qualify(val, bound);
+                self.qualify(body, bound);
+            }
+            Expr::Let(name, val, body) | Expr::For(name, val, body) => {
+                self.qualify(val, bound);
+                bound.push(name.clone());
+                self.qualify(body, bound);
+            }
+            Expr::Destructure(names, val, body) => {
+                self.qualify(val, bound);
+                bound.extend(names.iter().map(|(name, _)| name.clone()));
+                self.qualify(body, bound);
+            }
+            Expr::Closure(args, body) => {
+                bound.extend(args.iter().cloned());
+                if let Some(body) = Rc::get_mut(body) {
+                    self.qualify(body, bound);
+                }
+            }
+            Expr::Match(val, arms) => {
+                self.qualify(val, bound);
+                for ((pat, _), body) in arms {
+                    self.pattern(pat);
+                    let mut names = Vec::new();
+                    pat.bound_names(&mut names);
+                    bound.extend(names.into_iter().map(String::from));
+                    self.qualify(body, bound);
+                    bound.truncate(depth);
+                }
+            }
+            expr => {
+                if let Expr::Struct((name, _), _) | Expr::Variant((name, _), _, _) = expr {
+                    self.rename(name);
+                }
+                for child in expr.children_mut() {
+                    self.qualify(child, bound);
+                }
+            }
+        }
+        bound.truncate(depth);
+    }
+}
+
+/// Merges the loaded modules into one program. Items of imported modules are named `module::item`,
+/// every reference to them is renamed to match.
+fn merge(modules: Vec<LoadedModule>, errs: &mut Vec<Diagnostic>) -> Program {
+    let exports: Vec<Exports> = modules
+        .iter()
+        .map(|m| {
+            let program = &m.module.program;
+            Exports {
+                values: (program.funcs.keys())
+                    .chain(program.globals.keys())
+                    .cloned()
+                    .collect(),
+                items: (program.funcs.keys())
+                    .chain(program.globals.keys())
+                    .chain(program.structs.keys())
+                    .chain(program.enums.keys())
+                    .cloned()
+                    .collect(),
+            }
+        })
+        .collect();
+    let mut merged = Program {
+        funcs: HashMap::new(),
+        structs: HashMap::new(),
+        enums: HashMap::new(),
+        globals: HashMap::new(),
+    };
+    for loaded in modules {
+        let prefix = |name: &str| match loaded.name.as_str() {
+            "" => name.to_string(),
+            module => format!("{}::{}", module, name),
+        };
+        let Module {
+            program,
+            uses,
+            imports: _,
+        } = loaded.module;
+
+        let mut items: HashMap<String, String> = (program.funcs.keys())
+            .chain(program.globals.keys())
+            .chain(program.structs.keys())
+            .chain(program.enums.keys())
+            .map(|name| (name.clone(), prefix(name)))
+            .collect();
+        for ((module, module_span), (name, span)) in uses {
+            let Some(&idx) = loaded.imports.get(&module) else {
+                errs.push(
+                    Diagnostic::new(
+                        module_span.clone(),
+                        format!("Module '{}' is not imported", module),
+                    )
+                    .with_label(module_span, "Unknown module")
+                    .with_note(format!("Import it with `import \"{}.grsp\";`", module)),
+                );
+                continue;
+            };
+            if !exports[idx].items.contains(&name) {
+                let mut diag = Diagnostic::new(
+                    span.clone(),
+                    format!("Module '{}' has no item '{}'", module, name),
+                )
+                .with_label(span, "Unknown item");
+                if let Some(suggestion) = closest(
+                    &name,
+                    exports[idx].items.iter().map(String::as_str).collect(),
+                ) {
+                    diag = diag.with_note(format!("Did you mean '{}'?", suggestion));
+                }
+                errs.push(diag);
+            } else if items.contains_key(&name) {
+                errs.push(
+                    Diagnostic::new(
+                        span.clone(),
+                        format!("'{}' is already defined in this file", name),
+                    )
+                    .with_label(span, "Used here"),
+                );
+            } else {
+                items.insert(name.clone(), format!("{}::{}", module, name));
+            }
+        }
+
+        let mut scope = Scope {
+            items,
+            modules: (loaded.imports.iter())
+                .map(|(name, &idx)| (name.as_str(), &exports[idx]))
+                .collect(),
+            errs,
+        };
+        for (name, mut func) in program.funcs {
+            let mut bound = func.args.clone();
+            scope.qualify(&mut func.body, &mut bound);
+            merged.funcs.insert(prefix(&name), func);
+        }
+        for (name, mut global) in program.globals {
+            scope.qualify(&mut global.value, &mut Vec::new());
+            merged.globals.insert(prefix(&name), global);
+        }
+        for (name, s) in program.structs {
+            merged.structs.insert(prefix(&name), s);
+        }
+        for (name, e) in program.enums {
+            merged.enums.insert(prefix(&name), e);
+        }
+    }
+    // Items are visited in hash map order, the errors are reported in source order
+    errs.sort_by_key(|diag| diag.span.start);
+    merged
+}
+
+/// The result of loading a program and everything it imports
+pub struct Loaded {
+    pub sources: Sources,
+    /// The merged program, if all files could be loaded and parsed
+    pub program: Option<Program>,
+    pub syntax_errs: Vec<Simple<String>>,
+    pub errs: Vec<Diagnostic>,
+}
+
+/// Loads the file at `path` and the files it imports, relative to the importing file, and merges them into
+/// one program
+pub fn load(path: &str) -> Loaded {
+    let mut loader = Loader {
+        sources: Sources::default(),
+        modules: Vec::new(),
+        by_path: HashMap::new(),
+        names: HashMap::new(),
+        loading: Vec::new(),
+        syntax_errs: Vec::new(),
+        errs: Vec::new(),
+    };
+    let loaded = loader.load(Path::new(path), None);
+    let mut errs = loader.errs;
+    let program = loaded
+        .filter(|_| loader.syntax_errs.is_empty() && errs.is_empty())
+        .map(|_| merge(loader.modules, &mut errs))
+        .filter(|_| errs.is_empty());
+    Loaded {
+        sources: loader.sources,
+        program,
+        syntax_errs: loader.syntax_errs,
+        errs,
+    }
+}
+
+/// Writes the files into a new directory and loads the first one, for the tests of the passes
+#[cfg(test)]
+pub fn load_files(files: &[(&str, &str)]) -> Loaded {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "gruenspan-test-{}-{}",
+        std::process::id(),
+        DIRS.fetch_add(1, Ordering::Relaxed)
+    ));
+    for (name, src) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, src).unwrap();
+    }
+    let loaded = load(dir.join(files[0].0).to_str().unwrap());
+    fs::remove_dir_all(dir).unwrap();
+    loaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The message and note of every error found while loading the files
+    fn errors(files: &[(&str, &str)]) -> Vec<(String, Option<String>)> {
+        let loaded = load_files(files);
+        assert!(loaded.syntax_errs.is_empty(), "{:?}", loaded.syntax_errs);
+        loaded
+            .errs
+            .into_iter()
+            .map(|diag| (diag.msg, diag.note))
+            .collect()
+    }
+
+    #[test]
+    fn imports_are_merged_under_their_module_name() {
+        let loaded = load_files(&[
+            ("main.grsp", "import \"util.grsp\";\nimport \"lib/shapes.grsp\";\nuse util::helper;\nfn main() { helper(util::TWO) + shapes::side() }"),
+            ("util.grsp", "import \"lib/shapes.grsp\";\nconst TWO = 2\nfn helper(x) { x * shapes::side() }"),
+            ("lib/shapes.grsp", "fn side() { 4 }"),
+        ]);
+        assert!(loaded.errs.is_empty(), "{:?}", loaded.errs);
+        let program = loaded.program.expect("the program loads");
+        let mut funcs: Vec<&str> = program.funcs.keys().map(String::as_str).collect();
+        funcs.sort();
+        // The file imported twice is only loaded once
+        assert_eq!(funcs, vec!["main", "shapes::side", "util::helper"]);
+        assert_eq!(loaded.sources.files.len(), 3);
+        assert!(program.globals.contains_key("util::TWO"));
+    }
+
+    #[test]
+    fn import_cycles_are_rejected() {
+        let errs = errors(&[
+            ("main.grsp", "import \"a.grsp\";\nfn main() { a::fa() }"),
+            ("a.grsp", "import \"b.grsp\";\nfn fa() { 1 }"),
+            ("b.grsp", "import \"a.grsp\";\nfn fb() { 1 }"),
+        ]);
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert_eq!(errs[0].0, "Import cycle");
+        let note = errs[0].1.as_deref().unwrap();
+        let files: Vec<&str> = note
+            .strip_prefix("The imports form a cycle: ")
+            .unwrap()
+            .split(" -> ")
+            .map(|path| Path::new(path).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(files, vec!["a.grsp", "b.grsp", "a.grsp"]);
+
+        let errs = errors(&[("main.grsp", "import \"main.grsp\";\nfn main() { 0 }")]);
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert_eq!(errs[0].0, "Import cycle");
+    }
+
+    #[test]
+    fn unknown_imports_and_items() {
+        let errs = errors(&[("main.grsp", "import \"missing.grsp\";\nfn main() { 0 }")]);
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert!(errs[0].0.starts_with("Can't import '"), "{}", errs[0].0);
+
+        assert_eq!(
+            errors(&[
+                ("main.grsp", "import \"util.grsp\";\nuse util::helpr;\nuse other::x;\nfn main() { util::twice(1) }"),
+                ("util.grsp", "fn helper(x) { x }\nfn twice(x) { x * 2 }"),
+            ]),
+            vec![
+                (
+                    "Module 'util' has no item 'helpr'".to_string(),
+                    Some("Did you mean 'helper'?".to_string())
+                ),
+                (
+                    "Module 'other' is not imported".to_string(),
+                    Some("Import it with `import \"other.grsp\";`".to_string())
+                ),
+            ]
+        );
+        assert_eq!(
+            errors(&[
+                (
+                    "main.grsp",
+                    "import \"util.grsp\";\nfn main() { util::twcie(1) }"
+                ),
+                ("util.grsp", "fn twice(x) { x * 2 }"),
+            ]),
+            vec![(
+                "Module 'util' has no function or constant 'twcie'".to_string(),
+                Some("Did you mean 'twice'?".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn errors_are_in_source_order() {
+        let errs = errors(&[
+            (
+                "main.grsp",
+                "import \"util.grsp\";\nuse util::twice;\nuse util::nope;\n\
+                 fn twice(x) { util::a() }\nfn b() { util::b() }\nfn c() { util::c() }\n\
+                 fn d() { util::d() }\nfn e() { util::e() }\nfn main() { util::f() }",
+            ),
+            ("util.grsp", "fn twice(x) { x * 2 }"),
+        ]);
+        let msgs: Vec<&str> = errs.iter().map(|(msg, _)| msg.as_str()).collect();
+        assert_eq!(
+            msgs,
+            vec![
+                "'twice' is already defined in this file",
+                "Module 'util' has no item 'nope'",
+                "Module 'util' has no function or constant 'a'",
+                "Module 'util' has no function or constant 'b'",
+                "Module 'util' has no function or constant 'c'",
+                "Module 'util' has no function or constant 'd'",
+                "Module 'util' has no function or constant 'e'",
+                "Module 'util' has no function or constant 'f'",
+            ]
+        );
+    }
+}
@@ -1,24 +1,132 @@
-use chumsky::{prelude::*, primitive::Just};
+use chumsky::{prelude::*, Stream};
+use logos::{Logos, Skip};
 use paste::paste;
-use std::{collections::HashMap, fmt};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+};
 pub type Span = std::ops::Range<usize>;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Logos, Clone, Debug, PartialEq, Eq, Hash)]
+#[logos(skip r"[ \t\r\n\f]+")]
+#[logos(skip r"//[^\n]*")]
+#[logos(extras = LexExtras)]
+#[logos(error = LexError)]
 pub enum Token {
+    #[token("null")]
     Null,
+    #[token("true", |_| true)]
+    #[token("false", |_| false)]
     Bool(bool),
-    Num(String),
-    Str(String),
+    #[regex(r"[0-9][0-9_]*", lex_int, priority = 3)]
+    #[regex(r"0[xX][0-9a-fA-F_]+", lex_int, priority = 3)]
+    #[regex(r"0[oO][0-7_]+", lex_int, priority = 3)]
+    #[regex(r"0[bB][01_]+", lex_int, priority = 3)]
+    // Anything else that starts with a digit is a malformed number rather than a number followed by an identifier
+    #[regex(
+        r"[0-9][0-9a-zA-Z_]*(\.[0-9][0-9a-zA-Z_]*)?",
+        malformed_number,
+        priority = 1
+    )]
+    Int(i64),
+    #[regex(
+        r"[0-9][0-9_]*\.[0-9][0-9_]*([eE][+-]?[0-9_]+)?",
+        lex_float,
+        priority = 3
+    )]
+    #[regex(r"[0-9][0-9_]*[eE][+-]?[0-9_]+", lex_float, priority = 3)]
+    Float(Float),
+    #[token("\"", lex_string)]
+    Str(Vec<StrPart>),
+    #[token("+", |lex| lex.slice().to_owned())]
+    #[token("-", |lex| lex.slice().to_owned())]
+    #[token("*", |lex| lex.slice().to_owned())]
+    #[token("/", |lex| lex.slice().to_owned())]
+    #[token("%", |lex| lex.slice().to_owned())]
+    #[token("=", |lex| lex.slice().to_owned())]
+    #[token("==", |lex| lex.slice().to_owned())]
+    #[token("!=", |lex| lex.slice().to_owned())]
+    #[token("<", |lex| lex.slice().to_owned())]
+    #[token(">", |lex| lex.slice().to_owned())]
+    #[token("<=", |lex| lex.slice().to_owned())]
+    #[token(">=", |lex| lex.slice().to_owned())]
+    #[token("&&", |lex| lex.slice().to_owned())]
+    #[token("||", |lex| lex.slice().to_owned())]
+    #[token("!", |lex| lex.slice().to_owned())]
+    #[token("@", |lex| lex.slice().to_owned())]
+    #[token("+=", |lex| lex.slice().to_owned())]
+    #[token("-=", |lex| lex.slice().to_owned())]
+    #[token("*=", |lex| lex.slice().to_owned())]
+    #[token("/=", |lex| lex.slice().to_owned())]
+    #[token("%=", |lex| lex.slice().to_owned())]
+    #[token("&", |lex| lex.slice().to_owned())]
+    #[token("|", |lex| lex.slice().to_owned())]
+    #[token("^", |lex| lex.slice().to_owned())]
+    #[token("~", |lex| lex.slice().to_owned())]
+    #[token("<<", |lex| lex.slice().to_owned())]
+    #[token(">>", |lex| lex.slice().to_owned())]
+    #[token("&=", |lex| lex.slice().to_owned())]
+    #[token("|=", |lex| lex.slice().to_owned())]
+    #[token("^=", |lex| lex.slice().to_owned())]
+    #[token("<<=", |lex| lex.slice().to_owned())]
+    #[token(">>=", |lex| lex.slice().to_owned())]
+    #[token("..", |lex| lex.slice().to_owned())]
+    #[token("..=", |lex| lex.slice().to_owned())]
     Op(String),
+    #[regex(r"[()\[\]{};,:.]", |lex| lex.slice().chars().next())]
     Ctrl(char),
+    #[token("->")]
+    Arrow,
+    #[token("=>")]
+    FatArrow,
+    #[token("::")]
+    PathSep,
+    #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_owned())]
     Ident(String),
+    /// `////` and more slashes start an ordinary comment, like a banner line
+    #[regex(r"///([^/\n][^\n]*)?", |lex| lex.slice()[3..].trim().to_owned())]
+    Doc(String),
+    /// Never produced: block comments are skipped by their callback
+    #[token("/*", block_comment)]
+    BlockComment,
+    #[token("fn")]
     Fn,
+    #[token("let")]
     Let,
+    #[token("print")]
     Print,
+    #[token("if")]
     If,
+    #[token("else")]
     Else,
+    #[token("return")]
     Return,
+    #[token("loop")]
     Loop,
+    #[token("break")]
+    Break,
+    #[token("continue")]
+    Continue,
+    #[token("for")]
+    For,
+    #[token("in")]
+    In,
+    #[token("struct")]
+    Struct,
+    #[token("enum")]
+    Enum,
+    #[token("match")]
+    Match,
+    #[token("import")]
+    Import,
+    #[token("use")]
+    Use,
+    #[token("const")]
+    Const,
+    #[token("global")]
+    Global,
 }
 
 impl fmt::Display for Token {
@@ -26,11 +134,26 @@ impl fmt::Display for Token {
         match self {
             Token::Null => write!(f, "null"),
             Token::Bool(x) => write!(f, "{}", x),
-            Token::Num(n) => write!(f, "{}", n),
-            Token::Str(s) => write!(f, "{}", s),
+            Token::Int(n) => write!(f, "{}", n),
+            Token::Float(x) => write!(f, "{}", x.0),
+            Token::Str(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        StrPart::Lit(s) => write!(f, "{}", s)?,
+                        StrPart::Expr(_) => write!(f, "{{..}}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
             Token::Op(s) => write!(f, "{}", s),
             Token::Ctrl(c) => write!(f, "{}", c),
+            Token::Arrow => write!(f, "->"),
+            Token::FatArrow => write!(f, "=>"),
+            Token::PathSep => write!(f, "::"),
             Token::Ident(s) => write!(f, "{}", s),
+            Token::Doc(s) => write!(f, "/// {}", s),
+            Token::BlockComment => write!(f, "/* */"),
             Token::Fn => write!(f, "fn"),
             Token::Let => write!(f, "let"),
             Token::Print => write!(f, "print"),
@@ -38,74 +161,427 @@ impl fmt::Display for Token {
             Token::Else => write!(f, "else"),
             Token::Return => write!(f, "return"),
             Token::Loop => write!(f, "loop"),
+            Token::Break => write!(f, "break"),
+            Token::Continue => write!(f, "continue"),
+            Token::For => write!(f, "for"),
+            Token::In => write!(f, "in"),
+            Token::Struct => write!(f, "struct"),
+            Token::Enum => write!(f, "enum"),
+            Token::Match => write!(f, "match"),
+            Token::Import => write!(f, "import"),
+            Token::Use => write!(f, "use"),
+            Token::Const => write!(f, "const"),
+            Token::Global => write!(f, "global"),
         }
     }
 }
 
-pub fn lexer() -> impl Parser<char, Vec<(Token, Span)>, Error = Simple<char>> {
-    // A parser for numbers
-    let num = text::int(10)
-        .chain::<char, _, _>(just('.').chain(text::digits(10)).or_not().flatten())
-        .collect::<String>()
-        .map(Token::Num);
+/// Every operator the lexer knows. Longer operators win over their prefixes, so `<=` is one token but `<-` is `<` followed by `-`.
+pub const OPERATORS: &[&str] = &[
+    "+", "-", "*", "/", "%", "=", "==", "!=", "<", ">", "<=", ">=", "&&", "||", "!", "@", "+=",
+    "-=", "*=", "/=", "%=", "&", "|", "^", "~", "<<", ">>", "&=", "|=", "^=", "<<=", ">>=", "..",
+    "..=",
+];
 
-    // A parser for strings
-    let str_ = just('"')
-        .ignore_then(filter(|c| *c != '"').repeated())
-        .then_ignore(just('"'))
-        .collect::<String>()
-        .map(Token::Str);
+fn is_operator_char(c: char) -> bool {
+    "+-*/%=!<>&|@^~".contains(c)
+}
 
-    // A parser for operators
-    let op = one_of("+-*/!=<>@")
-        .repeated()
-        .at_least(1)
-        .collect::<String>()
-        .map(Token::Op);
-
-    // A parser for control characters (delimiters, semicolons, etc.)
-    let ctrl = one_of("()[]{};,").map(|c| Token::Ctrl(c));
-
-    // A parser for identifiers and keywords
-    let ident = text::ident().map(|ident: String| match ident.as_str() {
-        "fn" => Token::Fn,
-        "return" => Token::Return,
-        "let" => Token::Let,
-        "print" => Token::Print,
-        "if" => Token::If,
-        "else" => Token::Else,
-        "true" => Token::Bool(true),
-        "false" => Token::Bool(false),
-        "null" => Token::Null,
-        "loop" => Token::Loop,
-        _ => Token::Ident(ident),
-    });
+/// A float literal. Tokens have to be hashable, so literals compare by their bits.
+#[derive(Clone, Copy, Debug)]
+pub struct Float(pub f64);
 
-    // A single token can be one of the above
-    let token = num
-        .or(str_)
-        .or(op)
-        .or(ctrl)
-        .or(ident)
-        .recover_with(skip_then_retry_until([]));
+impl PartialEq for Float {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
 
-    let comment = just("//").then(take_until(just('\n'))).padded();
+impl Eq for Float {}
 
-    token
-        .padded_by(comment.repeated())
-        .map_with_span(|tok, span| (tok, span))
-        .padded()
-        .repeated()
+impl std::hash::Hash for Float {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state)
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub enum LexError {
+    #[default]
+    UnexpectedChar,
+    MalformedNumber(String),
+}
+
+/// Splits a `0x`/`0o`/`0b` prefix off an integer literal
+fn split_radix(lit: &str) -> (u32, &str) {
+    match lit.get(..2) {
+        Some("0x" | "0X") => (16, &lit[2..]),
+        Some("0o" | "0O") => (8, &lit[2..]),
+        Some("0b" | "0B") => (2, &lit[2..]),
+        _ => (10, lit),
+    }
+}
+
+fn lex_int(lex: &mut logos::Lexer<Token>) -> Result<i64, LexError> {
+    let (radix, digits) = split_radix(lex.slice());
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return Err(LexError::MalformedNumber(format!(
+            "Number literal '{}' has no digits",
+            lex.slice()
+        )));
+    }
+    // Hex, octal and binary literals give the bits, so `0xFFFF_FFFF_FFFF_FFFF` is `-1`
+    let n = match radix {
+        10 => i64::from_str_radix(&digits, radix).ok(),
+        _ => u64::from_str_radix(&digits, radix).ok().map(|n| n as i64),
+    };
+    n.ok_or_else(|| {
+        LexError::MalformedNumber(format!(
+            "Integer literal '{}' does not fit into 64 bits",
+            lex.slice()
+        ))
+    })
+}
+
+fn lex_float(lex: &mut logos::Lexer<Token>) -> Result<Float, LexError> {
+    lex.slice()
+        .replace('_', "")
+        .parse()
+        .map(Float)
+        .map_err(|_| {
+            LexError::MalformedNumber(format!("Malformed float literal '{}'", lex.slice()))
+        })
+}
+
+fn malformed_number(lex: &mut logos::Lexer<Token>) -> Result<i64, LexError> {
+    let lit = lex.slice();
+    let (radix, digits) = split_radix(lit);
+    let bad_digit = digits
+        .chars()
+        .find(|c| !(c.is_digit(radix) || *c == '_' || (radix == 10 && *c == '.')));
+    Err(LexError::MalformedNumber(match bad_digit {
+        Some(c) => format!("Invalid digit '{}' in base {} literal '{}'", c, radix, lit),
+        None => format!("Malformed number literal '{}'", lit),
+    }))
+}
+
+/// One piece of a string literal: either plain text or the tokens of an interpolated `{expr}`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StrPart {
+    Lit(String),
+    Expr(Vec<(Token, Span)>),
+}
+
+/// State threaded through the logos lexer. `base` is the offset of the lexed source within the file, so
+/// interpolations lexed on their own still get spans into the original input.
+#[derive(Default)]
+pub struct LexExtras {
+    base: usize,
+    errs: Vec<Simple<char>>,
+}
+
+fn lex_string(lex: &mut logos::Lexer<Token>) -> Vec<StrPart> {
+    let rest = lex.remainder();
+    // Absolute position of `rest` in the file
+    let start = lex.extras.base + lex.span().end;
+    let mut parts = Vec::new();
+    let mut lit = String::new();
+    let mut i = 0;
+    while let Some(c) = rest[i..].chars().next() {
+        match c {
+            '"' => {
+                lex.bump(i + 1);
+                if !lit.is_empty() || parts.is_empty() {
+                    parts.push(StrPart::Lit(lit));
+                }
+                return parts;
+            }
+            '\\' => {
+                let (escaped, len) = lex_escape(&rest[i + 1..]);
+                match escaped {
+                    Some(escaped) => lit.push(escaped),
+                    None => lex.extras.errs.push(Simple::custom(
+                        start + i..start + i + 1 + len,
+                        format!("Invalid escape sequence '{}'", &rest[i..i + 1 + len]),
+                    )),
+                }
+                i += 1 + len;
+            }
+            '{' => {
+                let Some(len) = interpolation_len(&rest[i + 1..]) else {
+                    lex.extras.errs.push(Simple::custom(
+                        start + i..start + i + 1,
+                        "Unterminated interpolation in string literal",
+                    ));
+                    // The string ends at the next quote instead of swallowing the rest of the file
+                    match rest[i + 1..].find('"') {
+                        Some(quote) => {
+                            i += 1 + quote;
+                            continue;
+                        }
+                        None => break,
+                    }
+                };
+                let inner = &rest[i + 1..i + 1 + len];
+                if inner.trim().is_empty() {
+                    lex.extras.errs.push(Simple::custom(
+                        start + i..start + i + len + 2,
+                        "Empty interpolation in string literal",
+                    ));
+                } else {
+                    let (tokens, errs) = lex_from(inner, start + i + 1);
+                    lex.extras.errs.extend(errs);
+                    if !lit.is_empty() {
+                        parts.push(StrPart::Lit(std::mem::take(&mut lit)));
+                    }
+                    parts.push(StrPart::Expr(tokens));
+                }
+                i += len + 2;
+            }
+            c => {
+                lit.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    // Running out of input swallows the rest of the file, so point at the opening quote instead
+    lex.bump(rest.len());
+    lex.extras.errs.push(Simple::custom(
+        start - 1..start,
+        "Unterminated string literal",
+    ));
+    parts
+}
+
+/// Skips a (possibly nested) block comment
+fn block_comment(lex: &mut logos::Lexer<Token>) -> Skip {
+    let rest = lex.remainder();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < rest.len() {
+        if rest[i..].starts_with("*/") {
+            if depth == 0 {
+                lex.bump(i + 2);
+                return Skip;
+            }
+            depth -= 1;
+            i += 2;
+        } else if rest[i..].starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else {
+            i += rest[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    let start = lex.extras.base + lex.span().start;
+    lex.bump(rest.len());
+    lex.extras.errs.push(Simple::custom(
+        start..start + 2,
+        "Unterminated block comment",
+    ));
+    Skip
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Decodes the escape sequence following a backslash, returning the character (if valid) and how many bytes it spans
+fn lex_escape(rest: &str) -> (Option<char>, usize) {
+    let Some(c) = rest.chars().next() else {
+        return (None, 0);
+    };
+    match c {
+        'n' => (Some('\n'), 1),
+        't' => (Some('\t'), 1),
+        'r' => (Some('\r'), 1),
+        '0' => (Some('\0'), 1),
+        '"' | '\\' | '{' | '}' => (Some(c), 1),
+        'u' => {
+            let Some(len) = rest.strip_prefix("u{").and_then(|hex| hex.find('}')) else {
+                return (None, 1);
+            };
+            let ch = u32::from_str_radix(&rest[2..2 + len], 16)
+                .ok()
+                .and_then(char::from_u32);
+            (ch, len + 3)
+        }
+        c => (None, c.len_utf8()),
+    }
+}
+
+/// Finds the `}` closing an interpolation, skipping nested braces and string literals
+fn interpolation_len(rest: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_str = false;
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if in_str => {
+                chars.next();
+            }
+            '"' => in_str = !in_str,
+            '{' if !in_str => depth += 1,
+            '}' if !in_str && depth == 0 => return Some(i),
+            '}' if !in_str => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits the source into tokens with their byte spans.
+/// Characters that don't start any token are reported and skipped, so lexing always runs to the end of the input.
+pub fn lexer(src: &str) -> (Vec<(Token, Span)>, Vec<Simple<char>>) {
+    lex_from(src, 0)
+}
+
+/// Lexes source that starts at `base` within the input, all spans are offset by it
+pub fn lex_from(src: &str, base: usize) -> (Vec<(Token, Span)>, Vec<Simple<char>>) {
+    let mut lex = Token::lexer_with_extras(
+        src,
+        LexExtras {
+            base,
+            errs: Vec::new(),
+        },
+    );
+    let shift = |span: Span| span.start + base..span.end + base;
+    let mut tokens = Vec::new();
+    // Stray operator characters are collected into one span so `a &|& b` gets a single diagnostic
+    let mut bad_op: Option<Span> = None;
+    while let Some(tok) = lex.next() {
+        let span = lex.span();
+        match tok {
+            Err(LexError::UnexpectedChar) if src[span.clone()].chars().all(is_operator_char) => {
+                bad_op = match bad_op {
+                    Some(op) if op.end == span.start => Some(op.start..span.end),
+                    op => {
+                        let errs = &mut lex.extras.errs;
+                        errs.extend(op.map(|op| unknown_operator(&src[op.clone()], shift(op))));
+                        Some(span)
+                    }
+                }
+            }
+            tok => {
+                let errs = &mut lex.extras.errs;
+                errs.extend(
+                    bad_op
+                        .take()
+                        .map(|op| unknown_operator(&src[op.clone()], shift(op))),
+                );
+                match tok {
+                    Ok(tok) => tokens.push((tok, shift(span))),
+                    Err(LexError::UnexpectedChar) => errs.push(Simple::expected_input_found(
+                        shift(span.clone()),
+                        Vec::new(),
+                        src[span].chars().next(),
+                    )),
+                    // A placeholder keeps the parser from reporting the gap the literal leaves
+                    Err(LexError::MalformedNumber(msg)) => {
+                        errs.push(Simple::custom(shift(span.clone()), msg));
+                        tokens.push((Token::Int(0), shift(span)));
+                    }
+                }
+            }
+        }
+    }
+    let mut errs = lex.extras.errs;
+    errs.extend(bad_op.map(|op| unknown_operator(&src[op.clone()], shift(op))));
+    (documented_items_only(tokens), errs)
+}
+
+/// Drops the doc comments that don't come right before an item, they are ordinary comments then
+fn documented_items_only(tokens: Vec<(Token, Span)>) -> Vec<(Token, Span)> {
+    let mut item_follows = false;
+    let mut tokens: Vec<_> = tokens
+        .into_iter()
+        .rev()
+        .filter(|(tok, _)| match tok {
+            Token::Doc(_) => item_follows,
+            tok => {
+                item_follows = matches!(
+                    tok,
+                    Token::Fn | Token::Struct | Token::Enum | Token::Const | Token::Global
+                );
+                true
+            }
+        })
+        .collect();
+    tokens.reverse();
+    tokens
+}
+
+fn unknown_operator(op: &str, span: Span) -> Simple<char> {
+    let msg = format!(
+        "Unknown operator '{}', expected one of {}",
+        op,
+        OPERATORS.join(" ")
+    );
+    Simple::custom(span, msg)
+}
+
+#[derive(Clone, Debug)]
 pub enum Value {
     Null,
     Bool(bool),
+    Int(i64),
     Num(f64),
     Str(String),
-    List(Vec<Value>),
+    /// Lists are shared, so pushing to one is visible through every value that refers to it
+    List(Rc<RefCell<Vec<Value>>>),
+    /// An instance of the named struct. Shared like lists, fields are ordered by name
+    Struct(String, Rc<RefCell<BTreeMap<String, Value>>>),
+    /// A variant of the named enum with its payload
+    Variant(String, String, Vec<Value>),
+    /// Tuples can't be changed, so unlike lists they are not shared
+    Tuple(Vec<Value>),
+    /// Maps are shared like lists and iterate in the order of their keys
+    Map(Rc<RefCell<BTreeMap<Key, Value>>>),
     Func(String),
+    Closure(Rc<Closure>),
+}
+
+/// An anonymous function together with the variables it captured when it was created
+#[derive(Debug)]
+pub struct Closure {
+    pub args: Vec<String>,
+    pub body: Rc<Spanned<Expr>>,
+    pub captures: Vec<(String, Var)>,
+}
+
+/// A variable of the evaluator. Closures share the variables they capture with the scope they were created in.
+pub type Var = Rc<RefCell<Value>>;
+
+/// Puts a new variable on the stack
+fn bind(stack: &mut Vec<(String, Var)>, name: &str, val: Value) {
+    stack.push((name.to_string(), Rc::new(RefCell::new(val))));
+}
+
+/// An integer equals the float with the same value
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Struct(a, a_fields), Value::Struct(b, b_fields)) => {
+                a == b && a_fields == b_fields
+            }
+            (Value::Variant(a, a_variant, a_vals), Value::Variant(b, b_variant, b_vals)) => {
+                a == b && a_variant == b_variant && a_vals == b_vals
+            }
+            (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Func(a), Value::Func(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => a == b,
+            _ => matches!((self.num(), other.num()), (Some(a), Some(b)) if a == b),
+        }
+    }
+}
+
+/// Closures are only equal to themselves
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
 }
 
 macro_rules! impl_value_methods {
@@ -127,43 +603,442 @@ macro_rules! impl_value_methods {
         }
     };
 }
-impl_value_methods!(Value, Num(f64), Str(String), Bool(bool), List(Vec<Value>));
+impl_value_methods!(
+    Value,
+    Bool(bool),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<BTreeMap<Key, Value>>>)
+);
+
+impl Value {
+    /// The value as a number, `None` if it is none
+    pub fn num(&self) -> Option<Num> {
+        match self {
+            Value::Int(n) => Some(Num::Int(*n)),
+            Value::Num(x) => Some(Num::Float(*x)),
+            _ => None,
+        }
+    }
+
+    fn num_or_err(self, span: Span) -> Result<Num, Error> {
+        self.num().ok_or_else(|| Error {
+            span,
+            msg: format!("'{}' is not a Num", self),
+        })
+    }
+
+    /// Bitwise ops accept integers and the floats that are whole and fit into an `i64`
+    fn int_or_err(self, span: Span) -> Result<i64, Error> {
+        match self {
+            Value::Int(n) => Ok(n),
+            Value::Num(x) if x.fract() == 0.0 && x.abs() < i64::MAX as f64 => Ok(x as i64),
+            _ => Err(Error {
+                span,
+                msg: format!("'{}' is not an integer", self),
+            }),
+        }
+    }
+
+    /// The fields of a struct, checking that it has the one that is accessed
+    fn field_or_err(
+        self,
+        span: Span,
+        (field, field_span): &Spanned<String>,
+    ) -> Result<Rc<RefCell<BTreeMap<String, Value>>>, Error> {
+        match self {
+            Value::Struct(name, fields) => {
+                if fields.borrow().contains_key(field) {
+                    Ok(fields)
+                } else {
+                    Err(Error {
+                        span: field_span.clone(),
+                        msg: format!("Struct '{}' has no field '{}'", name, field),
+                    })
+                }
+            }
+            _ => Err(Error {
+                span,
+                msg: format!("'{}' is not a Struct", self),
+            }),
+        }
+    }
+}
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Null => write!(f, "null"),
             Self::Bool(x) => write!(f, "{}", x),
+            Self::Int(n) => write!(f, "{}", n),
             Self::Num(x) => write!(f, "{}", x),
             Self::Str(x) => write!(f, "{}", x),
             Self::List(xs) => write!(
                 f,
                 "[{}]",
-                xs.iter()
+                xs.borrow()
+                    .iter()
                     .map(|x| x.to_string())
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
+            Self::Struct(name, fields) => write!(
+                f,
+                "{} {{ {} }}",
+                name,
+                fields
+                    .borrow()
+                    .iter()
+                    .map(|(field, x)| format!("{}: {}", field, x))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::Variant(name, variant, vals) if vals.is_empty() => {
+                write!(f, "{}::{}", name, variant)
+            }
+            Self::Variant(name, variant, vals) => write!(
+                f,
+                "{}::{}({})",
+                name,
+                variant,
+                vals.iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::Tuple(vals) => write!(f, "({})", tuple_items(vals)),
+            Self::Map(entries) => write!(f, "{{{}}}", map_entries(&entries.borrow())),
             Self::Func(name) => write!(f, "<function: {}>", name),
+            Self::Closure(_) => write!(f, "<closure>"),
+        }
+    }
+}
+
+/// A number operand. Integers stay exact as long as both sides of an operation are integers,
+/// otherwise both sides are used as floats.
+#[derive(Clone, Copy, Debug)]
+pub enum Num {
+    Int(i64),
+    Float(f64),
+}
+
+impl Num {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Num::Int(n) => n as f64,
+            Num::Float(x) => x,
+        }
+    }
+
+    /// Applies one of `+ - * / %`. Division always gives a float, so `7 / 2` is `3.5`.
+    /// Like Rust, the remainder takes the sign of the dividend. A zero divisor is an error rather than NaN.
+    pub fn arithmetic(self, op: &BinaryOp, rhs: Num) -> Result<Num, String> {
+        match op {
+            BinaryOp::Div => return Ok(Num::Float(self.as_f64() / rhs.as_f64())),
+            BinaryOp::Mod if rhs.as_f64() == 0.0 => {
+                return Err(format!("Modulo by zero: {} % {}", self, rhs))
+            }
+            _ => {}
+        }
+        let (Num::Int(a), Num::Int(b)) = (self, rhs) else {
+            let (a, b) = (self.as_f64(), rhs.as_f64());
+            return Ok(Num::Float(match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Sub => a - b,
+                BinaryOp::Mul => a * b,
+                _ => a % b,
+            }));
+        };
+        let (symbol, res) = match op {
+            BinaryOp::Add => ("+", a.checked_add(b)),
+            BinaryOp::Sub => ("-", a.checked_sub(b)),
+            BinaryOp::Mul => ("*", a.checked_mul(b)),
+            BinaryOp::Mod => ("%", a.checked_rem(b)),
+            _ => unreachable!("not an arithmetic operator"),
+        };
+        res.map(Num::Int)
+            .ok_or_else(|| format!("Integer overflow: {} {} {}", a, symbol, b))
+    }
+
+    pub fn checked_neg(self) -> Result<Num, String> {
+        match self {
+            Num::Int(n) => n
+                .checked_neg()
+                .map(Num::Int)
+                .ok_or_else(|| format!("Integer overflow: -({})", n)),
+            Num::Float(x) => Ok(Num::Float(-x)),
+        }
+    }
+
+    /// The next number of a range. Stepping past the largest integer gives infinity, which ends every range.
+    pub fn step(self) -> Num {
+        self.arithmetic(&BinaryOp::Add, Num::Int(1))
+            .unwrap_or(Num::Float(f64::INFINITY))
+    }
+
+    /// Whole numbers as integers, the fraction of floats is dropped
+    fn exact(self) -> i128 {
+        match self {
+            Num::Int(n) => n as i128,
+            Num::Float(x) => x as i128,
+        }
+    }
+}
+
+impl PartialEq for Num {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(std::cmp::Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Num {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (Num::Int(a), Num::Int(b)) => Some(a.cmp(b)),
+            (Num::Float(a), Num::Float(b)) => a.partial_cmp(b),
+            // A float equal to a rounded `i64` is whole, so the two can be compared exactly as integers
+            (a, b) => match a.as_f64().partial_cmp(&b.as_f64())? {
+                std::cmp::Ordering::Equal => Some(a.exact().cmp(&b.exact())),
+                ord => Some(ord),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Num {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Num::Int(n) => write!(f, "{}", n),
+            Num::Float(x) => write!(f, "{}", x),
         }
     }
 }
 
+impl From<Num> for Value {
+    fn from(num: Num) -> Self {
+        match num {
+            Num::Int(n) => Value::Int(n),
+            Num::Float(x) => Value::Num(x),
+        }
+    }
+}
+
+/// The items of a tuple as written between its parentheses, a tuple of one item has a trailing comma like `(1,)`
+pub fn tuple_items<T: fmt::Display>(items: &[T]) -> String {
+    let items: Vec<String> = items.iter().map(|x| x.to_string()).collect();
+    match items.as_slice() {
+        [item] => format!("{},", item),
+        items => items.join(", "),
+    }
+}
+
+/// The error for destructuring a value that is not a tuple with as many items as there are names
+pub fn destructure_error(val: impl fmt::Display, len: Option<usize>, names: usize) -> String {
+    match len {
+        Some(len) => format!(
+            "Can't destructure a tuple of {} values into {} names",
+            len, names
+        ),
+        None => format!("'{}' is not a tuple", val),
+    }
+}
+
+/// The key of a map entry. Only values with a total order can be keys, which keeps the
+/// iteration order of maps the same on every run
+#[derive(Clone, Debug)]
+pub enum Key {
+    Bool(bool),
+    Int(i64),
+    Num(f64),
+    Str(String),
+}
+
+impl Key {
+    /// The key for a value, `None` if the value can't be used as one
+    pub fn new(val: &Value) -> Option<Key> {
+        match val {
+            Value::Bool(x) => Some(Key::Bool(*x)),
+            Value::Int(n) => Some(Key::Int(*n)),
+            // Whole floats are the same key as the equal integer, which also makes `-0` and `0` the same
+            Value::Num(x) if x.fract() == 0.0 && x.abs() < i64::MAX as f64 => {
+                Some(Key::Int(*x as i64))
+            }
+            Value::Num(x) => Some(Key::Num(*x)),
+            Value::Str(x) => Some(Key::Str(x.clone())),
+            _ => None,
+        }
+    }
+
+    /// The key for a value or the error for using it as one
+    pub fn or_err(val: &Value) -> Result<Key, String> {
+        Key::new(val).ok_or_else(|| key_error(val))
+    }
+
+    /// Keys of different types are ordered booleans first, then numbers, then strings
+    fn rank(&self) -> u8 {
+        match self {
+            Key::Bool(_) => 0,
+            Key::Int(_) | Key::Num(_) => 1,
+            Key::Str(_) => 2,
+        }
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (self, other) {
+            (Key::Bool(a), Key::Bool(b)) => a.cmp(b),
+            (Key::Int(a), Key::Int(b)) => a.cmp(b),
+            (Key::Num(a), Key::Num(b)) => a.total_cmp(b),
+            // Floats that are keys aren't whole, so they are never equal to an integer
+            (Key::Int(a), Key::Num(b)) => (*a as f64).total_cmp(b).then(std::cmp::Ordering::Less),
+            (Key::Num(a), Key::Int(b)) => {
+                a.total_cmp(&(*b as f64)).then(std::cmp::Ordering::Greater)
+            }
+            (Key::Str(a), Key::Str(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Bool(x) => write!(f, "{}", x),
+            Key::Int(n) => write!(f, "{}", n),
+            Key::Num(x) => write!(f, "{}", x),
+            Key::Str(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl From<&Key> for Value {
+    fn from(key: &Key) -> Self {
+        match key {
+            Key::Bool(x) => Value::Bool(*x),
+            Key::Int(n) => Value::Int(*n),
+            Key::Num(x) => Value::Num(*x),
+            Key::Str(x) => Value::Str(x.clone()),
+        }
+    }
+}
+
+/// The error for using a value that is not a string, number or boolean as a map key
+pub fn key_error(val: impl fmt::Display) -> String {
+    format!(
+        "Map keys must be strings, numbers or booleans, found '{}'",
+        val
+    )
+}
+
+/// The error for looking up a key that is not in a map
+pub fn missing_key_error(key: &Key) -> String {
+    format!("Key '{}' not found in map", key)
+}
+
+/// The entries of a map as written between its braces
+pub fn map_entries<T: fmt::Display>(entries: &BTreeMap<Key, T>) -> String {
+    entries
+        .iter()
+        .map(|(k, v)| format!("{}: {}", k, v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[derive(Clone, Debug)]
 pub enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
     Eq,
     NotEq,
     LowerT,
     GreaterT,
+    LowerEq,
+    GreaterEq,
     ListAt,
+    Concat,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+#[derive(Clone, Debug)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    BitNot,
 }
 
 pub type Spanned<T> = (T, Span);
 
+/// The left side of a `match` arm
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`, matches anything
+    Wildcard,
+    /// A name, matches anything and binds it
+    Bind(String),
+    /// A literal, matches values equal to it
+    Value(Value),
+    /// `Enum::Variant(patterns..)`, matches the variant if its payload matches the patterns
+    Variant(Spanned<String>, Spanned<String>, Vec<Spanned<Pattern>>),
+}
+
+impl Pattern {
+    /// The variables the pattern binds
+    pub fn bound_names<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Pattern::Bind(name) => out.push(name),
+            Pattern::Variant(_, _, pats) => pats.iter().for_each(|(pat, _)| pat.bound_names(out)),
+            Pattern::Wildcard | Pattern::Value(_) => {}
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Wildcard => write!(f, "_"),
+            Pattern::Bind(name) => write!(f, "{}", name),
+            Pattern::Value(Value::Str(s)) => write!(f, "{:?}", s),
+            Pattern::Value(val) => write!(f, "{}", val),
+            Pattern::Variant((name, _), (variant, _), pats) if pats.is_empty() => {
+                write!(f, "{}::{}", name, variant)
+            }
+            Pattern::Variant((name, _), (variant, _), pats) => write!(
+                f,
+                "{}::{}({})",
+                name,
+                variant,
+                pats.iter()
+                    .map(|(pat, _)| pat.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
 // An expression node in the AST. Children are spanned so we can generate useful runtime errors.
 #[derive(Debug, Clone)]
 pub enum Expr {
@@ -172,33 +1047,349 @@ pub enum Expr {
     List(Vec<Spanned<Self>>),
     LocalVar(String),
     Let(String, Box<Spanned<Self>>, Box<Spanned<Self>>),
+    /// `(a, b)`, or `(a,)` for a single item
+    Tuple(Vec<Spanned<Self>>),
+    /// `{key: value, ..}`
+    Map(Vec<(Spanned<Self>, Spanned<Self>)>),
+    /// `let (a, b) = value; body`
+    Destructure(Vec<Spanned<String>>, Box<Spanned<Self>>, Box<Spanned<Self>>),
     Return(Box<Spanned<Self>>),
     Then(Box<Spanned<Self>>, Box<Spanned<Self>>),
     Binary(Box<Spanned<Self>>, BinaryOp, Box<Spanned<Self>>),
+    Unary(UnaryOp, Box<Spanned<Self>>),
     Call(Box<Spanned<Self>>, Spanned<Vec<Spanned<Self>>>),
     If(Box<Spanned<Self>>, Box<Spanned<Self>>, Box<Spanned<Self>>),
     Loop(Box<Spanned<Self>>, Box<Spanned<Self>>),
+    Break(Box<Spanned<Self>>),
+    Continue,
+    /// `for name in items { body }`, where the items are a list or a `Range`
+    For(String, Box<Spanned<Self>>, Box<Spanned<Self>>),
+    /// `start..end`, or `start..=end` if inclusive. Only appears as the items of a `for` loop
+    Range(Box<Spanned<Self>>, Box<Spanned<Self>>, bool),
     Print(Box<Spanned<Self>>),
     Assign(String, Box<Spanned<Self>>, Box<Spanned<Self>>),
+    /// `list[index] = value`
+    SetIndex(Box<Spanned<Self>>, Box<Spanned<Self>>, Box<Spanned<Self>>),
+    /// `Name { field: value, .. }`
+    Struct(Spanned<String>, Vec<(Spanned<String>, Spanned<Self>)>),
+    /// `value.field`
+    Field(Box<Spanned<Self>>, Spanned<String>),
+    /// `value.field = new_value`
+    SetField(Box<Spanned<Self>>, Spanned<String>, Box<Spanned<Self>>),
+    /// `Enum::Variant(values..)`
+    Variant(Spanned<String>, Spanned<String>, Vec<Spanned<Self>>),
+    /// `match value { pattern => body, .. }`
+    Match(Box<Spanned<Self>>, Vec<(Spanned<Pattern>, Spanned<Self>)>),
+    /// `|args| body`. The body is shared with the closures created from it.
+    Closure(Vec<String>, Rc<Spanned<Self>>),
+    /// `a::b` without parentheses, either a variant without payload or a function or constant of another module.
+    /// Paths are replaced when modules are merged, later passes never see them.
+    Path(Spanned<String>, Spanned<String>),
+}
+
+impl Expr {
+    /// The direct subexpressions, in evaluation order
+    pub fn children(&self) -> Vec<&Spanned<Expr>> {
+        match self {
+            Expr::Error | Expr::Value(_) | Expr::LocalVar(_) | Expr::Continue | Expr::Path(..) => {
+                Vec::new()
+            }
+            Expr::List(items) | Expr::Tuple(items) => items.iter().collect(),
+            Expr::Struct(_, fields) => fields.iter().map(|(_, val)| val).collect(),
+            Expr::Map(entries) => entries.iter().flat_map(|(k, v)| [k, v]).collect(),
+            Expr::Variant(_, _, vals) => vals.iter().collect(),
+            Expr::Match(val, arms) => std::iter::once(&**val)
+                .chain(arms.iter().map(|(_, body)| body))
+                .collect(),
+            Expr::Closure(_, body) => vec![&**body],
+            Expr::Field(a, _) => vec![a],
+            Expr::SetField(a, _, b) => vec![a, b],
+            Expr::Return(a) | Expr::Break(a) | Expr::Unary(_, a) | Expr::Print(a) => vec![a],
+            Expr::Let(_, a, b)
+            | Expr::Destructure(_, a, b)
+            | Expr::Then(a, b)
+            | Expr::Binary(a, _, b)
+            | Expr::Loop(a, b)
+            | Expr::For(_, a, b)
+            | Expr::Range(a, b, _)
+            | Expr::Assign(_, a, b) => vec![a, b],
+            Expr::Call(f, (args, _)) => std::iter::once(&**f).chain(args).collect(),
+            Expr::If(cond, a, b) | Expr::SetIndex(cond, a, b) => vec![cond, a, b],
+        }
+    }
+
+    /// The direct subexpressions, in evaluation order, for passes that rewrite the tree.
+    /// The bodies of closures are shared, so they are only rewritten if no closure uses them yet.
+    pub fn children_mut(&mut self) -> Vec<&mut Spanned<Expr>> {
+        match self {
+            Expr::Error | Expr::Value(_) | Expr::LocalVar(_) | Expr::Continue | Expr::Path(..) => {
+                Vec::new()
+            }
+            Expr::List(items) | Expr::Tuple(items) | Expr::Variant(_, _, items) => {
+                items.iter_mut().collect()
+            }
+            Expr::Struct(_, fields) => fields.iter_mut().map(|(_, val)| val).collect(),
+            Expr::Map(entries) => entries.iter_mut().flat_map(|(k, v)| [k, v]).collect(),
+            Expr::Match(val, arms) => std::iter::once(&mut **val)
+                .chain(arms.iter_mut().map(|(_, body)| body))
+                .collect(),
+            Expr::Closure(_, body) => Rc::get_mut(body).into_iter().collect(),
+            Expr::Field(a, _) => vec![a],
+            Expr::SetField(a, _, b) => vec![a, b],
+            Expr::Return(a) | Expr::Break(a) | Expr::Unary(_, a) | Expr::Print(a) => vec![a],
+            Expr::Let(_, a, b)
+            | Expr::Destructure(_, a, b)
+            | Expr::Then(a, b)
+            | Expr::Binary(a, _, b)
+            | Expr::Loop(a, b)
+            | Expr::For(_, a, b)
+            | Expr::Range(a, b, _)
+            | Expr::Assign(_, a, b) => vec![a, b],
+            Expr::Call(f, (args, _)) => std::iter::once(&mut **f).chain(args).collect(),
+            Expr::If(cond, a, b) | Expr::SetIndex(cond, a, b) => vec![cond, a, b],
+        }
+    }
+
+    /// The variables the expression uses without binding them itself, in order of first use.
+    /// Names of functions are included, they can't be told apart from variables without the scope.
+    pub fn free_vars(&self) -> Vec<String> {
+        fn used(name: &String, bound: &[&str], out: &mut Vec<String>) {
+            if !bound.contains(&name.as_str()) && !out.contains(name) {
+                out.push(name.clone());
+            }
+        }
+        fn collect<'a>(expr: &'a Expr, bound: &mut Vec<&'a str>, out: &mut Vec<String>) {
+            let depth = bound.len();
+            match expr {
+                Expr::LocalVar(name) => used(name, bound, out),
+                Expr::Assign(name, val, body) => {
+                    used(name, bound, out);
+                    collect(&val.0, bound, out);
+                    collect(&body.0, bound, out);
+                }
+                Expr::Let(name, val, body) | Expr::For(name, val, body) => {
+                    collect(&val.0, bound, out);
+                    bound.push(name);
+                    collect(&body.0, bound, out);
+                }
+                Expr::Destructure(names, val, body) => {
+                    collect(&val.0, bound, out);
+                    bound.extend(names.iter().map(|(name, _)| name.as_str()));
+                    collect(&body.0, bound, out);
+                }
+                Expr::Closure(args, body) => {
+                    bound.extend(args.iter().map(String::as_str));
+                    collect(&body.0, bound, out);
+                }
+                Expr::Match(val, arms) => {
+                    collect(&val.0, bound, out);
+                    for ((pat, _), body) in arms {
+                        pat.bound_names(bound);
+                        collect(&body.0, bound, out);
+                        bound.truncate(depth);
+                    }
+                }
+                expr => {
+                    for child in expr.children() {
+                        collect(&child.0, bound, out);
+                    }
+                }
+            }
+            bound.truncate(depth);
+        }
+        let mut out = Vec::new();
+        collect(self, &mut Vec::new(), &mut out);
+        out
+    }
+}
+
+/// A type annotation as written in the source
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Null,
+    Bool,
+    Num,
+    Str,
+    List(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Null => write!(f, "null"),
+            Type::Bool => write!(f, "bool"),
+            Type::Num => write!(f, "num"),
+            Type::Str => write!(f, "str"),
+            Type::List(t) => write!(f, "[{}]", t),
+            Type::Map(k, v) => write!(f, "{{{}: {}}}", k, v),
+        }
+    }
 }
 
 // A function node in the AST.
 #[derive(Debug, Clone)]
 pub struct Func {
     pub args: Vec<String>,
+    /// Annotations of the arguments, in the same order as `args`
+    pub arg_types: Vec<Option<Spanned<Type>>>,
+    pub ret_type: Option<Spanned<Type>>,
     pub body: Spanned<Expr>,
+    /// The lines of the `///` comments in front of the function
+    pub docs: Vec<String>,
+}
+
+/// A struct declaration, `struct Point { x, y }`
+#[derive(Debug, Clone)]
+pub struct Struct {
+    pub fields: Vec<Spanned<String>>,
+    /// The lines of the `///` comments in front of the struct
+    pub docs: Vec<String>,
+}
+
+/// An enum declaration, `enum Shape { Circle(r), Rect(w, h) }`
+#[derive(Debug, Clone)]
+pub struct Enum {
+    pub variants: Vec<Variant>,
+    /// The lines of the `///` comments in front of the enum
+    pub docs: Vec<String>,
+}
+
+/// A variant of an enum. The names of its payload values are only documentation, they are matched by position.
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub name: Spanned<String>,
+    pub fields: Vec<Spanned<String>>,
+}
+
+/// A top level `const LIMIT = 100` or `global counter = 0`. Both are evaluated at compile time,
+/// only globals can be assigned to.
+#[derive(Debug, Clone)]
+pub struct Global {
+    pub value: Spanned<Expr>,
+    pub mutable: bool,
+    /// The lines of the `///` comments in front of the declaration
+    pub docs: Vec<String>,
+}
+
+/// Everything declared in a program, or in one of its files before they are merged
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub funcs: HashMap<String, Func>,
+    pub structs: HashMap<String, Struct>,
+    pub enums: HashMap<String, Enum>,
+    /// Constants and globals, they share the namespace of functions
+    pub globals: HashMap<String, Global>,
+}
+
+/// A parsed source file, with what it takes from other files
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub program: Program,
+    /// Paths of the imported files as written, `import "util.grsp";`
+    pub imports: Vec<Spanned<String>>,
+    /// Items of imported modules used without their module name, `use util::helper;` is `(util, helper)`
+    pub uses: Vec<(Spanned<String>, Spanned<String>)>,
+}
+
+/// Desugars a string literal into a chain of concatenations, parsing each interpolated `{expr}` on its own
+fn interpolated_string(
+    parts: Vec<StrPart>,
+    span: Span,
+    emit: &mut dyn FnMut(Simple<Token>),
+) -> Expr {
+    if let [StrPart::Lit(s)] = parts.as_slice() {
+        return Expr::Value(Value::Str(s.clone()));
+    }
+    let mut acc = (Expr::Value(Value::Str(String::new())), span.clone());
+    for part in parts {
+        let part = match part {
+            StrPart::Lit(s) => (Expr::Value(Value::Str(s)), span.clone()),
+            StrPart::Expr(tokens) => {
+                let eoi = tokens.last().map_or(span.end, |(_, s)| s.end);
+                let (expr, errs) = expr_parser()
+                    .then_ignore(end())
+                    .parse_recovery(Stream::from_iter(eoi..eoi, tokens.into_iter()));
+                errs.into_iter().for_each(&mut *emit);
+                expr.unwrap_or((Expr::Error, span.clone()))
+            }
+        };
+        acc = match acc {
+            (Expr::Value(Value::Str(s)), _)
+                if s.is_empty() && matches!(part.0, Expr::Value(Value::Str(_))) =>
+            {
+                part
+            }
+            acc => (
+                Expr::Binary(Box::new(acc), BinaryOp::Concat, Box::new(part)),
+                span.clone(),
+            ),
+        };
+    }
+    acc.0
+}
+
+/// What a `let` binds
+enum LetTarget {
+    Name(String),
+    Tuple(Vec<Spanned<String>>),
+}
+
+/// What can follow an expression in a call chain like `f(x)[0](y)`
+enum Postfix {
+    Call(Spanned<Vec<Spanned<Expr>>>),
+    Index(Spanned<Expr>, Span),
+    Field(Spanned<String>),
+}
+
+fn apply_postfix(f: Spanned<Expr>, postfix: Postfix) -> Spanned<Expr> {
+    match postfix {
+        Postfix::Call(args) => {
+            let span = f.1.start..args.1.end;
+            (Expr::Call(Box::new(f), args), span)
+        }
+        // `xs[i]` is the same as `xs @ i`
+        Postfix::Index(index, index_span) => {
+            let span = f.1.start..index_span.end;
+            (
+                Expr::Binary(Box::new(f), BinaryOp::ListAt, Box::new(index)),
+                span,
+            )
+        }
+        Postfix::Field(name) => {
+            let span = f.1.start..name.1.end;
+            (Expr::Field(Box::new(f), name), span)
+        }
+    }
 }
 
 pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>> + Clone {
-    recursive(|expr| {
+    expr_parsers().1
+}
+
+/// The parsers of a single expression and of statements chained by semicolons
+// chumsky fixes the error type of `filter_map` to `Simple<Token>`, which can't be boxed
+#[allow(clippy::result_large_err)]
+fn expr_parsers() -> (
+    impl Parser<Token, Spanned<Expr>, Error = Simple<Token>> + Clone,
+    impl Parser<Token, Spanned<Expr>, Error = Simple<Token>> + Clone,
+) {
+    let mut expr = Recursive::declare();
+    let (raw_expr, statements) = {
+        let expr = expr.clone();
         let raw_expr = recursive(|raw_expr| {
             let val = filter_map(|span, tok| match tok {
                 Token::Null => Ok(Expr::Value(Value::Null)),
                 Token::Bool(x) => Ok(Expr::Value(Value::Bool(x))),
-                Token::Num(n) => Ok(Expr::Value(Value::Num(n.parse().unwrap()))),
-                Token::Str(s) => Ok(Expr::Value(Value::Str(s))),
+                Token::Int(n) => Ok(Expr::Value(Value::Int(n))),
+                Token::Float(x) => Ok(Expr::Value(Value::Num(x.0))),
                 _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
             })
+            .or(filter_map(|span, tok| match tok {
+                Token::Str(parts) => Ok(parts),
+                _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+            })
+            .validate(interpolated_string))
             .labelled("value");
 
             let ident = filter_map(|span, tok| match tok {
@@ -208,7 +1399,6 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
             .labelled("identifier");
 
             let assign_ = ident
-                .clone()
                 .then_ignore(just(Token::Op("=".to_string())))
                 .then(raw_expr.clone())
                 .then_ignore(just(Token::Ctrl(';')))
@@ -221,26 +1411,138 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 .then_ignore(just(Token::Ctrl(',')).or_not())
                 .or_not()
                 .map(|item| item.unwrap_or_else(Vec::new));
-            // A let expression
+            // A let expression, which either binds a name or takes a tuple apart with `let (a, b) = ..`
+            let names = ident
+                .map_with_span(|name, span| (name, span))
+                .separated_by(just(Token::Ctrl(',')))
+                .allow_trailing()
+                .at_least(1)
+                .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')));
             let let_ = just(Token::Let)
-                .ignore_then(ident)
+                .ignore_then(ident.map(LetTarget::Name).or(names.map(LetTarget::Tuple)))
                 .then_ignore(just(Token::Op("=".to_string())))
                 .then(raw_expr.clone())
                 .then_ignore(just(Token::Ctrl(';')))
                 .then(expr.clone())
-                .map(|((name, val), body)| Expr::Let(name, Box::new(val), Box::new(body)));
+                .map(|((target, val), body)| match target {
+                    LetTarget::Name(name) => Expr::Let(name, Box::new(val), Box::new(body)),
+                    LetTarget::Tuple(names) => {
+                        Expr::Destructure(names, Box::new(val), Box::new(body))
+                    }
+                });
 
             let list = items
                 .clone()
                 .delimited_by(just(Token::Ctrl('[')), just(Token::Ctrl(']')))
                 .map(Expr::List);
 
+            // `{}` is an empty map, blocks can't be empty so it isn't mistaken for one
+            let map = expr
+                .clone()
+                .then_ignore(just(Token::Ctrl(':')))
+                .then(expr.clone())
+                .separated_by(just(Token::Ctrl(',')))
+                .allow_trailing()
+                .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}')))
+                .map(Expr::Map)
+                .boxed();
+
+            let index = expr
+                .clone()
+                .delimited_by(just(Token::Ctrl('[')), just(Token::Ctrl(']')));
+
+            let field =
+                just(Token::Ctrl('.')).ignore_then(ident.map_with_span(|name, span| (name, span)));
+
+            // Assigning to a list element or field, `xs[i].y = v` sets field `y` of the struct `xs[i]`
+            let set_ = ident
+                .map_with_span(|name, span: Span| (Expr::LocalVar(name), span))
+                .then(
+                    index
+                        .clone()
+                        .map_with_span(Postfix::Index)
+                        .or(field.clone().map(Postfix::Field))
+                        .repeated()
+                        .at_least(1),
+                )
+                .then_ignore(just(Token::Op("=".to_string())))
+                .then(raw_expr.clone())
+                .map(|((target, mut accessors), val)| {
+                    let last = accessors.pop().unwrap();
+                    let target = Box::new(accessors.into_iter().fold(target, apply_postfix));
+                    match last {
+                        Postfix::Index(index, _) => {
+                            Expr::SetIndex(target, Box::new(index), Box::new(val))
+                        }
+                        Postfix::Field(name) => Expr::SetField(target, name, Box::new(val)),
+                        Postfix::Call(_) => unreachable!(),
+                    }
+                })
+                .boxed();
+
+            // Struct literals need at least one field, so `if x {}` isn't mistaken for one
+            let struct_ = ident
+                .map_with_span(|name, span| (name, span))
+                .then(
+                    ident
+                        .map_with_span(|name, span| (name, span))
+                        .then_ignore(just(Token::Ctrl(':')))
+                        .then(expr.clone())
+                        .separated_by(just(Token::Ctrl(',')))
+                        .allow_trailing()
+                        .at_least(1)
+                        .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}'))),
+                )
+                .map(|(name, fields)| Expr::Struct(name, fields))
+                .boxed();
+
+            // Variants without a payload are written without parentheses, `Shape::Empty`.
+            // `util::helper(x)` calls a function of another module, that is only known once modules are loaded.
+            let variant = ident
+                .map_with_span(|name, span| (name, span))
+                .then_ignore(just(Token::PathSep))
+                .then(ident.map_with_span(|name, span| (name, span)))
+                .then(
+                    items
+                        .clone()
+                        .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
+                        .or_not(),
+                )
+                .map(|((name, variant), vals)| match vals {
+                    Some(vals) => Expr::Variant(name, variant, vals),
+                    None => Expr::Path(name, variant),
+                })
+                .boxed();
+
+            // Closures take the rest of the expression as their body like in Rust, `|x| x + 1`
+            let closure = just(Token::Op("||".to_string()))
+                .to(Vec::new())
+                .or(ident
+                    .separated_by(just(Token::Ctrl(',')))
+                    .allow_trailing()
+                    .delimited_by(
+                        just(Token::Op("|".to_string())),
+                        just(Token::Op("|".to_string())),
+                    ))
+                .then(
+                    expr.clone()
+                        .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}')))
+                        .or(raw_expr.clone()),
+                )
+                .map(|(args, body)| Expr::Closure(args, Rc::new(body)))
+                .boxed();
+
             // 'Atoms' are expressions that contain no ambiguity
             let atom = val
+                .or(closure)
+                .or(variant)
+                .or(struct_)
                 .or(assign_)
+                .or(set_)
                 .or(ident.map(Expr::LocalVar))
                 .or(let_)
                 .or(list)
+                .or(map)
                 // In Nano Rust, `print` is just a keyword, just like Python 2, for simplicity
                 .or(just(Token::Print)
                     .ignore_then(
@@ -249,10 +1551,21 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                     )
                     .map(|expr| Expr::Print(Box::new(expr))))
                 .map_with_span(|expr, span| (expr, span))
-                // Atoms can also just be normal expressions, but surrounded with parentheses
+                // Atoms can also just be normal expressions, but surrounded with parentheses.
+                // With a comma they are tuples instead, `(x,)` is a tuple of one item.
                 .or(expr
                     .clone()
-                    .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')'))))
+                    .then(just(Token::Ctrl(',')).ignore_then(expr.clone()).repeated())
+                    .then(just(Token::Ctrl(',')).or_not())
+                    .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
+                    .map_with_span(|((first, rest), comma), span| {
+                        if rest.is_empty() && comma.is_none() {
+                            first
+                        } else {
+                            let items = std::iter::once(first).chain(rest).collect();
+                            (Expr::Tuple(items), span)
+                        }
+                    }))
                 // Attempt to recover anything that looks like a parenthesised expression but contains errors
                 .recover_with(nested_delimiters(
                     Token::Ctrl('('),
@@ -274,30 +1587,47 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                     |span| (Expr::Error, span),
                 ));
 
-            // Function calls have very high precedence so we prioritise them
+            // Function calls and indexing have very high precedence so we prioritise them
             let call = atom
                 .then(
                     items
                         .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
-                        .map_with_span(|args, span| (args, span))
+                        .map_with_span(|args, span| Postfix::Call((args, span)))
+                        .or(index.map_with_span(Postfix::Index))
+                        .or(field.map(Postfix::Field))
                         .repeated(),
                 )
-                .foldl(|f, args| {
-                    let span = f.1.start..args.1.end;
-                    (Expr::Call(Box::new(f), args), span)
-                });
+                .foldl(apply_postfix)
+                .boxed();
+
+            // Prefix ops bind tighter than any binary op
+            let op = just(Token::Op("!".to_string()))
+                .to(UnaryOp::Not)
+                .or(just(Token::Op("-".to_string())).to(UnaryOp::Neg))
+                .or(just(Token::Op("~".to_string())).to(UnaryOp::BitNot));
+            let unary = op
+                .map_with_span(|op, span: Span| (op, span))
+                .repeated()
+                .then(call)
+                .foldr(|(op, op_span), rhs| {
+                    let span = op_span.start..rhs.1.end;
+                    (Expr::Unary(op, Box::new(rhs)), span)
+                })
+                .boxed();
 
-            // Product ops (multiply and divide) have equal precedence
+            // Product ops (multiply, divide and modulo) have equal precedence
             let op = just(Token::Op("*".to_string()))
                 .to(BinaryOp::Mul)
-                .or(just(Token::Op("/".to_string())).to(BinaryOp::Div));
-            let product = call
+                .or(just(Token::Op("/".to_string())).to(BinaryOp::Div))
+                .or(just(Token::Op("%".to_string())).to(BinaryOp::Mod));
+            let product = unary
                 .clone()
-                .then(op.then(call).repeated())
+                .then(op.then(unary).repeated())
                 .foldl(|a, (op, b)| {
                     let span = a.1.start..b.1.end;
                     (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+                })
+                .boxed();
 
             // Sum ops (add and subtract) have equal precedence
             let op = just(Token::Op("+".to_string()))
@@ -309,19 +1639,66 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 .foldl(|a, (op, b)| {
                     let span = a.1.start..b.1.end;
                     (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+                })
+                .boxed();
+
+            // Shifts bind tighter than the bitwise ops, which in turn go and, xor, or like in Rust
+            let op = just(Token::Op("<<".to_string()))
+                .to(BinaryOp::Shl)
+                .or(just(Token::Op(">>".to_string())).to(BinaryOp::Shr));
+            let shift = sum
+                .clone()
+                .then(op.then(sum).repeated())
+                .foldl(|a, (op, b)| {
+                    let span = a.1.start..b.1.end;
+                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
+                })
+                .boxed();
+
+            let op = just(Token::Op("&".to_string())).to(BinaryOp::BitAnd);
+            let bit_and = shift
+                .clone()
+                .then(op.then(shift).repeated())
+                .foldl(|a, (op, b)| {
+                    let span = a.1.start..b.1.end;
+                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
+                })
+                .boxed();
+
+            let op = just(Token::Op("^".to_string())).to(BinaryOp::BitXor);
+            let bit_xor = bit_and
+                .clone()
+                .then(op.then(bit_and).repeated())
+                .foldl(|a, (op, b)| {
+                    let span = a.1.start..b.1.end;
+                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
+                })
+                .boxed();
+
+            let op = just(Token::Op("|".to_string())).to(BinaryOp::BitOr);
+            let bit_or = bit_xor
+                .clone()
+                .then(op.then(bit_xor).repeated())
+                .foldl(|a, (op, b)| {
+                    let span = a.1.start..b.1.end;
+                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
+                })
+                .boxed();
 
             let op = just(Token::Op("<".to_string()))
                 .to(BinaryOp::LowerT)
-                .or(just(Token::Op(">".to_string())).to(BinaryOp::GreaterT));
+                .or(just(Token::Op(">".to_string())).to(BinaryOp::GreaterT))
+                .or(just(Token::Op("<=".to_string())).to(BinaryOp::LowerEq))
+                .or(just(Token::Op(">=".to_string())).to(BinaryOp::GreaterEq));
 
-            let order = sum
+            let order = bit_or
                 .clone()
-                .then(op.then(sum).repeated())
+                .then(op.then(bit_or).repeated())
                 .foldl(|a, (op, b)| {
                     let span = a.1.start..b.1.end;
                     (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+                })
+                .boxed();
 
             let op = just(Token::Op("@".to_string())).to(BinaryOp::ListAt);
 
@@ -331,7 +1708,8 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 .foldl(|a, (op, b)| {
                     let span = a.1.start..b.1.end;
                     (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+                })
+                .boxed();
             // Comparison ops (equal, not-equal) have equal precedence
             let op = just(Token::Op("==".to_string()))
                 .to(BinaryOp::Eq)
@@ -343,9 +1721,27 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 .foldl(|a, (op, b)| {
                     let span = a.1.start..b.1.end;
                     (Expr::Binary(Box::new(a), op, Box::new(b)), span)
-                });
+                })
+                .boxed();
 
-            compare
+            // Logical and binds tighter than logical or
+            let op = just(Token::Op("&&".to_string())).to(BinaryOp::And);
+            let and = compare
+                .clone()
+                .then(op.then(compare).repeated())
+                .foldl(|a, (op, b)| {
+                    let span = a.1.start..b.1.end;
+                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
+                })
+                .boxed();
+
+            let op = just(Token::Op("||".to_string())).to(BinaryOp::Or);
+            and.clone()
+                .then(op.then(and).repeated())
+                .foldl(|a, (op, b)| {
+                    let span = a.1.start..b.1.end;
+                    (Expr::Binary(Box::new(a), op, Box::new(b)), span)
+                })
         });
 
         // Blocks are expressions but delimited with braces
@@ -363,11 +1759,10 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 |span| (Expr::Error, span),
             ));
 
-        let return_ = recursive(|return_| {
-            just(Token::Return)
-                .ignore_then(expr.clone())
-                .map_with_span(|return_rexpr, span| (Expr::Return(Box::new(return_rexpr)), span))
-        });
+        // `return` and `break` take an expression rather than statements, so `return x;` gives back `x` and not null
+        let return_ = just(Token::Return)
+            .ignore_then(raw_expr.clone())
+            .map_with_span(|return_rexpr, span| (Expr::Return(Box::new(return_rexpr)), span));
 
         let if_ = recursive(|if_| {
             just(Token::If)
@@ -394,17 +1789,129 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 })
         });
 
-        let loop_ = recursive(|loop_| {
-            just(Token::Loop)
-                .ignore_then(expr.clone())
-                .then(block.clone())
-                .map_with_span(|(cond, body), span| {
-                    (Expr::Loop(Box::new(cond), Box::new(body)), span)
-                })
-        });
+        let loop_ = just(Token::Loop)
+            .ignore_then(
+                expr.clone()
+                    .then(block.clone())
+                    // A loop without a condition runs until it is left with `break` or `return`
+                    .or(block.clone().map_with_span(|body, span| {
+                        ((Expr::Value(Value::Bool(true)), span), body)
+                    })),
+            )
+            .map_with_span(|(cond, body), span| (Expr::Loop(Box::new(cond), Box::new(body)), span));
+
+        let range_op = just(Token::Op("..".to_string()))
+            .to(false)
+            .or(just(Token::Op("..=".to_string())).to(true));
+        let items = raw_expr
+            .clone()
+            .then(range_op.then(raw_expr.clone()).or_not())
+            .map_with_span(|(start, end), span| match end {
+                Some((inclusive, end)) => {
+                    (Expr::Range(Box::new(start), Box::new(end), inclusive), span)
+                }
+                None => start,
+            });
+
+        let for_ = just(Token::For)
+            .ignore_then(
+                filter_map(|span, tok| match tok {
+                    Token::Ident(ident) => Ok(ident.clone()),
+                    _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+                })
+                .labelled("identifier"),
+            )
+            .then_ignore(just(Token::In))
+            .then(items)
+            .then(block.clone())
+            .map_with_span(|((name, items), body), span| {
+                (Expr::For(name, Box::new(items), Box::new(body)), span)
+            });
+
+        let break_ = just(Token::Break)
+            .ignore_then(raw_expr.clone().or_not())
+            .map_with_span(|val, span: Span| {
+                // A `break` without a value leaves the loop with null
+                let val = val.unwrap_or_else(|| (Expr::Value(Value::Null), span.clone()));
+                (Expr::Break(Box::new(val)), span)
+            });
+
+        let continue_ = just(Token::Continue).map_with_span(|_, span| (Expr::Continue, span));
+
+        let pattern = recursive(|pattern| {
+            let literal = filter_map(|span, tok| match tok {
+                Token::Null => Ok(Value::Null),
+                Token::Bool(x) => Ok(Value::Bool(x)),
+                Token::Int(n) => Ok(Value::Int(n)),
+                Token::Float(x) => Ok(Value::Num(x.0)),
+                Token::Str(parts) => match parts.as_slice() {
+                    [StrPart::Lit(s)] => Ok(Value::Str(s.clone())),
+                    _ => Err(Simple::custom(
+                        span,
+                        "Patterns can't contain interpolated strings",
+                    )),
+                },
+                _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+            });
+            let negative =
+                just(Token::Op("-".to_string())).ignore_then(filter_map(|span, tok| match tok {
+                    Token::Int(n) => Ok(Value::Int(n.wrapping_neg())),
+                    Token::Float(x) => Ok(Value::Num(-x.0)),
+                    _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+                }));
+            let ident = filter_map(|span, tok| match tok {
+                Token::Ident(ident) => Ok(ident.clone()),
+                _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+            })
+            .map_with_span(|name, span| (name, span));
+            let variant = ident
+                .then_ignore(just(Token::PathSep))
+                .then(ident)
+                .then(
+                    pattern
+                        .separated_by(just(Token::Ctrl(',')))
+                        .allow_trailing()
+                        .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
+                        .or_not(),
+                )
+                .map(|((name, variant), pats)| {
+                    Pattern::Variant(name, variant, pats.unwrap_or_default())
+                });
+            literal
+                .or(negative)
+                .map(Pattern::Value)
+                .or(variant)
+                .or(ident.map(|(name, _)| match name.as_str() {
+                    "_" => Pattern::Wildcard,
+                    _ => Pattern::Bind(name),
+                }))
+                .map_with_span(|pat, span| (pat, span))
+                .labelled("pattern")
+        });
+
+        // Arms are separated by commas, which may be left out after blocks like in Rust
+        let match_ = just(Token::Match)
+            .ignore_then(expr.clone())
+            .then(
+                pattern
+                    .then_ignore(just(Token::FatArrow))
+                    .then(expr.clone())
+                    .then_ignore(just(Token::Ctrl(',')).or_not())
+                    .repeated()
+                    .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}'))),
+            )
+            .map_with_span(|(val, arms), span| (Expr::Match(Box::new(val), arms), span));
 
         // Both blocks and `if` are 'block expressions' and can appear in the place of statements
-        let block_expr = block.or(if_).or(return_).or(loop_).labelled("block");
+        let block_expr = block
+            .or(if_)
+            .or(return_)
+            .or(loop_)
+            .or(for_)
+            .or(break_)
+            .or(continue_)
+            .or(match_)
+            .labelled("block");
 
         let block_chain = block_expr
             .clone()
@@ -414,7 +1921,7 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                 (Expr::Then(Box::new(a), Box::new(b)), span)
             });
 
-        block_chain
+        let statements = block_chain
             // Expressions, chained by semicolons, are statements
             .or(raw_expr.clone())
             .then(just(Token::Ctrl(';')).ignore_then(expr.or_not()).repeated())
@@ -430,31 +1937,88 @@ pub fn expr_parser() -> impl Parser<Token, Spanned<Expr>, Error = Simple<Token>>
                     ),
                     span,
                 )
-            })
-    })
+            });
+        (raw_expr, statements)
+    };
+    expr.define(statements);
+    (raw_expr, expr)
+}
+
+/// A top level declaration
+enum Item {
+    Func(Func),
+    Struct(Struct),
+    Enum(Enum),
+    Global(Global),
+    /// The name is the imported path
+    Import,
+    /// The name is the used item, of the module given here
+    Use(Spanned<String>),
 }
 
-pub fn funcs_parser() -> impl Parser<Token, HashMap<String, Func>, Error = Simple<Token>> + Clone {
+// chumsky fixes the error type of `filter_map` to `Simple<Token>`, which can't be boxed
+#[allow(clippy::result_large_err)]
+pub fn funcs_parser() -> impl Parser<Token, Module, Error = Simple<Token>> + Clone {
     let ident = filter_map(|span, tok| match tok {
         Token::Ident(ident) => Ok(ident.clone()),
         _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
     });
 
-    // Argument lists are just identifiers separated by commas, surrounded by parentheses
+    // Types are either a name, a list type `[T]` or a map type `{K: V}`
+    let ty = recursive(|ty| {
+        filter_map(|span, tok| match tok {
+            Token::Null => Ok(Type::Null),
+            Token::Ident(name) => match name.as_str() {
+                "bool" => Ok(Type::Bool),
+                "num" => Ok(Type::Num),
+                "str" => Ok(Type::Str),
+                _ => Err(Simple::custom(
+                    span,
+                    format!(
+                        "Unknown type '{}', expected one of null, bool, num, str",
+                        name
+                    ),
+                )),
+            },
+            _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+        })
+        .or(ty
+            .clone()
+            .delimited_by(just(Token::Ctrl('[')), just(Token::Ctrl(']')))
+            .map(|t| Type::List(Box::new(t))))
+        .or(ty
+            .clone()
+            .then_ignore(just(Token::Ctrl(':')))
+            .then(ty)
+            .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}')))
+            .map(|(k, v)| Type::Map(Box::new(k), Box::new(v))))
+    })
+    .map_with_span(|ty, span| (ty, span))
+    .labelled("type");
+
+    // Argument lists are identifiers with optional type annotations separated by commas, surrounded by parentheses
     let args = ident
-        .clone()
+        .then(just(Token::Ctrl(':')).ignore_then(ty.clone()).or_not())
         .separated_by(just(Token::Ctrl(',')))
         .allow_trailing()
         .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
         .labelled("function args");
 
-    let func = just(Token::Fn)
-        .ignore_then(
+    let docs = filter_map(|span, tok| match tok {
+        Token::Doc(doc) => Ok(doc),
+        _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+    })
+    .repeated();
+
+    let func = docs
+        .then_ignore(just(Token::Fn))
+        .then(
             ident
                 .map_with_span(|name, span| (name, span))
                 .labelled("function name"),
         )
         .then(args)
+        .then(just(Token::Arrow).ignore_then(ty).or_not())
         .then(
             expr_parser()
                 .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}')))
@@ -469,175 +2033,1243 @@ pub fn funcs_parser() -> impl Parser<Token, HashMap<String, Func>, Error = Simpl
                     |span| (Expr::Error, span),
                 )),
         )
-        .map(|((name, args), body)| (name, Func { args, body }))
+        .map(|((((docs, name), args), ret_type), body)| {
+            let (args, arg_types) = args.into_iter().unzip();
+            (
+                name,
+                Func {
+                    args,
+                    arg_types,
+                    ret_type,
+                    body,
+                    docs,
+                },
+            )
+        })
         .labelled("function");
 
-    func.repeated()
-        .try_map(|fs, _| {
+    let struct_ = docs
+        .then_ignore(just(Token::Struct))
+        .then(
+            ident
+                .map_with_span(|name, span| (name, span))
+                .labelled("struct name"),
+        )
+        .then(
+            ident
+                .map_with_span(|name, span| (name, span))
+                .separated_by(just(Token::Ctrl(',')))
+                .allow_trailing()
+                .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}'))),
+        )
+        .map(|((docs, name), fields)| (name, Struct { fields, docs }))
+        .labelled("struct");
+
+    let variant = ident
+        .map_with_span(|name, span| (name, span))
+        .then(
+            ident
+                .map_with_span(|name, span| (name, span))
+                .separated_by(just(Token::Ctrl(',')))
+                .allow_trailing()
+                .delimited_by(just(Token::Ctrl('(')), just(Token::Ctrl(')')))
+                .or_not(),
+        )
+        .map(|(name, fields)| Variant {
+            name,
+            fields: fields.unwrap_or_default(),
+        });
+
+    let enum_ = docs
+        .then_ignore(just(Token::Enum))
+        .then(
+            ident
+                .map_with_span(|name, span| (name, span))
+                .labelled("enum name"),
+        )
+        .then(
+            variant
+                .separated_by(just(Token::Ctrl(',')))
+                .allow_trailing()
+                .delimited_by(just(Token::Ctrl('{')), just(Token::Ctrl('}'))),
+        )
+        .map(|((docs, name), variants)| (name, Enum { variants, docs }))
+        .labelled("enum");
+
+    let import = just(Token::Import)
+        .ignore_then(filter_map(|span, tok| match tok {
+            Token::Str(parts) => match parts.as_slice() {
+                [StrPart::Lit(path)] => Ok((path.clone(), span)),
+                _ => Err(Simple::custom(span, "Import paths can't be interpolated")),
+            },
+            _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+        }))
+        .then_ignore(just(Token::Ctrl(';')))
+        .labelled("import");
+
+    let use_ = just(Token::Use)
+        .ignore_then(ident.map_with_span(|name, span| (name, span)))
+        .then_ignore(just(Token::PathSep))
+        .then(ident.map_with_span(|name, span| (name, span)))
+        .then_ignore(just(Token::Ctrl(';')))
+        .labelled("use");
+
+    // The value is a single expression, optionally followed by a `;`
+    let global = docs
+        .then(
+            just(Token::Const)
+                .to(false)
+                .or(just(Token::Global).to(true)),
+        )
+        .then(
+            ident
+                .map_with_span(|name, span| (name, span))
+                .labelled("constant name"),
+        )
+        .then_ignore(just(Token::Op("=".to_string())))
+        .then(expr_parsers().0)
+        .then_ignore(just(Token::Ctrl(';')).or_not())
+        .map(|(((docs, mutable), name), value)| {
+            (
+                name,
+                Global {
+                    value,
+                    mutable,
+                    docs,
+                },
+            )
+        })
+        .labelled("constant");
+
+    func.map(|(name, f)| (name, Item::Func(f)))
+        .or(struct_.map(|(name, s)| (name, Item::Struct(s))))
+        .or(enum_.map(|(name, e)| (name, Item::Enum(e))))
+        .or(global.map(|(name, g)| (name, Item::Global(g))))
+        .or(import.map(|path| (path, Item::Import)))
+        .or(use_.map(|(module, name)| (name, Item::Use(module))))
+        .repeated()
+        .try_map(|items, _| {
             let mut funcs = HashMap::new();
-            for ((name, name_span), f) in fs {
-                if funcs.insert(name.clone(), f).is_some() {
-                    return Err(Simple::custom(
-                        name_span.clone(),
-                        format!("Function '{}' already exists", name),
-                    ));
+            let mut structs = HashMap::new();
+            let mut enums: HashMap<String, Enum> = HashMap::new();
+            let mut globals: HashMap<String, Global> = HashMap::new();
+            let mut imports = Vec::new();
+            let mut uses = Vec::new();
+            for ((name, name_span), item) in items {
+                match item {
+                    Item::Import => imports.push((name, name_span)),
+                    Item::Use(module) => uses.push((module, (name, name_span))),
+                    Item::Func(f) => {
+                        if is_builtin(&name) {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("Function '{}' is a builtin and can't be redefined", name),
+                            ));
+                        }
+                        if globals.contains_key(&name) {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("'{}' already exists as a constant or global", name),
+                            ));
+                        }
+                        if funcs.insert(name.clone(), f).is_some() {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("Function '{}' already exists", name),
+                            ));
+                        }
+                    }
+                    Item::Global(g) => {
+                        if is_builtin(&name) {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("'{}' is a builtin and can't be redefined", name),
+                            ));
+                        }
+                        if funcs.contains_key(&name) || globals.insert(name.clone(), g).is_some() {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("'{}' already exists", name),
+                            ));
+                        }
+                    }
+                    Item::Struct(s) => {
+                        if s.fields.is_empty() {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("Struct '{}' needs at least one field", name),
+                            ));
+                        }
+                        let mut seen = Vec::new();
+                        for (field, field_span) in &s.fields {
+                            if seen.contains(&field) {
+                                return Err(Simple::custom(
+                                    field_span.clone(),
+                                    format!(
+                                        "Field '{}' already exists in struct '{}'",
+                                        field, name
+                                    ),
+                                ));
+                            }
+                            seen.push(field);
+                        }
+                        if enums.contains_key(&name) || structs.insert(name.clone(), s).is_some() {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("Type '{}' already exists", name),
+                            ));
+                        }
+                    }
+                    Item::Enum(e) => {
+                        if e.variants.is_empty() {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("Enum '{}' needs at least one variant", name),
+                            ));
+                        }
+                        let mut seen = Vec::new();
+                        for variant in &e.variants {
+                            let (variant_name, variant_span) = &variant.name;
+                            if seen.contains(&variant_name) {
+                                return Err(Simple::custom(
+                                    variant_span.clone(),
+                                    format!(
+                                        "Variant '{}' already exists in enum '{}'",
+                                        variant_name, name
+                                    ),
+                                ));
+                            }
+                            seen.push(variant_name);
+                        }
+                        if structs.contains_key(&name) || enums.insert(name.clone(), e).is_some() {
+                            return Err(Simple::custom(
+                                name_span,
+                                format!("Type '{}' already exists", name),
+                            ));
+                        }
+                    }
                 }
             }
-            Ok(funcs)
+            Ok(Module {
+                program: Program {
+                    funcs,
+                    structs,
+                    enums,
+                    globals,
+                },
+                imports,
+                uses,
+            })
         })
         .then_ignore(end())
 }
 
+/// Functions that are part of the language, with the number of arguments they take
+pub const BUILTINS: &[(&str, usize)] = &[
+    ("len", 1),
+    ("push", 2),
+    ("pop", 1),
+    ("keys", 1),
+    ("contains", 2),
+    ("remove", 2),
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.iter().any(|(builtin, _)| *builtin == name)
+}
+
+/// Checks that a number can index a list of the given length
+fn list_index(num: Num, len: usize, span: Span) -> Result<usize, Error> {
+    match num {
+        Num::Int(n) if (0..len as i64).contains(&n) => Ok(n as usize),
+        Num::Float(x) if x.fract() == 0.0 && x >= 0.0 && (x as usize) < len => Ok(x as usize),
+        _ => Err(Error {
+            span,
+            msg: format!("'{}' index out of range for list length {}", num, len),
+        }),
+    }
+}
+
+/// Checks that a value can be used as the key of a map
+fn map_key(val: Value, span: Span) -> Result<Key, Error> {
+    Key::or_err(&val).map_err(|msg| Error { span, msg })
+}
+
+/// Checks whether a value matches a pattern, pushing the variables the pattern binds
+fn bind_pattern(pat: &Pattern, val: &Value, stack: &mut Vec<(String, Var)>) -> bool {
+    match (pat, val) {
+        (Pattern::Wildcard, _) => true,
+        (Pattern::Bind(name), val) => {
+            bind(stack, name, val.clone());
+            true
+        }
+        (Pattern::Value(lit), val) => lit == val,
+        (Pattern::Variant((name, _), (variant, _), pats), Value::Variant(n, v, vals)) => {
+            name == n
+                && variant == v
+                && pats
+                    .iter()
+                    .zip(vals)
+                    .all(|((pat, _), val)| bind_pattern(pat, val, stack))
+        }
+        _ => false,
+    }
+}
+
+/// Calls a builtin with its evaluated arguments. The resolver made sure the argument count is right.
+fn call_builtin(name: &str, args: Vec<(Value, Span)>) -> Result<Value, Error> {
+    let mut args = args.into_iter();
+    let (first, span) = args.next().unwrap();
+    if let "keys" | "contains" | "remove" = name {
+        let entries = first.map_or_err(span)?;
+        if name == "keys" {
+            let keys = entries.borrow().keys().map(Value::from).collect();
+            return Ok(Value::List(Rc::new(RefCell::new(keys))));
+        }
+        let (key, key_span) = args.next().unwrap();
+        let key = map_key(key, key_span.clone())?;
+        return Ok(match name {
+            "contains" => Value::Bool(entries.borrow().contains_key(&key)),
+            _ => entries.borrow_mut().remove(&key).ok_or_else(|| Error {
+                span: key_span,
+                msg: missing_key_error(&key),
+            })?,
+        });
+    }
+    let list = first.list_or_err(span.clone())?;
+    Ok(match name {
+        "len" => Value::Int(list.borrow().len() as i64),
+        "push" => {
+            list.borrow_mut().push(args.next().unwrap().0);
+            Value::Null
+        }
+        "pop" => list.borrow_mut().pop().ok_or_else(|| Error {
+            span,
+            msg: "Cannot pop from an empty list".to_string(),
+        })?,
+        _ => unreachable!(),
+    })
+}
+
 pub struct Error {
     pub span: Span,
     pub msg: String,
 }
 
+/// Why evaluation of an expression stopped early. Everything but errors is control flow that gets caught further up.
+pub enum Unwind {
+    Error(Error),
+    Break(Value),
+    Continue,
+    Return(Value),
+}
+
+impl From<Error> for Unwind {
+    fn from(err: Error) -> Self {
+        Unwind::Error(err)
+    }
+}
+
+/// Evaluates the body of a function
 pub fn ast_evaluator(
     expr: &Spanned<Expr>,
     funcs: &HashMap<String, Func>,
-    stack: &mut Vec<(String, Value)>,
+    stack: &mut Vec<(String, Var)>,
 ) -> Result<Value, Error> {
+    match evaluate(expr, funcs, stack) {
+        Ok(val) | Err(Unwind::Return(val)) => Ok(val),
+        Err(Unwind::Error(err)) => Err(err),
+        // The resolver rejects `break` and `continue` outside of loops
+        Err(Unwind::Break(_) | Unwind::Continue) => unreachable!(),
+    }
+}
+
+fn evaluate(
+    expr: &Spanned<Expr>,
+    funcs: &HashMap<String, Func>,
+    stack: &mut Vec<(String, Var)>,
+) -> Result<Value, Unwind> {
     Ok(match &expr.0 {
-        Expr::Return(returnexpr) => ast_evaluator(returnexpr, funcs, stack)?,
+        Expr::Return(returnexpr) => {
+            return Err(Unwind::Return(evaluate(returnexpr, funcs, stack)?))
+        }
         Expr::Error => unreachable!(), // Error expressions only get created by parser errors, so cannot exist in a valid AST
         Expr::Value(val) => val.clone(),
-        Expr::List(items) => Value::List(
+        Expr::List(items) => Value::List(Rc::new(RefCell::new(
             items
                 .iter()
-                .map(|item| ast_evaluator(item, funcs, stack))
+                .map(|item| evaluate(item, funcs, stack))
                 .collect::<Result<_, _>>()?,
-        ),
+        ))),
         Expr::LocalVar(name) => stack
             .iter()
             .rev()
             .find(|(l, _)| l == name)
-            .map(|(_, v)| v.clone())
+            .map(|(_, v)| v.borrow().clone())
             .or_else(|| Some(Value::Func(name.clone())).filter(|_| funcs.contains_key(name)))
             .ok_or_else(|| Error {
                 span: expr.1.clone(),
                 msg: format!("No such variable '{}' in scope", name),
             })?,
         Expr::Let(local, val, body) => {
-            let val = ast_evaluator(val, funcs, stack)?;
-            stack.push((local.clone(), val));
-            let res = ast_evaluator(body, funcs, stack)?;
+            let val = evaluate(val, funcs, stack)?;
+            bind(stack, local, val);
+            let res = evaluate(body, funcs, stack)?;
             stack.pop();
             res
         }
+        Expr::Tuple(items) => Value::Tuple(
+            items
+                .iter()
+                .map(|item| evaluate(item, funcs, stack))
+                .collect::<Result<_, _>>()?,
+        ),
+        Expr::Map(entries) => {
+            let mut vals = BTreeMap::new();
+            for (key, val) in entries {
+                let key_val = map_key(evaluate(key, funcs, stack)?, key.1.clone())?;
+                vals.insert(key_val, evaluate(val, funcs, stack)?);
+            }
+            Value::Map(Rc::new(RefCell::new(vals)))
+        }
+        Expr::Destructure(names, val, body) => {
+            let vals = match evaluate(val, funcs, stack)? {
+                Value::Tuple(vals) if vals.len() == names.len() => vals,
+                other => {
+                    let len = match &other {
+                        Value::Tuple(vals) => Some(vals.len()),
+                        _ => None,
+                    };
+                    return Err(Error {
+                        span: val.1.clone(),
+                        msg: destructure_error(other, len, names.len()),
+                    }
+                    .into());
+                }
+            };
+            let depth = stack.len();
+            for ((name, _), val) in names.iter().zip(vals) {
+                bind(stack, name, val);
+            }
+            let res = evaluate(body, funcs, stack)?;
+            stack.truncate(depth);
+            res
+        }
         Expr::Then(a, b) => {
-            ast_evaluator(a, funcs, stack)?;
-            ast_evaluator(b, funcs, stack)?
+            evaluate(a, funcs, stack)?;
+            evaluate(b, funcs, stack)?
+        }
+        Expr::Binary(
+            a,
+            op @ (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod),
+            b,
+        ) => {
+            let lhs = evaluate(a, funcs, stack)?.num_or_err(a.1.clone())?;
+            let rhs = evaluate(b, funcs, stack)?.num_or_err(b.1.clone())?;
+            let res = lhs.arithmetic(op, rhs).map_err(|msg| Error {
+                span: expr.1.clone(),
+                msg,
+            })?;
+            res.into()
         }
-        Expr::Binary(a, BinaryOp::Add, b) => Value::Num(
-            ast_evaluator(a, funcs, stack)?.num_or_err(a.1.clone())?
-                + ast_evaluator(b, funcs, stack)?.num_or_err(b.1.clone())?,
-        ),
         Expr::Binary(a, BinaryOp::LowerT, b) => Value::Bool(
-            ast_evaluator(a, funcs, stack)?.num_or_err(a.1.clone())?
-                < ast_evaluator(b, funcs, stack)?.num_or_err(b.1.clone())?,
+            evaluate(a, funcs, stack)?.num_or_err(a.1.clone())?
+                < evaluate(b, funcs, stack)?.num_or_err(b.1.clone())?,
         ),
         Expr::Binary(a, BinaryOp::GreaterT, b) => Value::Bool(
-            ast_evaluator(a, funcs, stack)?.num_or_err(a.1.clone())?
-                > ast_evaluator(b, funcs, stack)?.num_or_err(b.1.clone())?,
+            evaluate(a, funcs, stack)?.num_or_err(a.1.clone())?
+                > evaluate(b, funcs, stack)?.num_or_err(b.1.clone())?,
+        ),
+        Expr::Binary(a, BinaryOp::LowerEq, b) => Value::Bool(
+            evaluate(a, funcs, stack)?.num_or_err(a.1.clone())?
+                <= evaluate(b, funcs, stack)?.num_or_err(b.1.clone())?,
         ),
-        Expr::Binary(a, BinaryOp::Sub, b) => Value::Num(
-            ast_evaluator(a, funcs, stack)?.num_or_err(a.1.clone())?
-                - ast_evaluator(b, funcs, stack)?.num_or_err(b.1.clone())?,
+        Expr::Binary(a, BinaryOp::GreaterEq, b) => Value::Bool(
+            evaluate(a, funcs, stack)?.num_or_err(a.1.clone())?
+                >= evaluate(b, funcs, stack)?.num_or_err(b.1.clone())?,
         ),
-        Expr::Binary(a, BinaryOp::Mul, b) => Value::Num(
-            ast_evaluator(a, funcs, stack)?.num_or_err(a.1.clone())?
-                * ast_evaluator(b, funcs, stack)?.num_or_err(b.1.clone())?,
+        Expr::Binary(a, BinaryOp::Concat, b) => Value::Str(format!(
+            "{}{}",
+            evaluate(a, funcs, stack)?,
+            evaluate(b, funcs, stack)?
+        )),
+        // The right side is only evaluated if the left side doesn't decide the result
+        Expr::Binary(a, BinaryOp::And, b) => Value::Bool(
+            evaluate(a, funcs, stack)?.bool_or_err(a.1.clone())?
+                && evaluate(b, funcs, stack)?.bool_or_err(b.1.clone())?,
         ),
-        Expr::Binary(a, BinaryOp::Div, b) => Value::Num(
-            ast_evaluator(a, funcs, stack)?.num_or_err(a.1.clone())?
-                / ast_evaluator(b, funcs, stack)?.num_or_err(b.1.clone())?,
+        Expr::Binary(a, BinaryOp::Or, b) => Value::Bool(
+            evaluate(a, funcs, stack)?.bool_or_err(a.1.clone())?
+                || evaluate(b, funcs, stack)?.bool_or_err(b.1.clone())?,
         ),
+        Expr::Unary(UnaryOp::Neg, a) => {
+            let num = evaluate(a, funcs, stack)?.num_or_err(a.1.clone())?;
+            let res = num.checked_neg().map_err(|msg| Error {
+                span: expr.1.clone(),
+                msg,
+            })?;
+            res.into()
+        }
+        Expr::Binary(a, op @ (BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor), b) => {
+            let lhs = evaluate(a, funcs, stack)?.int_or_err(a.1.clone())?;
+            let rhs = evaluate(b, funcs, stack)?.int_or_err(b.1.clone())?;
+            Value::Int(match op {
+                BinaryOp::BitAnd => lhs & rhs,
+                BinaryOp::BitOr => lhs | rhs,
+                _ => lhs ^ rhs,
+            })
+        }
+        Expr::Binary(a, op @ (BinaryOp::Shl | BinaryOp::Shr), b) => {
+            let lhs = evaluate(a, funcs, stack)?.int_or_err(a.1.clone())?;
+            let rhs = evaluate(b, funcs, stack)?.int_or_err(b.1.clone())?;
+            if !(0..64).contains(&rhs) {
+                return Err(Error {
+                    span: b.1.clone(),
+                    msg: format!("Shift amount {} is out of range 0..64", rhs),
+                }
+                .into());
+            }
+            Value::Int(match op {
+                BinaryOp::Shl => lhs << rhs,
+                _ => lhs >> rhs,
+            })
+        }
+        Expr::Unary(UnaryOp::BitNot, a) => {
+            Value::Int(!evaluate(a, funcs, stack)?.int_or_err(a.1.clone())?)
+        }
+        Expr::Unary(UnaryOp::Not, a) => {
+            Value::Bool(!evaluate(a, funcs, stack)?.bool_or_err(a.1.clone())?)
+        }
         Expr::Binary(a, BinaryOp::Eq, b) => {
-            Value::Bool(ast_evaluator(a, funcs, stack)? == ast_evaluator(b, funcs, stack)?)
+            Value::Bool(evaluate(a, funcs, stack)? == evaluate(b, funcs, stack)?)
         }
         Expr::Binary(a, BinaryOp::NotEq, b) => {
-            Value::Bool(ast_evaluator(a, funcs, stack)? != ast_evaluator(b, funcs, stack)?)
+            Value::Bool(evaluate(a, funcs, stack)? != evaluate(b, funcs, stack)?)
         }
-        Expr::Binary(l, BinaryOp::ListAt, i) => {
-            let listContent = ast_evaluator(l, funcs, stack)?.list_or_err(l.1.clone())?;
-            let num = ast_evaluator(i, funcs, stack)?.num_or_err(i.1.clone())?;
-
-            if (num as usize) < listContent.len() {
-                listContent[num as usize].clone()
-            } else {
-                return Err(Error {
+        // Indexing a map looks up a key instead
+        Expr::Binary(l, BinaryOp::ListAt, i) => match evaluate(l, funcs, stack)? {
+            Value::Map(entries) => {
+                let key = map_key(evaluate(i, funcs, stack)?, i.1.clone())?;
+                let val = entries.borrow().get(&key).cloned();
+                val.ok_or_else(|| Error {
                     span: i.1.clone(),
-                    msg: format!(
-                        "'{:?}' index out of range for list length {}",
-                        num,
-                        listContent.len()
-                    ),
-                });
+                    msg: missing_key_error(&key),
+                })?
+            }
+            list => {
+                let list = list.list_or_err(l.1.clone())?;
+                let num = evaluate(i, funcs, stack)?.num_or_err(i.1.clone())?;
+                let list_content = list.borrow();
+                list_content[list_index(num, list_content.len(), i.1.clone())?].clone()
+            }
+        },
+        // Setting a key of a map inserts it if it is missing
+        Expr::SetIndex(l, i, val) => match evaluate(l, funcs, stack)? {
+            Value::Map(entries) => {
+                let key = map_key(evaluate(i, funcs, stack)?, i.1.clone())?;
+                let val = evaluate(val, funcs, stack)?;
+                entries.borrow_mut().insert(key, val);
+                Value::Null
             }
+            list => {
+                let list = list.list_or_err(l.1.clone())?;
+                let num = evaluate(i, funcs, stack)?.num_or_err(i.1.clone())?;
+                let val = evaluate(val, funcs, stack)?;
+                let mut list_content = list.borrow_mut();
+                let index = list_index(num, list_content.len(), i.1.clone())?;
+                list_content[index] = val;
+                Value::Null
+            }
+        },
+        Expr::Struct((name, _), fields) => {
+            let mut vals = BTreeMap::new();
+            for ((field, _), val) in fields {
+                vals.insert(field.clone(), evaluate(val, funcs, stack)?);
+            }
+            Value::Struct(name.clone(), Rc::new(RefCell::new(vals)))
+        }
+        Expr::Field(obj, field) => {
+            let fields = evaluate(obj, funcs, stack)?.field_or_err(obj.1.clone(), field)?;
+            let val = fields.borrow()[&field.0].clone();
+            val
+        }
+        Expr::SetField(obj, field, val) => {
+            let fields = evaluate(obj, funcs, stack)?.field_or_err(obj.1.clone(), field)?;
+            let val = evaluate(val, funcs, stack)?;
+            fields.borrow_mut().insert(field.0.clone(), val);
+            Value::Null
+        }
+        Expr::Variant((name, _), (variant, _), vals) => Value::Variant(
+            name.clone(),
+            variant.clone(),
+            vals.iter()
+                .map(|val| evaluate(val, funcs, stack))
+                .collect::<Result<_, _>>()?,
+        ),
+        // The first arm whose pattern matches is taken, its bindings are only visible in its body
+        Expr::Match(val, arms) => {
+            let scrutinee = evaluate(val, funcs, stack)?;
+            let depth = stack.len();
+            let arm = arms.iter().find(|((pat, _), _)| {
+                stack.truncate(depth);
+                bind_pattern(pat, &scrutinee, stack)
+            });
+            let Some((_, body)) = arm else {
+                return Err(Error {
+                    span: val.1.clone(),
+                    msg: format!("No pattern matches '{}'", scrutinee),
+                }
+                .into());
+            };
+            let res = evaluate(body, funcs, stack);
+            stack.truncate(depth);
+            res?
+        }
+        Expr::Call(func, (args, _)) if matches!(&func.0, Expr::LocalVar(name) if is_builtin(name)) =>
+        {
+            let Expr::LocalVar(name) = &func.0 else {
+                unreachable!()
+            };
+            let args = args
+                .iter()
+                .map(|arg| Ok((evaluate(arg, funcs, stack)?, arg.1.clone())))
+                .collect::<Result<_, Unwind>>()?;
+            call_builtin(name, args)?
         }
         Expr::Call(func, (args, args_span)) => {
-            let f = ast_evaluator(func, funcs, stack)?;
-            match f {
+            let f = evaluate(func, funcs, stack)?;
+            // Closures start out with the variables they captured
+            let (name, params, body, mut new_stack) = match &f {
                 Value::Func(name) => {
-                    let f = &funcs[&name];
-                    let mut stack = if f.args.len() != args.len() {
-                        return Err(Error {
-                            span: args_span.clone(),
-                            msg: format!("'{}' called with wrong number of arguments (expected {}, found {})", name, f.args.len(), args.len()),
-                        });
-                    } else {
-                        f.args
-                            .iter()
-                            .zip(args.iter())
-                            .map(|(name, arg)| {
-                                Ok((name.clone(), ast_evaluator(arg, funcs, stack)?))
-                            })
-                            .collect::<Result<_, _>>()?
-                    };
-                    ast_evaluator(&f.body, funcs, &mut stack)?
+                    let f = &funcs[name];
+                    (name.as_str(), &f.args, &f.body, Vec::new())
                 }
+                Value::Closure(c) => ("<closure>", &c.args, &*c.body, c.captures.clone()),
                 f => {
                     return Err(Error {
                         span: func.1.clone(),
                         msg: format!("'{:?}' is not callable", f),
-                    })
+                    }
+                    .into())
                 }
+            };
+            if params.len() != args.len() {
+                return Err(Error {
+                    span: args_span.clone(),
+                    msg: format!(
+                        "'{}' called with wrong number of arguments (expected {}, found {})",
+                        name,
+                        params.len(),
+                        args.len()
+                    ),
+                }
+                .into());
             }
+            for (param, arg) in params.iter().zip(args) {
+                let val = evaluate(arg, funcs, stack)?;
+                bind(&mut new_stack, param, val);
+            }
+            ast_evaluator(body, funcs, &mut new_stack)?
+        }
+        // Captured variables are shared, assigning to them is seen by both the closure and its scope
+        Expr::Closure(args, body) => {
+            let captures = expr
+                .0
+                .free_vars()
+                .into_iter()
+                .filter_map(|name| {
+                    let var = stack.iter().rev().find(|(var, _)| *var == name)?.1.clone();
+                    Some((name, var))
+                })
+                .collect();
+            Value::Closure(Rc::new(Closure {
+                args: args.clone(),
+                body: body.clone(),
+                captures,
+            }))
         }
         Expr::If(cond, a, b) => {
-            let c = ast_evaluator(cond, funcs, stack)?;
+            let c = evaluate(cond, funcs, stack)?;
             match c {
-                Value::Bool(true) => ast_evaluator(a, funcs, stack)?,
-                Value::Bool(false) => ast_evaluator(b, funcs, stack)?,
+                Value::Bool(true) => evaluate(a, funcs, stack)?,
+                Value::Bool(false) => evaluate(b, funcs, stack)?,
                 c => {
                     return Err(Error {
                         span: cond.1.clone(),
                         msg: format!("Conditions must be booleans, found '{:?}'", c),
-                    })
+                    }
+                    .into())
                 }
             }
         }
         Expr::Print(a) => {
-            let val = ast_evaluator(a, funcs, stack)?;
+            let val = evaluate(a, funcs, stack)?;
             println!("{}", val);
             val
         }
         Expr::Assign(local, val, body) => {
-            let val = ast_evaluator(val, funcs, stack)?;
-            stack.into_iter().for_each(|elem| {
-                if elem.0 == *local {
-                    elem.1 = val.clone();
+            let span = expr.1.start..val.1.end;
+            let val = evaluate(val, funcs, stack)?;
+            let Some((_, var)) = stack.iter().rev().find(|(name, _)| name == local) else {
+                return Err(Error {
+                    span,
+                    msg: format!("No such variable '{}' in scope", local),
                 }
-            });
-            let res = ast_evaluator(body, funcs, stack)?;
+                .into());
+            };
+            *var.borrow_mut() = val;
+            evaluate(body, funcs, stack)?
+        }
+        // A loop produces the value it is left with by `break`, or null once its condition is false
+        Expr::Loop(cond, body) => loop {
+            if !evaluate(cond, funcs, stack)?.bool_or_err(cond.1.clone())? {
+                break Value::Null;
+            }
+            // Variables of an iteration that got cut short are still on the stack
+            let depth = stack.len();
+            let res = evaluate(body, funcs, stack);
+            stack.truncate(depth);
+            match res {
+                Ok(_) | Err(Unwind::Continue) => {}
+                Err(Unwind::Break(val)) => break val,
+                Err(err) => return Err(err),
+            }
+        },
+        Expr::For(name, items, body) => {
+            let items_val = match evaluate(items, funcs, stack)? {
+                // Maps are iterated by key, the keys are taken before the loop starts
+                Value::Map(entries) => {
+                    let keys = entries.borrow().keys().map(Value::from).collect();
+                    Rc::new(RefCell::new(keys))
+                }
+                items_val => items_val.list_or_err(items.1.clone())?,
+            };
+            let mut res = Value::Null;
+            // Items pushed to the list while it is iterated are visited as well
+            for i in 0.. {
+                let Some(item) = items_val.borrow().get(i).cloned() else {
+                    break;
+                };
+                let depth = stack.len();
+                bind(stack, name, item);
+                let iteration = evaluate(body, funcs, stack);
+                stack.truncate(depth);
+                match iteration {
+                    Ok(_) | Err(Unwind::Continue) => {}
+                    Err(Unwind::Break(val)) => {
+                        res = val;
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
             res
         }
-        Expr::Loop(cond, body) => {
-            while let Value::Bool(true) = ast_evaluator(cond, funcs, stack)? {
-                ast_evaluator(body, funcs, stack)?;
+        // The evaluator has no lazy iterators, ranges simply become lists of numbers
+        Expr::Range(start, end, inclusive) => {
+            let mut x = evaluate(start, funcs, stack)?.num_or_err(start.1.clone())?;
+            let end_val = evaluate(end, funcs, stack)?.num_or_err(end.1.clone())?;
+            let mut items = Vec::new();
+            while x < end_val || (*inclusive && x == end_val) {
+                items.push(x.into());
+                x = x.step();
             }
-            stack.last().unwrap().1.clone()
+            Value::List(Rc::new(RefCell::new(items)))
         }
+        Expr::Break(val) => return Err(Unwind::Break(evaluate(val, funcs, stack)?)),
+        Expr::Continue => return Err(Unwind::Continue),
+        Expr::Path(..) => unreachable!("paths are replaced when modules are merged"),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chumsky::error::SimpleReason;
+
+    /// The tokens of source that lexes without errors
+    fn tokens(src: &str) -> Vec<Token> {
+        let (tokens, errs) = lexer(src);
+        assert!(errs.is_empty(), "{:?}", errs);
+        tokens.into_iter().map(|(tok, _)| tok).collect()
+    }
+
+    fn op(op: &str) -> Token {
+        Token::Op(op.to_string())
+    }
+
+    fn ident(name: &str) -> Token {
+        Token::Ident(name.to_string())
+    }
+
+    fn lex_errors(src: &str) -> Vec<String> {
+        lexer(src)
+            .1
+            .iter()
+            .map(|e| match e.reason() {
+                SimpleReason::Custom(msg) => msg.clone(),
+                reason => format!("{:?}", reason),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn operators_take_the_longest_match() {
+        for op_str in OPERATORS {
+            assert_eq!(tokens(op_str), vec![op(op_str)]);
+        }
+        assert_eq!(tokens("a<=b"), vec![ident("a"), op("<="), ident("b")]);
+        assert_eq!(
+            tokens("a<-b"),
+            vec![ident("a"), op("<"), op("-"), ident("b")]
+        );
+        assert_eq!(
+            tokens("a==>b"),
+            vec![ident("a"), op("=="), op(">"), ident("b")]
+        );
+        assert_eq!(
+            tokens("a::b => c"),
+            vec![
+                ident("a"),
+                Token::PathSep,
+                ident("b"),
+                Token::FatArrow,
+                ident("c"),
+            ]
+        );
+    }
+
+    fn lit(s: &str) -> StrPart {
+        StrPart::Lit(s.to_string())
+    }
+
+    #[test]
+    fn string_escapes_are_decoded() {
+        assert_eq!(
+            tokens(r#""a\n\t\r\0\"\\\{\}\u{1F600}""#),
+            vec![Token::Str(vec![lit("a\n\t\r\0\"\\{}\u{1F600}")])]
+        );
+        assert_eq!(tokens(r#""""#), vec![Token::Str(vec![lit("")])]);
+
+        assert_eq!(
+            lex_errors(r#""a\q" "\u{110000}""#),
+            vec![
+                "Invalid escape sequence '\\q'",
+                "Invalid escape sequence '\\u{110000}'"
+            ]
+        );
+    }
+
+    #[test]
+    fn interpolations_are_lexed_in_place() {
+        let (spanned, errs) = lexer(r#""x = {x + 1}!""#);
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(
+            spanned,
+            vec![(
+                Token::Str(vec![
+                    lit("x = "),
+                    StrPart::Expr(vec![
+                        (ident("x"), 6..7),
+                        (op("+"), 8..9),
+                        (Token::Int(1), 10..11)
+                    ]),
+                    lit("!"),
+                ]),
+                0..14
+            )]
+        );
+        // Escaped braces are plain text
+        assert_eq!(
+            tokens(r#""\{x\} {y}""#),
+            vec![Token::Str(vec![
+                lit("{x} "),
+                StrPart::Expr(vec![(ident("y"), 8..9)])
+            ])]
+        );
+        // Braces and strings inside an interpolation don't end it
+        let nested = tokens(r#""{f([a, "}"])}""#);
+        let [Token::Str(parts)] = nested.as_slice() else {
+            panic!("expected a single string");
+        };
+        let [StrPart::Expr(inner)] = parts.as_slice() else {
+            panic!("expected a single interpolation, found {:?}", parts);
+        };
+        assert_eq!(
+            inner.iter().map(|(tok, _)| tok.clone()).collect::<Vec<_>>(),
+            vec![
+                ident("f"),
+                Token::Ctrl('('),
+                Token::Ctrl('['),
+                ident("a"),
+                Token::Ctrl(','),
+                Token::Str(vec![lit("}")]),
+                Token::Ctrl(']'),
+                Token::Ctrl(')'),
+            ]
+        );
+    }
+
+    #[test]
+    fn string_errors() {
+        assert_eq!(
+            lex_errors(r#""a {} b""#),
+            vec!["Empty interpolation in string literal"]
+        );
+        let (_, errs) = lexer("x \"abc");
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].span(), 2..3);
+        assert_eq!(lex_errors("\"abc"), vec!["Unterminated string literal"]);
+        // An interpolation without `}` ends at the quote that closes the string
+        assert_eq!(
+            lex_errors(r#""a{" + b"#),
+            vec!["Unterminated interpolation in string literal"]
+        );
+        let (spanned, errs) = lexer(r#""a{" + b"#);
+        assert_eq!(errs[0].span(), 2..3);
+        assert_eq!(
+            spanned,
+            vec![
+                (Token::Str(vec![lit("a")]), 0..4),
+                (op("+"), 5..6),
+                (ident("b"), 7..8)
+            ]
+        );
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(
+            tokens("a /* x /* y */ z */ b /**/ c"),
+            vec![ident("a"), ident("b"), ident("c")]
+        );
+        assert_eq!(tokens("a /* // */ b"), vec![ident("a"), ident("b")]);
+        let (spanned, errs) = lexer("a /* x /* y */ b");
+        assert_eq!(spanned, vec![(ident("a"), 0..1)]);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].span(), 2..4);
+        assert_eq!(lex_errors("/*"), vec!["Unterminated block comment"]);
+    }
+
+    #[test]
+    fn doc_comments_only_before_items() {
+        assert_eq!(
+            tokens("/// Adds\n///  one \nfn f() { /// note\n 1 } /// end"),
+            vec![
+                Token::Doc("Adds".to_string()),
+                Token::Doc("one".to_string()),
+                Token::Fn,
+                ident("f"),
+                Token::Ctrl('('),
+                Token::Ctrl(')'),
+                Token::Ctrl('{'),
+                Token::Int(1),
+                Token::Ctrl('}'),
+            ]
+        );
+        // Banner lines are ordinary comments
+        assert_eq!(tokens("//// Banner ////\n////\nfn"), vec![Token::Fn]);
+        assert_eq!(
+            tokens("///\nstruct"),
+            vec![Token::Doc(String::new()), Token::Struct]
+        );
+    }
+
+    /// Writes operators as prefix s-expressions, so the tests can see how an expression was grouped
+    fn grouping(expr: &Expr) -> String {
+        match expr {
+            Expr::Binary(a, op, b) => format!("({:?} {} {})", op, grouping(&a.0), grouping(&b.0)),
+            Expr::Unary(op, a) => format!("({:?} {})", op, grouping(&a.0)),
+            Expr::LocalVar(name) => name.clone(),
+            Expr::Value(val) => val.to_string(),
+            Expr::Field(a, (name, _)) => format!("{}.{}", grouping(&a.0), name),
+            Expr::Call(f, (args, _)) => format!(
+                "{}({})",
+                grouping(&f.0),
+                args.iter()
+                    .map(|arg| grouping(&arg.0))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            expr => format!("{:?}", expr),
+        }
+    }
+
+    fn parse_expr(src: &str) -> String {
+        let (tokens, errs) = lexer(src);
+        assert!(errs.is_empty(), "{:?}", errs);
+        let len = src.len();
+        let expr = expr_parser()
+            .then_ignore(end())
+            .parse(Stream::from_iter(len..len, tokens.into_iter()))
+            .unwrap_or_else(|errs| panic!("{}: {:?}", src, errs));
+        grouping(&expr.0)
+    }
+
+    #[test]
+    fn operator_precedence() {
+        for (src, grouped) in [
+            ("a + b * c", "(Add a (Mul b c))"),
+            ("a - b - c", "(Sub (Sub a b) c)"),
+            ("a <= b == c >= d", "(Eq (LowerEq a b) (GreaterEq c d))"),
+            ("(a + b) * c", "(Mul (Add a b) c)"),
+            ("!a || b", "(Or (Not a) b)"),
+            ("a != b && c", "(And (NotEq a b) c)"),
+            ("a || b && c", "(Or a (And b c))"),
+            ("!!f(a)", "(Not (Not f(a)))"),
+            ("-a * b % c", "(Mod (Mul (Neg a) b) c)"),
+            ("a - -b", "(Sub a (Neg b))"),
+            ("!-~a", "(Not (Neg (BitNot a)))"),
+            ("-f(x).y", "(Neg f(x).y)"),
+            ("a << b + c", "(Shl a (Add b c))"),
+            ("a & b << c", "(BitAnd a (Shl b c))"),
+            ("a | b ^ c & d", "(BitOr a (BitXor b (BitAnd c d)))"),
+            ("a < b | c", "(LowerT a (BitOr b c))"),
+        ] {
+            assert_eq!(parse_expr(src), grouped, "{}", src);
+        }
+    }
+
+    /// Parses a module, giving the messages of the syntax errors if there are any
+    fn parse(src: &str) -> Result<Module, Vec<String>> {
+        let (tokens, lex_errs) = lexer(src);
+        assert!(lex_errs.is_empty(), "{:?}", lex_errs);
+        let end = src.len();
+        funcs_parser()
+            .parse(Stream::from_iter(end..end, tokens.into_iter()))
+            .map_err(|errs| {
+                errs.iter()
+                    .map(|e| match e.reason() {
+                        SimpleReason::Custom(msg) => msg.clone(),
+                        _ => e.to_string(),
+                    })
+                    .collect()
+            })
+    }
+
+    /// Runs `main` with the AST evaluator
+    fn evaluate_main(src: &str) -> Value {
+        let funcs = parse(src).unwrap().program.funcs;
+        ast_evaluator(&funcs["main"].body, &funcs, &mut Vec::new())
+            .unwrap_or_else(|e| panic!("{}", e.msg))
+    }
+
+    #[test]
+    fn assigning_to_an_unknown_variable_is_an_error() {
+        let src = "fn main() { let x = 1; y = x + 1; x }";
+        let funcs = parse(src).unwrap().program.funcs;
+        let err = ast_evaluator(&funcs["main"].body, &funcs, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.msg, "No such variable 'y' in scope");
+        assert_eq!(&src[err.span], "y = x + 1");
+    }
+
+    #[test]
+    fn for_loops_visit_lists_and_ranges() {
+        assert_eq!(
+            evaluate_main(
+                "fn main() {\n\
+                 let s = 0;\n\
+                 for x in [3, 1, 2] { s = s * 10 + x; s };\n\
+                 for i in 4..=5 { s = s * 10 + i; s };\n\
+                 s\n\
+             }"
+            )
+            .to_string(),
+            "31245"
+        );
+    }
+
+    #[test]
+    fn evaluator_closures_share_the_variables_they_capture() {
+        let src = "fn counter(start) { let n = start; || { n = n + 1; n } }\n\
+                   fn main() {\n\
+                       let c = 0;\n\
+                       let inc = || { c = c + 1; c };\n\
+                       let out = [inc(), inc(), c];\n\
+                       c = 10;\n\
+                       push(out, inc());\n\
+                       let a = counter(5);\n\
+                       push(out, a()); push(out, a());\n\
+                       let fs = [];\n\
+                       for i in 0..3 { push(fs, || i * 2) };\n\
+                       for f in fs { push(out, f()) };\n\
+                       out\n\
+                   }";
+        assert_eq!(
+            evaluate_main(src).to_string(),
+            "[1, 2, 2, 11, 6, 7, 0, 2, 4]"
+        );
+    }
+
+    #[test]
+    fn doc_comments_attach_to_items() {
+        let module = parse(
+            "/// Twice\n/// the value\nfn double(x) {\n    /// just a comment\n    let y = x * 2; /// here too\n    y\n}\n\n/// A point\nstruct P { x }\n/// Maybe a value\nenum Opt { Some(v), None }\n/// The answer\nconst A = 42\nfn main() { double(A) }\n/// dangling",
+        )
+        .unwrap();
+        let program = &module.program;
+        assert_eq!(program.funcs["double"].docs, vec!["Twice", "the value"]);
+        assert!(program.funcs["main"].docs.is_empty());
+        assert_eq!(program.structs["P"].docs, vec!["A point"]);
+        assert_eq!(program.enums["Opt"].docs, vec!["Maybe a value"]);
+        assert_eq!(program.globals["A"].docs, vec!["The answer"]);
+    }
+
+    #[test]
+    fn interpolations_are_concatenated() {
+        assert_eq!(
+            evaluate_main(r#"fn main() { let x = 2; "x + 1 = {x + 1}, \{x\} {"a"}b" }"#)
+                .to_string(),
+            "x + 1 = 3, {x} ab"
+        );
+    }
+
+    #[test]
+    fn spans_are_byte_offsets() {
+        let (tokens, errs) = lexer("\"äö\" // ü\nlet x");
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Str(vec![lit("äö")]), 0..6),
+                (Token::Let, 13..16),
+                (ident("x"), 17..18)
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_characters_are_skipped() {
+        let (tokens, errs) = lexer("a $ b");
+        assert_eq!(tokens, vec![(ident("a"), 0..1), (ident("b"), 4..5)]);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].span(), 2..3);
+        assert_eq!(errs[0].found(), Some(&'$'));
+    }
+
+    #[test]
+    fn numeric_literals() {
+        assert_eq!(
+            tokens("42 1_000 0x1F 0XfF 0o17 0b1010_1010"),
+            vec![
+                Token::Int(42),
+                Token::Int(1000),
+                Token::Int(31),
+                Token::Int(255),
+                Token::Int(15),
+                Token::Int(170)
+            ]
+        );
+        assert_eq!(
+            tokens("1.5 2e3 1.5e-2 1_0.2_5 3E+1"),
+            vec![
+                Token::Float(Float(1.5)),
+                Token::Float(Float(2000.0)),
+                Token::Float(Float(0.015)),
+                Token::Float(Float(10.25)),
+                Token::Float(Float(30.0))
+            ]
+        );
+        // A float needs digits on both sides of the dot
+        assert_eq!(
+            tokens("1.x"),
+            vec![Token::Int(1), Token::Ctrl('.'), ident("x")]
+        );
+        assert_eq!(
+            evaluate_main("fn main() { 0x10 + 1_0.5e1 }").to_string(),
+            "121"
+        );
+    }
+
+    #[test]
+    fn malformed_numeric_literals() {
+        assert_eq!(
+            lex_errors("0x_"),
+            vec!["Number literal '0x_' has no digits"]
+        );
+        assert_eq!(
+            lex_errors("0b102"),
+            vec!["Invalid digit '2' in base 2 literal '0b102'"]
+        );
+        assert_eq!(
+            lex_errors("12abc"),
+            vec!["Invalid digit 'a' in base 10 literal '12abc'"]
+        );
+        assert_eq!(
+            lex_errors("0o8"),
+            vec!["Invalid digit '8' in base 8 literal '0o8'"]
+        );
+    }
+
+    #[test]
+    fn globals_take_an_optional_semicolon() {
+        let module =
+            parse("const A = 1;\nconst B = [A, 2]\nglobal C = A + 1;\nfn main() { C }").unwrap();
+        let globals = &module.program.globals;
+        assert!(matches!(globals["A"].value.0, Expr::Value(Value::Int(1))));
+        assert!(matches!(globals["B"].value.0, Expr::List(_)));
+        assert!(matches!(globals["C"].value.0, Expr::Binary(..)));
+
+        // Only one expression is taken, anything after it must start the next item
+        for (src, found) in [
+            ("const A = 1; 2", "found '2'"),
+            ("const A = 1; 2;", "found '2'"),
+            ("global A = 1;;", "found ';'"),
+            ("const A = if true { 1 } else { 2 }", "found 'if'"),
+        ] {
+            let errs = parse(src).unwrap_err();
+            assert!(
+                errs.iter().any(|e| e.starts_with(found)),
+                "{}: {:?}",
+                src,
+                errs
+            );
+        }
+    }
+
+    #[test]
+    fn integer_literals_have_64_bits() {
+        assert_eq!(
+            tokens(
+                "9007199254740993 9223372036854775807 0xFFFF_FFFF_FFFF_FFFF 0x8000_0000_0000_0000"
+            ),
+            vec![
+                Token::Int((1 << 53) + 1),
+                Token::Int(i64::MAX),
+                Token::Int(-1),
+                Token::Int(i64::MIN)
+            ]
+        );
+        assert_eq!(
+            lex_errors("9223372036854775808 0x1_0000_0000_0000_0000"),
+            vec![
+                "Integer literal '9223372036854775808' does not fit into 64 bits",
+                "Integer literal '0x1_0000_0000_0000_0000' does not fit into 64 bits"
+            ]
+        );
+        assert_eq!(
+            evaluate_main("fn main() { 9007199254740993 - 1 }").to_string(),
+            "9007199254740992"
+        );
+    }
+
+    #[test]
+    fn bitwise_operators_keep_all_bits() {
+        assert_eq!(
+            evaluate_main("fn main() { (1 << 60 | 1) ^ ~0 }").to_string(),
+            "-1152921504606846978"
+        );
+    }
+
+    #[test]
+    fn malformed_literals_leave_a_placeholder() {
+        let src = "fn main() { f(0b102) }";
+        let (tokens, errs) = lexer(src);
+        assert_eq!(errs.len(), 1);
+        assert_eq!(tokens[7], (Token::Int(0), 14..19));
+        // The parser doesn't report anything on top of the lexer error
+        let end = src.len();
+        let parsed = funcs_parser().parse(Stream::from_iter(end..end, tokens.into_iter()));
+        assert!(parsed.is_ok(), "{:?}", parsed);
+    }
+}
@@ -0,0 +1,650 @@
+use crate::{
+    diagnostic::Diagnostic,
+    exhaustive::missing_pattern,
+    globals::order,
+    parser::{
+        is_builtin, Enum, Expr, Func, Global, Pattern, Program, Span, Spanned, Struct, BUILTINS,
+    },
+};
+use std::collections::HashMap;
+
+/// Checks that every name used in a function refers to a variable in scope or to a declared function,
+/// that functions are called with as many arguments as they take, and that `break` and `continue` are inside loops.
+/// Also checks that every `match` has an arm for every value, and that the values of constants and globals
+/// can be evaluated at compile time. Code generation relies on this, it does not handle unknown names itself.
+struct Resolver<'a> {
+    funcs: &'a HashMap<String, Func>,
+    structs: &'a HashMap<String, Struct>,
+    enums: &'a HashMap<String, Enum>,
+    globals: &'a HashMap<String, Global>,
+    /// Variables in scope, innermost last
+    scope: Vec<&'a str>,
+    /// Whether the expression is the value of a constant or global, which may only use constants
+    initializer: bool,
+    /// How many loops the current expression is nested in
+    loops: usize,
+    errs: Vec<Diagnostic>,
+}
+
+impl<'a> Resolver<'a> {
+    fn is_var(&self, name: &str) -> bool {
+        self.scope.contains(&name)
+    }
+
+    /// Variables and globals, for suggestions
+    fn values(&self) -> Vec<&'a str> {
+        let globals = self.globals.keys().map(String::as_str);
+        self.scope.iter().copied().chain(globals).collect()
+    }
+
+    fn not_constant(&mut self, span: Span, msg: String) {
+        self.errs.push(
+            Diagnostic::new(span.clone(), msg)
+                .with_label(span, "Not a constant")
+                .with_note("Constants and globals are evaluated at compile time, so their values can only use constants"),
+        );
+    }
+
+    fn undefined(&mut self, span: Span, kind: &str, name: &str, candidates: Vec<&str>) {
+        let mut diag = Diagnostic::new(span.clone(), format!("Undefined {} '{}'", kind, name))
+            .with_label(span, format!("No {} named '{}' in scope", kind, name));
+        if let Some(suggestion) = closest(name, candidates) {
+            diag = diag.with_note(format!("Did you mean '{}'?", suggestion));
+        }
+        self.errs.push(diag);
+    }
+
+    /// Checks that a struct literal gives every field of the struct exactly once
+    fn check_fields(
+        &mut self,
+        name: &str,
+        name_span: &Span,
+        decl: &Struct,
+        fields: &[(Spanned<String>, Spanned<Expr>)],
+    ) {
+        let declared: Vec<&str> = decl.fields.iter().map(|(f, _)| f.as_str()).collect();
+        let mut given = Vec::new();
+        for ((field, field_span), _) in fields {
+            if !declared.contains(&field.as_str()) {
+                let mut diag = Diagnostic::new(
+                    field_span.clone(),
+                    format!("Struct '{}' has no field '{}'", name, field),
+                )
+                .with_label(field_span.clone(), "Unknown field");
+                if let Some(suggestion) = closest(field, declared.clone()) {
+                    diag = diag.with_note(format!("Did you mean '{}'?", suggestion));
+                }
+                self.errs.push(diag);
+            } else if given.contains(&field.as_str()) {
+                self.errs.push(
+                    Diagnostic::new(
+                        field_span.clone(),
+                        format!("Field '{}' is given more than once", field),
+                    )
+                    .with_label(field_span.clone(), "Already given before"),
+                );
+            }
+            given.push(field.as_str());
+        }
+        let missing: Vec<String> = declared
+            .iter()
+            .filter(|field| !given.contains(field))
+            .map(|field| format!("'{}'", field))
+            .collect();
+        if !missing.is_empty() {
+            self.errs.push(
+                Diagnostic::new(
+                    name_span.clone(),
+                    format!("Missing fields in struct '{}'", name),
+                )
+                .with_label(name_span.clone(), format!("Needs {}", missing.join(", "))),
+            );
+        }
+    }
+
+    /// Checks that a variant exists and gets as many values as it is declared with. Returns whether it does.
+    fn check_variant(
+        &mut self,
+        (name, name_span): &Spanned<String>,
+        (variant, variant_span): &Spanned<String>,
+        len: usize,
+        span: &Span,
+    ) -> bool {
+        let Some(decl) = self.enums.get(name) else {
+            let candidates = self.enums.keys().map(String::as_str).collect();
+            self.undefined(name_span.clone(), "enum", name, candidates);
+            return false;
+        };
+        let Some(decl) = decl.variants.iter().find(|v| v.name.0 == *variant) else {
+            let mut diag = Diagnostic::new(
+                variant_span.clone(),
+                format!("Enum '{}' has no variant '{}'", name, variant),
+            )
+            .with_label(variant_span.clone(), "Unknown variant");
+            let candidates = decl.variants.iter().map(|v| v.name.0.as_str()).collect();
+            if let Some(suggestion) = closest(variant, candidates) {
+                diag = diag.with_note(format!("Did you mean '{}'?", suggestion));
+            }
+            self.errs.push(diag);
+            return false;
+        };
+        let arity = decl.fields.len();
+        if arity != len {
+            let fields: Vec<&str> = decl.fields.iter().map(|(f, _)| f.as_str()).collect();
+            let declared = if fields.is_empty() {
+                variant.clone()
+            } else {
+                format!("{}({})", variant, fields.join(", "))
+            };
+            self.errs.push(
+                Diagnostic::new(
+                    span.clone(),
+                    format!(
+                        "Variant '{}::{}' has {} value{}, found {}",
+                        name,
+                        variant,
+                        arity,
+                        if arity == 1 { "" } else { "s" },
+                        len
+                    ),
+                )
+                .with_label(
+                    span.clone(),
+                    format!(
+                        "Expected {} value{} here",
+                        arity,
+                        if arity == 1 { "" } else { "s" }
+                    ),
+                )
+                .with_note(format!(
+                    "'{}::{}' is declared as {}",
+                    name, variant, declared
+                )),
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Checks the variants in a pattern and brings the variables it binds into scope. Returns whether it is valid.
+    fn resolve_pattern(
+        &mut self,
+        (pat, span): &'a Spanned<Pattern>,
+        bound: &mut Vec<&'a str>,
+    ) -> bool {
+        match pat {
+            Pattern::Wildcard | Pattern::Value(_) => true,
+            Pattern::Bind(name) => {
+                if bound.contains(&name.as_str()) {
+                    self.errs.push(
+                        Diagnostic::new(
+                            span.clone(),
+                            format!(
+                                "Variable '{}' is bound more than once in the same pattern",
+                                name
+                            ),
+                        )
+                        .with_label(span.clone(), "Bound again here"),
+                    );
+                    return false;
+                }
+                bound.push(name);
+                self.scope.push(name);
+                true
+            }
+            Pattern::Variant(name, variant, pats) => {
+                let valid = self.check_variant(name, variant, pats.len(), span);
+                pats.iter().fold(valid, |valid, pat| {
+                    self.resolve_pattern(pat, bound) && valid
+                })
+            }
+        }
+    }
+
+    fn resolve(&mut self, expr: &'a Spanned<Expr>) {
+        match &expr.0 {
+            Expr::LocalVar(name) if self.is_var(name) => {}
+            Expr::LocalVar(name)
+                if self.initializer && self.globals.get(name).is_some_and(|g| g.mutable) =>
+            {
+                self.not_constant(expr.1.clone(), format!("'{}' is a global", name))
+            }
+            Expr::LocalVar(name) if self.globals.contains_key(name) => {}
+            Expr::LocalVar(name) if self.initializer && self.funcs.contains_key(name) => {
+                self.not_constant(expr.1.clone(), format!("'{}' is a function", name))
+            }
+            Expr::LocalVar(name) if self.funcs.contains_key(name) => {}
+            Expr::LocalVar(name) if is_builtin(name) => self.errs.push(
+                Diagnostic::new(
+                    expr.1.clone(),
+                    format!("Builtin '{}' used as a value", name),
+                )
+                .with_label(expr.1.clone(), "Builtins can only be called"),
+            ),
+            Expr::LocalVar(name) => {
+                let candidates = self.values();
+                self.undefined(expr.1.clone(), "variable", name, candidates);
+            }
+            Expr::Let(name, val, body) => {
+                self.resolve(val);
+                self.scope.push(name);
+                self.resolve(body);
+                self.scope.pop();
+            }
+            Expr::Destructure(names, val, body) => {
+                self.resolve(val);
+                self.scope
+                    .extend(names.iter().map(|(name, _)| name.as_str()));
+                self.resolve(body);
+                self.scope.truncate(self.scope.len() - names.len());
+            }
+            Expr::Assign(name, val, body) => {
+                // Assignments start with the name they assign to
+                let span = expr.1.start..expr.1.start + name.len();
+                match self.globals.get(name) {
+                    _ if self.is_var(name) => {}
+                    Some(_) if self.initializer => {
+                        self.not_constant(span, format!("'{}' can't be assigned here", name))
+                    }
+                    Some(global) if !global.mutable => self.errs.push(
+                        Diagnostic::new(
+                            span.clone(),
+                            format!("Can't assign to constant '{}'", name),
+                        )
+                        .with_label(span, "Assigned here")
+                        .with_label(global.value.1.clone(), "Declared as a constant")
+                        .with_note(format!(
+                            "Declare it with `global {} = ...` to make it assignable",
+                            name
+                        )),
+                    ),
+                    Some(_) => {}
+                    None => {
+                        let candidates = self.values();
+                        self.undefined(span, "variable", name, candidates);
+                    }
+                }
+                self.resolve(val);
+                self.resolve(body);
+            }
+            Expr::Call(func, (args, args_span)) => {
+                match &func.0 {
+                    // Variables holding functions are checked when they are called
+                    Expr::LocalVar(name) if self.is_var(name) => {}
+                    Expr::LocalVar(name) if self.initializer && self.funcs.contains_key(name) => {
+                        self.not_constant(func.1.clone(), format!("'{}' is a function", name))
+                    }
+                    Expr::LocalVar(name) if self.globals.contains_key(name) => self.resolve(func),
+                    Expr::LocalVar(name) if self.funcs.contains_key(name) || is_builtin(name) => {
+                        let (arity, signature) = match self.funcs.get(name) {
+                            Some(f) => (
+                                f.args.len(),
+                                format!(
+                                    "'{}' is declared as {}({})",
+                                    name,
+                                    name,
+                                    f.args.join(", ")
+                                ),
+                            ),
+                            None => {
+                                let (_, arity) = BUILTINS.iter().find(|(b, _)| b == name).unwrap();
+                                (*arity, format!("'{}' is a builtin", name))
+                            }
+                        };
+                        if arity != args.len() {
+                            self.errs.push(
+                                Diagnostic::new(
+                                    args_span.clone(),
+                                    format!(
+                                        "'{}' called with wrong number of arguments (expected {}, found {})",
+                                        name,
+                                        arity,
+                                        args.len()
+                                    ),
+                                )
+                                .with_label(
+                                    args_span.clone(),
+                                    format!(
+                                        "Expected {} argument{} here",
+                                        arity,
+                                        if arity == 1 { "" } else { "s" }
+                                    ),
+                                )
+                                .with_note(signature),
+                            );
+                        }
+                    }
+                    Expr::LocalVar(name) => {
+                        let candidates = self
+                            .funcs
+                            .keys()
+                            .map(String::as_str)
+                            .chain(BUILTINS.iter().map(|(b, _)| *b))
+                            .collect();
+                        self.undefined(func.1.clone(), "function", name, candidates);
+                    }
+                    _ => self.resolve(func),
+                }
+                args.iter().for_each(|arg| self.resolve(arg));
+            }
+            Expr::Struct((name, name_span), fields) => {
+                match self.structs.get(name) {
+                    Some(decl) => self.check_fields(name, name_span, decl, fields),
+                    None => {
+                        let candidates = self.structs.keys().map(String::as_str).collect();
+                        self.undefined(name_span.clone(), "struct", name, candidates);
+                    }
+                }
+                fields.iter().for_each(|(_, val)| self.resolve(val));
+            }
+            Expr::Variant(name, variant, vals) => {
+                self.check_variant(name, variant, vals.len(), &expr.1);
+                vals.iter().for_each(|val| self.resolve(val));
+            }
+            Expr::Match(val, arms) => {
+                self.resolve(val);
+                let mut valid = true;
+                for (pat, body) in arms {
+                    let mut bound = Vec::new();
+                    valid &= self.resolve_pattern(pat, &mut bound);
+                    self.resolve(body);
+                    self.scope.truncate(self.scope.len() - bound.len());
+                }
+                // Exhaustiveness only makes sense once every pattern names real variants
+                let pats: Vec<&Pattern> = arms.iter().map(|((pat, _), _)| pat).collect();
+                if let Some(missing) = valid.then(|| missing_pattern(self.enums, &pats)).flatten() {
+                    self.errs.push(
+                        Diagnostic::new(
+                            val.1.clone(),
+                            format!("Non-exhaustive match: '{}' is not covered", missing),
+                        )
+                        .with_label(
+                            val.1.clone(),
+                            format!("'{}' is not matched by any arm", missing),
+                        )
+                        .with_note("Add an arm for it, or a '_' arm that matches everything"),
+                    );
+                }
+            }
+            // `break` and `continue` can't leave the closure
+            Expr::Closure(args, body) => {
+                let loops = std::mem::take(&mut self.loops);
+                self.scope.extend(args.iter().map(String::as_str));
+                self.resolve(body);
+                self.scope.truncate(self.scope.len() - args.len());
+                self.loops = loops;
+            }
+            Expr::Loop(cond, body) => {
+                self.resolve(cond);
+                self.loops += 1;
+                self.resolve(body);
+                self.loops -= 1;
+            }
+            Expr::For(name, items, body) => {
+                self.resolve(items);
+                self.scope.push(name);
+                self.loops += 1;
+                self.resolve(body);
+                self.loops -= 1;
+                self.scope.pop();
+            }
+            Expr::Break(_) | Expr::Continue if self.loops == 0 => {
+                let keyword = if let Expr::Break(_) = expr.0 {
+                    "break"
+                } else {
+                    "continue"
+                };
+                self.errs.push(
+                    Diagnostic::new(expr.1.clone(), format!("'{}' outside of a loop", keyword))
+                        .with_label(
+                            expr.1.clone(),
+                            format!("'{}' can only be used inside a loop", keyword),
+                        ),
+                );
+                expr.0
+                    .children()
+                    .into_iter()
+                    .for_each(|child| self.resolve(child));
+            }
+            expr => expr
+                .children()
+                .into_iter()
+                .for_each(|child| self.resolve(child)),
+        }
+    }
+}
+
+/// Edit distance between two names, counting a swap of neighbouring characters as a single edit
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+/// The candidate closest to `name`, if it is close enough to plausibly be a typo
+pub fn closest<'b>(name: &str, candidates: Vec<&'b str>) -> Option<&'b str> {
+    let max = (name.chars().count() / 3).max(1);
+    let mut candidates: Vec<_> = candidates
+        .into_iter()
+        .map(|c| (distance(name, c), c))
+        .filter(|(d, _)| *d <= max)
+        .collect();
+    candidates.sort();
+    candidates.first().map(|(_, c)| *c)
+}
+
+/// Resolves the names of all functions. Returns one error per unknown name or mismatched call, ordered by position.
+pub fn resolve(program: &Program) -> Vec<Diagnostic> {
+    let mut errs = Vec::new();
+    let resolver = |scope, initializer| Resolver {
+        funcs: &program.funcs,
+        structs: &program.structs,
+        enums: &program.enums,
+        globals: &program.globals,
+        scope,
+        initializer,
+        loops: 0,
+        errs: Vec::new(),
+    };
+    for func in program.funcs.values() {
+        let mut resolver = resolver(func.args.iter().map(String::as_str).collect(), false);
+        resolver.resolve(&func.body);
+        errs.append(&mut resolver.errs);
+    }
+    for global in program.globals.values() {
+        let mut resolver = resolver(Vec::new(), true);
+        resolver.resolve(&global.value);
+        errs.append(&mut resolver.errs);
+    }
+    if let Err(cycle) = order(&program.globals) {
+        let span = program.globals[cycle[0]].value.1.clone();
+        let path: Vec<&str> = cycle.iter().map(|name| name.as_str()).collect();
+        errs.push(
+            Diagnostic::new(span.clone(), format!("'{}' depends on itself", cycle[0]))
+                .with_label(span, "This value uses itself")
+                .with_note(format!("The values form a cycle: {}", path.join(" -> "))),
+        );
+    }
+    errs.sort_by_key(|e| e.span.start);
+    errs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules;
+
+    /// The message and note of every error in a program that parses
+    fn errors(src: &str) -> Vec<(String, Option<String>)> {
+        let loaded = modules::load_files(&[("main.grsp", src)]);
+        assert!(loaded.syntax_errs.is_empty(), "{:?}", loaded.syntax_errs);
+        let program = loaded.program.expect("the program loads");
+        resolve(&program)
+            .into_iter()
+            .map(|diag| (diag.msg, diag.note))
+            .collect()
+    }
+
+    fn error(msg: &str, note: Option<&str>) -> (String, Option<String>) {
+        (msg.to_string(), note.map(str::to_string))
+    }
+
+    #[test]
+    fn typos_are_one_edit_per_swap() {
+        assert_eq!(distance("count", "count"), 0);
+        assert_eq!(distance("coutn", "count"), 1);
+        assert_eq!(distance("cont", "count"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(
+            closest("lenght", vec!["len", "length", "width"]),
+            Some("length")
+        );
+        // Short names only allow one edit, so unrelated names aren't suggested
+        assert_eq!(closest("ab", vec!["xy", "cd"]), None);
+        assert_eq!(closest("x", vec!["y", "xs"]), Some("xs"));
+    }
+
+    #[test]
+    fn undefined_names_suggest_the_closest_one() {
+        assert_eq!(
+            errors("fn main() { let count = 1; coutn }"),
+            vec![error(
+                "Undefined variable 'coutn'",
+                Some("Did you mean 'count'?")
+            )]
+        );
+        assert_eq!(
+            errors("fn main() { let count = 1; total }"),
+            vec![error("Undefined variable 'total'", None)]
+        );
+        assert_eq!(
+            errors("fn main() { let count = 1; cuont = 2; count }"),
+            vec![error(
+                "Undefined variable 'cuont'",
+                Some("Did you mean 'count'?")
+            )]
+        );
+        assert_eq!(
+            errors("fn helper() { 1 }\nfn main() { halper() + helpr() }"),
+            vec![
+                error(
+                    "Undefined function 'halper'",
+                    Some("Did you mean 'helper'?")
+                ),
+                error("Undefined function 'helpr'", Some("Did you mean 'helper'?")),
+            ]
+        );
+        assert_eq!(
+            errors("fn main() { lne([]) }"),
+            vec![error(
+                "Undefined function 'lne'",
+                Some("Did you mean 'len'?")
+            )]
+        );
+        // A variable is only in scope after its `let`
+        assert_eq!(
+            errors("fn main() { print(x); let x = 1; x }"),
+            vec![error("Undefined variable 'x'", None)]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_and_variants_suggest_the_closest_one() {
+        assert_eq!(
+            errors("struct P { width, height }\nfn main() { P { width: 1, hieght: 2 } }"),
+            vec![
+                error("Missing fields in struct 'P'", None),
+                error(
+                    "Struct 'P' has no field 'hieght'",
+                    Some("Did you mean 'height'?")
+                ),
+            ]
+        );
+        assert_eq!(
+            errors("enum Shape { Circle(r), Empty }\nfn main() { Shape::Cirlce(1) }"),
+            vec![error(
+                "Enum 'Shape' has no variant 'Cirlce'",
+                Some("Did you mean 'Circle'?")
+            )]
+        );
+    }
+
+    #[test]
+    fn only_builtins_are_not_values() {
+        assert_eq!(
+            errors("fn helper() { 1 }\nfn main() { let f = helper; f() }"),
+            Vec::new()
+        );
+        assert_eq!(
+            errors("fn main() { let f = len; f([1]) }"),
+            vec![error("Builtin 'len' used as a value", None)]
+        );
+    }
+
+    #[test]
+    fn calls_must_match_the_arity() {
+        assert_eq!(
+            errors("fn add(a, b) { a + b }\nfn main() { add(1) + add(1, 2) }"),
+            vec![error(
+                "'add' called with wrong number of arguments (expected 2, found 1)",
+                Some("'add' is declared as add(a, b)")
+            )]
+        );
+        assert_eq!(
+            errors("fn one() { 1 }\nfn main() { one(2) }"),
+            vec![error(
+                "'one' called with wrong number of arguments (expected 0, found 1)",
+                Some("'one' is declared as one()")
+            )]
+        );
+        assert_eq!(
+            errors("fn main() { len([1], [2]) }"),
+            vec![error(
+                "'len' called with wrong number of arguments (expected 1, found 2)",
+                Some("'len' is a builtin")
+            )]
+        );
+        assert_eq!(
+            errors("enum Shape { Rect(w, h), Empty }\nfn main() { Shape::Rect(1); Shape::Empty }"),
+            vec![error(
+                "Variant 'Shape::Rect' has 2 values, found 1",
+                Some("'Shape::Rect' is declared as Rect(w, h)")
+            )]
+        );
+        // Variables holding functions are only checked when they are called
+        assert_eq!(
+            errors("fn add(a, b) { a + b }\nfn main() { let f = add; f(1) }"),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn break_needs_a_loop() {
+        assert_eq!(
+            errors("fn f() { continue }\nfn main() { loop { f(); break } }"),
+            vec![error("'continue' outside of a loop", None)]
+        );
+        assert_eq!(
+            errors("fn main() { if true { break 1 } else { 2 } }"),
+            vec![error("'break' outside of a loop", None)]
+        );
+    }
+}
@@ -1,18 +1,30 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 
-use crate::codegen::{ByteCodeFunction, ByteCodeOp, ByteCodeValue};
+use crate::{
+    codegen::{ByteCodeFunction, ByteCodeIter, ByteCodeOp, ByteCodeValue},
+    parser::{destructure_error, key_error, missing_key_error, BinaryOp, Key, Num, Span},
+};
 
 #[derive(Debug)]
 pub struct Runtime {
     operations: Vec<ByteCodeOp>,
+    spans: Vec<Option<Span>>,
     pc: usize,
     call_stack: Vec<usize>,
     value_stack: Vec<ByteCodeValue>,
     ftxc_stack: Vec<HashMap<usize, ByteCodeValue>>,
+    /// Values of the globals by slot, set at the start of `main`
+    globals: HashMap<usize, ByteCodeValue>,
     label_offsets: HashMap<String, usize>,
+    /// Number of arguments of every function, including the captured values of closures
+    arities: HashMap<String, usize>,
 }
 
 impl std::fmt::Display for Runtime {
@@ -27,10 +39,83 @@ impl std::fmt::Display for Runtime {
     }
 }
 
+/// An error while running the program, located at the source of the failing instruction if it has one
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub span: Option<Span>,
+    pub error: Error,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+/// Checks that a popped value can index a list of the given length
+fn checked_index(index: Option<ByteCodeValue>, len: usize) -> Result<usize, Error> {
+    match index {
+        Some(ByteCodeValue::Int(index)) if (0..len as i64).contains(&index) => Ok(index as usize),
+        Some(ByteCodeValue::Number(index))
+            if index.fract() == 0.0 && index >= 0.0 && (index as usize) < len =>
+        {
+            Ok(index as usize)
+        }
+        Some(index @ (ByteCodeValue::Int(_) | ByteCodeValue::Number(_))) => {
+            bail!("Index {} out of range for list length {}", index, len)
+        }
+        Some(val) => bail!("Type error: list index must be a number, found '{}'", val),
+        None => panic!("RT list index empty stack"),
+    }
+}
+
+/// Checks that a popped value can be the key of a map
+fn checked_key(key: Option<ByteCodeValue>) -> Result<Key, Error> {
+    match key {
+        Some(val) => match val.key() {
+            Some(key) => Ok(key),
+            None => bail!(key_error(val)),
+        },
+        None => panic!("RT map key empty stack"),
+    }
+}
+
+impl Iterator for ByteCodeIter {
+    type Item = ByteCodeValue;
+
+    fn next(&mut self) -> Option<ByteCodeValue> {
+        match self {
+            ByteCodeIter::List(items, i) => {
+                let item = items.borrow().get(*i).cloned();
+                *i += 1;
+                item
+            }
+            ByteCodeIter::Range {
+                next,
+                end,
+                inclusive,
+            } => {
+                if *next < *end || (*inclusive && *next == *end) {
+                    let item = *next;
+                    *next = item.step();
+                    Some(item.into())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
 impl Runtime {
     pub fn new(function_list: Vec<ByteCodeFunction>) -> Self {
         let mut label_offsets = HashMap::new();
         let mut pc = 0;
+        let spans = function_list
+            .iter()
+            .flat_map(|function| &function.ops)
+            .map(|op| op.span.clone())
+            .collect();
         let operations = function_list
             .iter()
             .flat_map(|function| &function.ops)
@@ -45,13 +130,20 @@ impl Runtime {
                 op.bytecode_op.clone()
             })
             .collect();
+        let arities = function_list
+            .iter()
+            .map(|function| (function.name.clone(), function.arg_ct))
+            .collect();
         Runtime {
             operations,
+            spans,
             pc,
             call_stack: Vec::new(),
             value_stack: Vec::new(),
             ftxc_stack: vec![HashMap::new()],
+            globals: HashMap::new(),
             label_offsets,
+            arities,
         }
     }
 
@@ -60,7 +152,86 @@ impl Runtime {
         self.value_stack.push(val);
     }
 
-    pub fn execute_program(&mut self) -> Result<usize, Error> {
+    /// Pops an operand of an arithmetic op or a comparison, the type checker made sure it is a number
+    fn pop_num(&mut self, op: &str) -> Num {
+        match self.value_stack.pop().as_ref().and_then(ByteCodeValue::num) {
+            Some(num) => num,
+            None => panic!("RT {} received non number", op),
+        }
+    }
+
+    /// Pops both operands of an arithmetic op and applies it
+    fn arithmetic(&mut self, op: BinaryOp, name: &str) -> Result<(), Error> {
+        let a = self.pop_num(name);
+        let b = self.pop_num(name);
+        let res = b.arithmetic(&op, a).map_err(Error::msg)?;
+        self.push_next(res.into());
+        Ok(())
+    }
+
+    /// Pops an operand of a bitwise op. Integers and the floats that are whole and fit into an `i64` are accepted.
+    fn pop_int(&mut self, op: &str) -> Result<i64, Error> {
+        match self.value_stack.pop() {
+            Some(ByteCodeValue::Int(n)) => Ok(n),
+            Some(ByteCodeValue::Number(x)) if x.fract() == 0.0 && x.abs() < i64::MAX as f64 => {
+                Ok(x as i64)
+            }
+            Some(val) => bail!("Type error: {} expects integers, found '{}'", op, val),
+            None => panic!("RT {} empty stack", op),
+        }
+    }
+
+    fn pop_list(&mut self, op: &str) -> Result<Rc<RefCell<Vec<ByteCodeValue>>>, Error> {
+        match self.value_stack.pop() {
+            Some(ByteCodeValue::List(items)) => Ok(items),
+            Some(val) => bail!("Type error: {} expects a list, found '{}'", op, val),
+            None => panic!("RT {} empty stack", op),
+        }
+    }
+
+    fn pop_map(&mut self, op: &str) -> Result<Rc<RefCell<BTreeMap<Key, ByteCodeValue>>>, Error> {
+        match self.value_stack.pop() {
+            Some(ByteCodeValue::Map(entries)) => Ok(entries),
+            Some(val) => bail!("Type error: {} expects a map, found '{}'", op, val),
+            None => panic!("RT {} empty stack", op),
+        }
+    }
+
+    /// Pops a struct, checking that it has the field that is accessed
+    fn pop_struct(
+        &mut self,
+        field: &str,
+    ) -> Result<Rc<RefCell<BTreeMap<String, ByteCodeValue>>>, Error> {
+        match self.value_stack.pop() {
+            Some(ByteCodeValue::Struct(name, fields)) => {
+                if !fields.borrow().contains_key(field) {
+                    bail!("Struct '{}' has no field '{}'", name, field);
+                }
+                Ok(fields)
+            }
+            Some(val) => bail!("Type error: field access expects a struct, found '{}'", val),
+            None => panic!("RT field access empty stack"),
+        }
+    }
+
+    /// Pops a variant, giving its tag and payload
+    fn pop_variant(&mut self, op: &str) -> Result<(usize, Vec<ByteCodeValue>), Error> {
+        match self.value_stack.pop() {
+            Some(ByteCodeValue::Variant { tag, vals, .. }) => Ok((tag, vals)),
+            Some(val) => bail!("Type error: {} expects an enum value, found '{}'", op, val),
+            None => panic!("RT {} empty stack", op),
+        }
+    }
+
+    pub fn execute_program(&mut self) -> Result<usize, RuntimeError> {
+        // Failing instructions don't advance the pc, so it still points at them
+        self.run().map_err(|error| RuntimeError {
+            span: self.spans[self.pc].clone(),
+            error,
+        })
+    }
+
+    fn run(&mut self) -> Result<usize, Error> {
         while self.operations[self.pc] != ByteCodeOp::End {
             // println!("{}", self);
             // println!("Call {:?}", self.call_stack);
@@ -88,64 +259,281 @@ impl Runtime {
                     self.ftxc_stack.last_mut().unwrap().insert(*index, value);
                     self.pc += 1;
                 }
+                ByteCodeOp::MakeCell => {
+                    let Some(val) = self.value_stack.pop() else {
+                        panic!("RT MakeCell empty stack");
+                    };
+                    self.push_next(ByteCodeValue::Cell(Rc::new(RefCell::new(val))))
+                }
+                ByteCodeOp::CellGet(index) => {
+                    let Some(ByteCodeValue::Cell(cell)) =
+                        self.ftxc_stack.last().unwrap().get(index)
+                    else {
+                        panic!("RT CellGet variable not in a cell");
+                    };
+                    let val = cell.borrow().clone();
+                    self.push_next(val);
+                }
+                ByteCodeOp::CellSet(index) => {
+                    let Some(value) = self.value_stack.pop() else {
+                        panic!("RT CellSet empty stack");
+                    };
+                    let Some(ByteCodeValue::Cell(cell)) =
+                        self.ftxc_stack.last().unwrap().get(index)
+                    else {
+                        panic!("RT CellSet variable not in a cell");
+                    };
+                    *cell.borrow_mut() = value;
+                    self.pc += 1;
+                }
+                ByteCodeOp::GlobalGet(slot) => {
+                    let Some(val) = self.globals.get(slot) else {
+                        panic!("RT GlobalGet global not set");
+                    };
+                    self.push_next(val.clone());
+                }
+                ByteCodeOp::GlobalSet(slot) => {
+                    let Some(value) = self.value_stack.pop() else {
+                        panic!("RT Global Set empty stack");
+                    };
+                    self.globals.insert(*slot, value);
+                    self.pc += 1;
+                }
                 ByteCodeOp::Const(val) => {
                     self.push_next(val.clone());
                 }
-                ByteCodeOp::Add => {
-                    let Some(ByteCodeValue::Number(a)) = self.value_stack.pop() else {
-                        panic!("RT Add received non number");
+                ByteCodeOp::Add => self.arithmetic(BinaryOp::Add, "Add")?,
+                ByteCodeOp::Sub => self.arithmetic(BinaryOp::Sub, "Sub")?,
+                ByteCodeOp::Div => self.arithmetic(BinaryOp::Div, "Div")?,
+                ByteCodeOp::Mod => self.arithmetic(BinaryOp::Mod, "Mod")?,
+                ByteCodeOp::Neg => {
+                    let a = self.pop_num("Neg").checked_neg().map_err(Error::msg)?;
+                    self.push_next(a.into())
+                }
+                ByteCodeOp::BitAnd => {
+                    let a = self.pop_int("BitAnd")?;
+                    let b = self.pop_int("BitAnd")?;
+                    self.push_next(ByteCodeValue::Int(b & a))
+                }
+                ByteCodeOp::BitOr => {
+                    let a = self.pop_int("BitOr")?;
+                    let b = self.pop_int("BitOr")?;
+                    self.push_next(ByteCodeValue::Int(b | a))
+                }
+                ByteCodeOp::BitXor => {
+                    let a = self.pop_int("BitXor")?;
+                    let b = self.pop_int("BitXor")?;
+                    self.push_next(ByteCodeValue::Int(b ^ a))
+                }
+                ByteCodeOp::BitNot => {
+                    let a = self.pop_int("BitNot")?;
+                    self.push_next(ByteCodeValue::Int(!a))
+                }
+                ByteCodeOp::Shl | ByteCodeOp::Shr => {
+                    let shl = self.operations[self.pc] == ByteCodeOp::Shl;
+                    let a = self.pop_int("Shift")?;
+                    let b = self.pop_int("Shift")?;
+                    if !(0..64).contains(&a) {
+                        bail!("Shift amount {} is out of range 0..64", a);
+                    }
+                    let res = if shl { b << a } else { b >> a };
+                    self.push_next(ByteCodeValue::Int(res))
+                }
+                ByteCodeOp::Mul => self.arithmetic(BinaryOp::Mul, "Mul")?,
+                ByteCodeOp::ListAt => {
+                    let index = self.value_stack.pop();
+                    let item = match self.value_stack.pop() {
+                        Some(ByteCodeValue::List(items)) => {
+                            let items = items.borrow();
+                            items[checked_index(index, items.len())?].clone()
+                        }
+                        Some(ByteCodeValue::Map(entries)) => {
+                            let key = checked_key(index)?;
+                            let val = entries.borrow().get(&key).cloned();
+                            let Some(val) = val else {
+                                bail!(missing_key_error(&key));
+                            };
+                            val
+                        }
+                        Some(val) => bail!("Type error: @ expects a list or map, found '{}'", val),
+                        None => panic!("RT @ empty stack"),
                     };
-                    let Some(ByteCodeValue::Number(b)) = self.value_stack.pop() else {
-                        panic!("RT Add received non number");
+                    self.push_next(item)
+                }
+                ByteCodeOp::ListSet => {
+                    let Some(val) = self.value_stack.pop() else {
+                        panic!("RT ListSet empty stack");
                     };
-                    self.push_next(ByteCodeValue::Number(a + b))
+                    let index = self.value_stack.pop();
+                    match self.value_stack.pop() {
+                        Some(ByteCodeValue::List(items)) => {
+                            let mut items = items.borrow_mut();
+                            let index = checked_index(index, items.len())?;
+                            items[index] = val;
+                        }
+                        Some(ByteCodeValue::Map(entries)) => {
+                            let key = checked_key(index)?;
+                            entries.borrow_mut().insert(key, val);
+                        }
+                        Some(val) => bail!(
+                            "Type error: Index assignment expects a list or map, found '{}'",
+                            val
+                        ),
+                        None => panic!("RT ListSet empty stack"),
+                    }
+                    self.push_next(ByteCodeValue::Null)
+                }
+                ByteCodeOp::MakeList(len) => {
+                    let items = self.value_stack.split_off(self.value_stack.len() - len);
+                    self.push_next(ByteCodeValue::List(Rc::new(RefCell::new(items))))
                 }
-                ByteCodeOp::Sub => {
-                    let Some(ByteCodeValue::Number(a)) = self.value_stack.pop() else {
-                        panic!("RT Sub received non number");
+                ByteCodeOp::MakeTuple(len) => {
+                    let items = self.value_stack.split_off(self.value_stack.len() - len);
+                    self.push_next(ByteCodeValue::Tuple(items))
+                }
+                ByteCodeOp::MakeMap(len) => {
+                    let vals = self.value_stack.split_off(self.value_stack.len() - 2 * len);
+                    let mut entries = BTreeMap::new();
+                    for pair in vals.chunks(2) {
+                        entries.insert(checked_key(Some(pair[0].clone()))?, pair[1].clone());
+                    }
+                    self.push_next(ByteCodeValue::Map(Rc::new(RefCell::new(entries))))
+                }
+                ByteCodeOp::UnpackTuple(len) => match self.value_stack.pop() {
+                    Some(ByteCodeValue::Tuple(items)) if items.len() == *len => {
+                        self.value_stack.extend(items);
+                        self.pc += 1;
+                    }
+                    Some(ByteCodeValue::Tuple(items)) => {
+                        bail!(destructure_error("", Some(items.len()), *len))
+                    }
+                    Some(val) => bail!(destructure_error(val, None, *len)),
+                    None => panic!("RT UnpackTuple empty stack"),
+                },
+                ByteCodeOp::MakeStruct(name, fields) => {
+                    let vals = self
+                        .value_stack
+                        .split_off(self.value_stack.len() - fields.len());
+                    let fields: BTreeMap<_, _> = fields.iter().cloned().zip(vals).collect();
+                    self.push_next(ByteCodeValue::Struct(
+                        name.clone(),
+                        Rc::new(RefCell::new(fields)),
+                    ))
+                }
+                ByteCodeOp::GetField(field) => {
+                    let field = field.clone();
+                    let fields = self.pop_struct(&field)?;
+                    let val = fields.borrow()[&field].clone();
+                    self.push_next(val)
+                }
+                ByteCodeOp::SetField(field) => {
+                    let field = field.clone();
+                    let Some(val) = self.value_stack.pop() else {
+                        panic!("RT SetField empty stack");
                     };
-                    let Some(ByteCodeValue::Number(b)) = self.value_stack.pop() else {
-                        panic!("RT Sub received non number");
+                    self.pop_struct(&field)?.borrow_mut().insert(field, val);
+                    self.push_next(ByteCodeValue::Null)
+                }
+                ByteCodeOp::MakeVariant {
+                    name,
+                    variant,
+                    tag,
+                    len,
+                } => {
+                    let vals = self.value_stack.split_off(self.value_stack.len() - len);
+                    let val = ByteCodeValue::Variant {
+                        name: name.clone(),
+                        variant: variant.clone(),
+                        tag: *tag,
+                        vals,
                     };
-                    self.push_next(ByteCodeValue::Number(b - a))
+                    self.push_next(val)
+                }
+                ByteCodeOp::IsVariant(expected) => {
+                    let expected = *expected;
+                    let (tag, _) = self.pop_variant("match")?;
+                    self.push_next(ByteCodeValue::Boolean(tag == expected))
+                }
+                ByteCodeOp::VariantField(i) => {
+                    let i = *i;
+                    let (_, mut vals) = self.pop_variant("match")?;
+                    self.push_next(vals.swap_remove(i))
                 }
-                ByteCodeOp::Div => {
-                    let Some(ByteCodeValue::Number(a)) = self.value_stack.pop() else {
-                        panic!("RT Div received non number");
+                ByteCodeOp::JumpTable(labels) => {
+                    let label = {
+                        let labels = labels.clone();
+                        let (tag, _) = self.pop_variant("match")?;
+                        labels[tag].clone()
                     };
-                    let Some(ByteCodeValue::Number(b)) = self.value_stack.pop() else {
-                        panic!("RT Div received non number");
+                    self.pc = self.label_offsets[&label];
+                }
+                ByteCodeOp::ListLen => {
+                    let len = self.pop_list("len")?.borrow().len();
+                    self.push_next(ByteCodeValue::Int(len as i64))
+                }
+                ByteCodeOp::ListPush => {
+                    let Some(item) = self.value_stack.pop() else {
+                        panic!("RT ListPush empty stack");
                     };
-                    self.push_next(ByteCodeValue::Number(a / b))
+                    self.pop_list("push")?.borrow_mut().push(item);
+                    self.push_next(ByteCodeValue::Null)
                 }
-                ByteCodeOp::Mul => {
-                    let Some(ByteCodeValue::Number(a)) = self.value_stack.pop() else {
-                        panic!("RT Mul received non number");
+                ByteCodeOp::ListPop => {
+                    let Some(item) = self.pop_list("pop")?.borrow_mut().pop() else {
+                        bail!("Cannot pop from an empty list");
                     };
-                    let Some(ByteCodeValue::Number(b)) = self.value_stack.pop() else {
-                        panic!("RT Mul received non number");
+                    self.push_next(item)
+                }
+                ByteCodeOp::MapKeys => {
+                    let keys = self
+                        .pop_map("keys")?
+                        .borrow()
+                        .keys()
+                        .map(Into::into)
+                        .collect();
+                    self.push_next(ByteCodeValue::List(Rc::new(RefCell::new(keys))))
+                }
+                ByteCodeOp::MapContains => {
+                    let key = checked_key(self.value_stack.pop())?;
+                    let found = self.pop_map("contains")?.borrow().contains_key(&key);
+                    self.push_next(ByteCodeValue::Boolean(found))
+                }
+                ByteCodeOp::MapRemove => {
+                    let key = checked_key(self.value_stack.pop())?;
+                    let Some(val) = self.pop_map("remove")?.borrow_mut().remove(&key) else {
+                        bail!(missing_key_error(&key));
                     };
-                    self.push_next(ByteCodeValue::Number(a * b));
+                    self.push_next(val)
                 }
-                ByteCodeOp::ListAt => panic!("Listen machma spaeter"),
-                ByteCodeOp::LowerT => {
-                    let Some(ByteCodeValue::Number(a)) = self.value_stack.pop() else {
-                        panic!("RT LowerT received non number");
+                ByteCodeOp::Concat => {
+                    let Some(a) = self.value_stack.pop() else {
+                        panic!("RT Concat empty stack");
                     };
-                    let Some(ByteCodeValue::Number(b)) = self.value_stack.pop() else {
-                        panic!("RT LowerT received non number");
+                    let Some(b) = self.value_stack.pop() else {
+                        panic!("RT Concat empty stack");
                     };
+                    self.push_next(ByteCodeValue::String(format!("{}{}", b, a)))
+                }
+                ByteCodeOp::LowerT => {
+                    let a = self.pop_num("LowerT");
+                    let b = self.pop_num("LowerT");
                     self.push_next(ByteCodeValue::Boolean(b < a))
                 }
                 ByteCodeOp::GreaterT => {
-                    let Some(ByteCodeValue::Number(a)) = self.value_stack.pop() else {
-                        panic!("RT GreaterT received non number");
-                    };
-                    let Some(ByteCodeValue::Number(b)) = self.value_stack.pop() else {
-                        panic!("RT GreaterT received non number");
-                    };
+                    let a = self.pop_num("GreaterT");
+                    let b = self.pop_num("GreaterT");
                     self.push_next(ByteCodeValue::Boolean(b > a))
                 }
+                ByteCodeOp::LowerEq => {
+                    let a = self.pop_num("LowerEq");
+                    let b = self.pop_num("LowerEq");
+                    self.push_next(ByteCodeValue::Boolean(b <= a))
+                }
+                ByteCodeOp::GreaterEq => {
+                    let a = self.pop_num("GreaterEq");
+                    let b = self.pop_num("GreaterEq");
+                    self.push_next(ByteCodeValue::Boolean(b >= a))
+                }
                 ByteCodeOp::Equal => {
                     let Some(a) = self.value_stack.pop() else {
                         panic!("RT Equal received non number");
@@ -164,6 +552,12 @@ impl Runtime {
                     };
                     self.push_next(ByteCodeValue::Boolean(a != b))
                 }
+                ByteCodeOp::Not => {
+                    let Some(ByteCodeValue::Boolean(a)) = self.value_stack.pop() else {
+                        panic!("RT Not received non boolean");
+                    };
+                    self.push_next(ByteCodeValue::Boolean(!a))
+                }
                 ByteCodeOp::Call(funcname, argc) => {
                     let mut new_fctx = HashMap::new();
                     for i in (0..*argc).rev() {
@@ -177,9 +571,38 @@ impl Runtime {
                     self.call_stack.push(self.pc + 1);
                     self.pc = *self.label_offsets.get(funcname).unwrap();
                 }
+                // Closures get their captured values as the first arguments
+                ByteCodeOp::CallIndirect(argc) => {
+                    let argc = *argc;
+                    let args = self.value_stack.split_off(self.value_stack.len() - argc);
+                    let (funcname, captures) = match self.value_stack.pop() {
+                        Some(ByteCodeValue::Func(name)) => (name, Vec::new()),
+                        Some(ByteCodeValue::Closure(name, captures)) => (name, captures.to_vec()),
+                        Some(val) => bail!("Type error: '{}' is not callable", val),
+                        None => panic!("RT CallIndirect empty stack"),
+                    };
+                    let expected = self.arities[&funcname] - captures.len();
+                    if expected != argc {
+                        bail!(
+                            "Function called with wrong number of arguments (expected {}, found {})",
+                            expected,
+                            argc
+                        );
+                    }
+                    let new_fctx = captures.into_iter().chain(args).enumerate().collect();
+                    self.value_stack.push(ByteCodeValue::Return);
+                    self.ftxc_stack.push(new_fctx);
+                    self.call_stack.push(self.pc + 1);
+                    self.pc = self.label_offsets[&funcname];
+                }
+                ByteCodeOp::MakeClosure(name, len) => {
+                    let captures = self.value_stack.split_off(self.value_stack.len() - len);
+                    self.push_next(ByteCodeValue::Closure(name.clone(), Rc::new(captures)))
+                }
+                // `print` evaluates to the value it printed, so it stays on the stack
                 ByteCodeOp::Print => {
-                    let Some(value) = self.value_stack.pop() else {
-                        panic!("RT Add received non number");
+                    let Some(value) = self.value_stack.last() else {
+                        panic!("RT Print empty stack");
                     };
                     println!("{}", value);
                     self.pc += 1;
@@ -203,6 +626,72 @@ impl Runtime {
                 }
                 ByteCodeOp::End => break,
                 ByteCodeOp::Jump(label) => self.pc = *self.label_offsets.get(label).unwrap(),
+                ByteCodeOp::SaveStack(slot) => {
+                    let height = ByteCodeValue::Int(self.value_stack.len() as i64);
+                    self.ftxc_stack.last_mut().unwrap().insert(*slot, height);
+                    self.pc += 1;
+                }
+                ByteCodeOp::Truncate(slot, keep) => {
+                    let Some(ByteCodeValue::Int(height)) =
+                        self.ftxc_stack.last().unwrap().get(slot)
+                    else {
+                        panic!("RT Truncate stack height not saved");
+                    };
+                    let height = *height as usize;
+                    let kept = self.value_stack.split_off(self.value_stack.len() - keep);
+                    self.value_stack.truncate(height);
+                    self.value_stack.extend(kept);
+                    self.pc += 1;
+                }
+                ByteCodeOp::IterList(slot) => {
+                    let iter = match self.value_stack.pop() {
+                        Some(ByteCodeValue::List(items)) => ByteCodeIter::List(items, 0),
+                        // Maps are iterated over the keys they have when the loop starts
+                        Some(ByteCodeValue::Map(entries)) => {
+                            let keys = entries.borrow().keys().map(Into::into).collect();
+                            ByteCodeIter::List(Rc::new(RefCell::new(keys)), 0)
+                        }
+                        Some(val) => {
+                            bail!("Type error: for expects a list or map, found '{}'", val)
+                        }
+                        None => panic!("RT IterList empty stack"),
+                    };
+                    let locals = self.ftxc_stack.last_mut().unwrap();
+                    locals.insert(*slot, ByteCodeValue::Iter(iter));
+                    self.pc += 1;
+                }
+                ByteCodeOp::IterRange(slot, inclusive) => {
+                    let end = self.value_stack.pop();
+                    let start = self.value_stack.pop();
+                    let (Some(next), Some(end)) = (
+                        start.as_ref().and_then(ByteCodeValue::num),
+                        end.as_ref().and_then(ByteCodeValue::num),
+                    ) else {
+                        bail!(
+                            "Type error: range bounds must be numbers, found '{}' and '{}'",
+                            start.unwrap_or(ByteCodeValue::Null),
+                            end.unwrap_or(ByteCodeValue::Null)
+                        );
+                    };
+                    let iter = ByteCodeIter::Range {
+                        next,
+                        end,
+                        inclusive: *inclusive,
+                    };
+                    let locals = self.ftxc_stack.last_mut().unwrap();
+                    locals.insert(*slot, ByteCodeValue::Iter(iter));
+                    self.pc += 1;
+                }
+                ByteCodeOp::IterNext(slot, label) => {
+                    let locals = self.ftxc_stack.last_mut().unwrap();
+                    let Some(ByteCodeValue::Iter(iter)) = locals.get_mut(slot) else {
+                        panic!("RT IterNext no iterator in slot {}", slot);
+                    };
+                    match iter.next() {
+                        Some(item) => self.push_next(item),
+                        None => self.pc = *self.label_offsets.get(label).unwrap(),
+                    }
+                }
                 ByteCodeOp::Pop => {
                     self.value_stack.pop();
                     self.pc += 1;
@@ -212,3 +701,499 @@ impl Runtime {
         Ok(0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Generator, globals, modules, resolve, typecheck};
+
+    /// Compiles and runs the program, giving what `main` returned or the runtime error
+    fn run(src: &str) -> Result<String, String> {
+        let loaded = modules::load_files(&[("main.grsp", src)]);
+        assert!(loaded.syntax_errs.is_empty(), "{:?}", loaded.syntax_errs);
+        let program = loaded.program.expect("the program loads");
+        let errs = resolve::resolve(&program);
+        assert!(errs.is_empty(), "{:?}", errs);
+        let errs = typecheck::check(&program);
+        assert!(errs.is_empty(), "{:?}", errs);
+        let values = globals::evaluate(&program).expect("the globals evaluate");
+        let bytecode = Generator::new(program, values).generate_bytecod().unwrap();
+        let mut runtime = Runtime::new(bytecode);
+        runtime.execute_program().map_err(|e| e.to_string())?;
+        // Nothing may be left over besides the result
+        assert_eq!(runtime.value_stack.len(), 1, "{:?}", runtime.value_stack);
+        Ok(runtime.value_stack[0].to_string())
+    }
+
+    #[test]
+    fn interpolations_are_concatenated() {
+        assert_eq!(
+            run(r#"fn main() { let x = 2; "x + 1 = {x + 1}, \{x\} {"a"}b" }"#).unwrap(),
+            "x + 1 = 3, {x} ab"
+        );
+    }
+    #[test]
+    fn comparisons() {
+        for (src, expected) in [
+            ("1 <= 1", "true"),
+            ("3 <= 2", "false"),
+            ("2 >= 3", "false"),
+            ("2 >= 2", "true"),
+            ("1 < 2", "true"),
+            ("2 > 2", "false"),
+            ("1 + 1 <= 2 == 3 >= 4", "false"),
+        ] {
+            let main = format!("fn main() {{ {} }}", src);
+            assert_eq!(run(&main).unwrap(), expected, "{}", src);
+        }
+    }
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert_eq!(
+            run("fn main() {\n\
+                 let x = 0;\n\
+                 let a = false && (x = x + 1; true);\n\
+                 let b = true || (x = x + 10; true);\n\
+                 let c = true && (x = x + 100; true);\n\
+                 let d = false || !(x = x + 1000; true);\n\
+                 if a || !b || !c || d { 0 } else { x }\n\
+             }")
+            .unwrap(),
+            "1100"
+        );
+    }
+
+    #[test]
+    fn division_divides_left_by_right() {
+        assert_eq!(run("fn main() { 7 / 2 }").unwrap(), "3.5");
+        assert_eq!(run("fn main() { 2 / 8 - 1 }").unwrap(), "-0.75");
+        assert_eq!(run("fn main() { 7 % 2 }").unwrap(), "1");
+    }
+
+    #[test]
+    fn integers_stay_exact() {
+        for (src, expected) in [
+            ("9007199254740993 + 2", "9007199254740995"),
+            ("-9223372036854775807 - 1", "-9223372036854775808"),
+            ("7 * 3 % 4", "1"),
+            ("1 + 0.5", "1.5"),
+            ("6 / 3 == 2", "true"),
+            ("9007199254740993 == 9007199254740992.0", "false"),
+            ("9007199254740993 > 9007199254740992.0", "true"),
+            ("let m = {1: \"a\"}; m[1.0]", "a"),
+        ] {
+            let main = format!("fn main() {{ {} }}", src);
+            assert_eq!(run(&main).unwrap(), expected, "{}", src);
+        }
+        assert_eq!(
+            run("fn main() { 9223372036854775807 + 1 }").unwrap_err(),
+            "Integer overflow: 9223372036854775807 + 1"
+        );
+        assert_eq!(
+            run("fn main() { let x = -9223372036854775807 - 1; -x }").unwrap_err(),
+            "Integer overflow: -(-9223372036854775808)"
+        );
+    }
+
+    #[test]
+    fn negation_and_modulo() {
+        for (src, expected) in [
+            ("let x = 3; -x + 1", "-2"),
+            ("let x = 3; --x", "3"),
+            ("7 % 3", "1"),
+            ("-7 % 3", "-1"),
+            ("7.5 % 2", "1.5"),
+            ("2 * -3 % 4", "-2"),
+        ] {
+            let main = format!("fn main() {{ {} }}", src);
+            assert_eq!(run(&main).unwrap(), expected, "{}", src);
+        }
+        assert_eq!(
+            run("fn main() { let x = 0; 1 % x }").unwrap_err(),
+            "Modulo by zero: 1 % 0"
+        );
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        for (src, expected) in [
+            ("6 & 3", "2"),
+            ("6 | 3", "7"),
+            ("6 ^ 3", "5"),
+            ("~0", "-1"),
+            ("1 << 4", "16"),
+            ("-16 >> 2", "-4"),
+            ("0xFF & ~0x0F", "240"),
+            // All 64 bits survive, not just the 53 a float can hold
+            ("1 << 60 | 1", "1152921504606846977"),
+            ("~0x7FFF_FFFF_FFFF_FFFF", "-9223372036854775808"),
+            ("0xFFFF_FFFF_FFFF_FFFF >> 60", "-1"),
+            ("(1 << 62 | 3) ^ (1 << 62)", "3"),
+        ] {
+            let main = format!("fn main() {{ {} }}", src);
+            assert_eq!(run(&main).unwrap(), expected, "{}", src);
+        }
+        assert_eq!(
+            run("fn main() { 1.5 & 1 }").unwrap_err(),
+            "Type error: BitAnd expects integers, found '1.5'"
+        );
+        assert_eq!(
+            run("fn main() { let n = 64; 1 << n }").unwrap_err(),
+            "Shift amount 64 is out of range 0..64"
+        );
+    }
+
+    #[test]
+    fn break_and_continue() {
+        assert_eq!(
+            run("fn main() {\n\
+                 let i = 0;\n\
+                 let odd = 0;\n\
+                 let r = (loop {\n\
+                     i = i + 1; i;\n\
+                     if i > 6 { break i * 10 };\n\
+                     if i % 2 == 0 { continue };\n\
+                     odd = odd + i; odd\n\
+                 });\n\
+                 r + odd\n\
+             }")
+            .unwrap(),
+            "79"
+        );
+        assert_eq!(
+            run("fn main() { let i = 0; loop i < 3 { i = i + 1; i }; i }").unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn break_and_continue_drop_unfinished_values() {
+        assert_eq!(
+            run("fn main() { 2 * (loop { 1 + (break 5) }) }").unwrap(),
+            "10"
+        );
+        assert_eq!(
+            run("fn main() {\n\
+                 let i = 0;\n\
+                 let total = 0;\n\
+                 loop i < 5 { i = i + 1; total = total + (if i == 2 { continue } else { i }); total };\n\
+                 total\n\
+             }")
+            .unwrap(),
+            "13"
+        );
+        assert_eq!(
+            run("fn main() {\n\
+                 let i = 0;\n\
+                 let r = (loop { i = i + 1; i; 1 + (if i == 3 { break i * 10 } else { 0 }); });\n\
+                 r + 1 + 2\n\
+             }")
+            .unwrap(),
+            "33"
+        );
+        // Breaking out of the inner loop leaves the values of the outer one alone
+        assert_eq!(
+            run("fn main() {\n\
+                 3 + (loop { let j = 0; loop { j = j + 1; 10 + (break 7) }; 1 + (break 9) })\n\
+             }")
+            .unwrap(),
+            "12"
+        );
+    }
+
+    #[test]
+    fn for_loops() {
+        assert_eq!(
+            run("fn main() {\n\
+                 let out = 0;\n\
+                 for i in 0..3 { out = out * 10 + i; out };\n\
+                 for i in 5..=6 { out = out * 10 + i; out };\n\
+                 for i in 3..1 { out = out * 10 + i; out };\n\
+                 out\n\
+             }")
+            .unwrap(),
+            "1256"
+        );
+        assert_eq!(
+            run("fn main() {\n\
+                 let sum = 0;\n\
+                 for i in 0..3 { for j in 0..i { sum = sum + 10 * i + j; sum } };\n\
+                 sum\n\
+             }")
+            .unwrap(),
+            "51"
+        );
+        assert_eq!(
+            run("fn main() { let n = 0; for i in 0..10 { n = i; if i == 4 { break } }; n }")
+                .unwrap(),
+            "4"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            run("fn main() { let xs = [1, 2]; push(xs, 3); let last = pop(xs); [len(xs), last] }")
+                .unwrap(),
+            "[2, 3]"
+        );
+        // Lists are shared, not copied
+        assert_eq!(
+            run("fn main() { let a = [1]; let b = a; push(b, 2); a }").unwrap(),
+            "[1, 2]"
+        );
+        assert_eq!(
+            run("fn main() { [[1], [], [2, 3]] }").unwrap(),
+            "[[1], [], [2, 3]]"
+        );
+        assert_eq!(run("fn main() { [4, 5, 6] @ 1 }").unwrap(), "5");
+        assert_eq!(
+            run("fn main() { [4, 5, 6] @ 3 }").unwrap_err(),
+            "Index 3 out of range for list length 3"
+        );
+        assert_eq!(
+            run("fn main() { let xs = []; pop(xs) + 1 }").unwrap_err(),
+            "Cannot pop from an empty list"
+        );
+    }
+
+    #[test]
+    fn for_loops_over_lists() {
+        assert_eq!(
+            run("fn main() { let out = []; for x in [3, 1, 2] { push(out, x * 2) }; out }")
+                .unwrap(),
+            "[6, 2, 4]"
+        );
+        // Items pushed while iterating are visited too, the list is shared
+        assert_eq!(
+            run("fn main() {\n\
+                 let xs = [1];\n\
+                 for x in xs { if x < 4 { push(xs, x + 1) } };\n\
+                 xs\n\
+             }")
+            .unwrap(),
+            "[1, 2, 3, 4]"
+        );
+    }
+
+    #[test]
+    fn indexing() {
+        assert_eq!(
+            run("fn main() { let xs = [10, 20, 30]; xs[1] = xs[0] + xs[2]; xs }").unwrap(),
+            "[10, 40, 30]"
+        );
+        assert_eq!(
+            run("fn main() { let m = [[1, 2], [3, 4]]; m[1][0] = 9; m[1][0] + m[0][1] }").unwrap(),
+            "11"
+        );
+        assert_eq!(run("fn main() { [5, 6] @ 1 }").unwrap(), "6");
+        assert_eq!(
+            run("fn main() { let xs = [1]; xs[3] }").unwrap_err(),
+            "Index 3 out of range for list length 1"
+        );
+        assert_eq!(
+            run("fn main() { let xs = [1]; xs[-1] = 2; xs }").unwrap_err(),
+            "Index -1 out of range for list length 1"
+        );
+        assert_eq!(
+            run("fn main() { let xs = [1]; xs[0.5] }").unwrap_err(),
+            "Index 0.5 out of range for list length 1"
+        );
+    }
+
+    #[test]
+    fn structs() {
+        assert_eq!(
+            run("struct P { x, y }\nfn main() { let p = P { y: 2, x: 1 }; p.x = p.x + p.y; p }")
+                .unwrap(),
+            "P { x: 3, y: 2 }"
+        );
+        // Structs are shared like lists
+        assert_eq!(
+            run("struct P { x }\nfn bump(p) { p.x = p.x + 1; p.x }\nfn main() { let p = P { x: 1 }; bump(p); bump(p); p.x }")
+                .unwrap(),
+            "3"
+        );
+        assert_eq!(
+            run("struct Line { a, b }\nstruct P { x }\nfn main() { let l = Line { a: P { x: 1 }, b: P { x: 2 } }; l.b.x = 5; l.a.x + l.b.x }")
+                .unwrap(),
+            "6"
+        );
+    }
+
+    #[test]
+    fn enums_and_match() {
+        let src = "enum Shape { Circle(r), Rect(w, h), Empty }\n\
+                   enum Boxed { Full(s), Nothing }\n\
+                   fn area(s) { match s { Shape::Circle(r) => 3 * r * r, Shape::Rect(w, h) => w * h, Shape::Empty => 0 } }\n\
+                   fn describe(b) {\n\
+                       match b {\n\
+                           Boxed::Full(Shape::Rect(w, 1)) => \"thin {w}\",\n\
+                           Boxed::Full(Shape::Circle(_)) => \"circle\",\n\
+                           Boxed::Full(other) => \"area {area(other)}\",\n\
+                           Boxed::Nothing => \"nothing\",\n\
+                       }\n\
+                   }\n\
+                   fn name(n) { match n { 0 => \"zero\", -1 => \"minus one\", _ => \"many\" } }\n\
+                   fn main() {\n\
+                       [\n\
+                           describe(Boxed::Full(Shape::Rect(4, 1))),\n\
+                           describe(Boxed::Full(Shape::Rect(4, 2))),\n\
+                           describe(Boxed::Full(Shape::Circle(1))),\n\
+                           describe(Boxed::Nothing),\n\
+                           name(0), name(-1), name(7),\n\
+                       ]\n\
+                   }";
+        assert_eq!(
+            run(src).unwrap(),
+            "[thin 4, area 8, circle, nothing, zero, minus one, many]"
+        );
+        assert_eq!(
+            run("enum Opt { Some(v), None }\nfn main() { [Opt::Some(1), Opt::None] }").unwrap(),
+            "[Opt::Some(1), Opt::None]"
+        );
+    }
+
+    #[test]
+    fn first_class_functions() {
+        let src = "fn apply(f, x) { f(x) }\n\
+                   fn twice(f) { |x| f(f(x)) }\n\
+                   fn make_adder(n) { |x| x + n }\n\
+                   fn inc(x) { x + 1 }\n\
+                   fn main() {\n\
+                       let fs = [inc, make_adder(2)];\n\
+                       let early = |x| { if x > 0 { return 1 }; 2 };\n\
+                       [apply(inc, 41), apply(|x| x * 2, 21), twice(twice(make_adder(3)))(0), fs[1](1), early(1), early(0)]\n\
+                   }";
+        assert_eq!(run(src).unwrap(), "[42, 42, 12, 3, 1, 2]");
+        assert_eq!(run("fn main() { let f = |x| x; f }").unwrap(), "<closure>");
+    }
+
+    #[test]
+    fn closures_share_the_variables_they_capture() {
+        let src = "fn counter(start) { let n = start; || { n = n + 1; n } }\n\
+                   fn main() {\n\
+                       let c = 0;\n\
+                       let inc = || { c = c + 1; c };\n\
+                       let out = [inc(), inc(), c];\n\
+                       c = 10;\n\
+                       push(out, inc());\n\
+                       let a = counter(5);\n\
+                       let b = counter(100);\n\
+                       push(out, a()); push(out, a()); push(out, b());\n\
+                       let outer = 1;\n\
+                       let mk = || { || { outer = outer + 1; outer } };\n\
+                       let g = mk();\n\
+                       g();\n\
+                       push(out, outer);\n\
+                       out\n\
+                   }";
+        assert_eq!(run(src).unwrap(), "[1, 2, 2, 11, 6, 7, 101, 2]");
+        // Every iteration has its own loop variable
+        assert_eq!(
+            run("fn main() {\n\
+                 let fs = [];\n\
+                 for i in 0..3 { push(fs, || i * 2) };\n\
+                 let out = [];\n\
+                 for f in fs { push(out, f()) };\n\
+                 out\n\
+             }")
+            .unwrap(),
+            "[0, 2, 4]"
+        );
+    }
+
+    #[test]
+    fn constants_and_globals() {
+        let src = "const STEP = 2;\n\
+                   global COUNT = 0\n\
+                   global SEEN = [STEP]\n\
+                   fn bump() { COUNT = COUNT + STEP; push(SEEN, COUNT); COUNT }\n\
+                   fn main() { bump(); push(SEEN, bump()); SEEN }";
+        assert_eq!(run(src).unwrap(), "[2, 2, 4, 4]");
+        // Every use of a constant list gets its own copy
+        assert_eq!(
+            run("const L = [1]\nfn main() { let a = L; push(a, 2); [a, L] }").unwrap(),
+            "[[1, 2], [1]]"
+        );
+    }
+
+    #[test]
+    fn tuples() {
+        let src = "fn divmod(a, b) { ((a - a % b) / b, a % b) }\n\
+                   fn main() {\n\
+                       let (q, r) = divmod(17, 5);\n\
+                       let t = (q, (r,), \"x\");\n\
+                       let (a, b, c) = t;\n\
+                       let (r2,) = b;\n\
+                       (a + r2, b, c)\n\
+                   }";
+        assert_eq!(run(src).unwrap(), "(5, (2,), x)");
+    }
+
+    #[test]
+    fn maps() {
+        let src = "fn count(words: [str]) -> {str: num} {\n\
+                       let m = {};\n\
+                       for w in words { if contains(m, w) { m[w] = m[w] + 1 } else { m[w] = 1 } };\n\
+                       m\n\
+                   }\n\
+                   fn main() {\n\
+                       let m = {\"b\": 2, \"a\": 1,};\n\
+                       m[\"c\"] = 3;\n\
+                       m[\"a\"] = 10;\n\
+                       let seen = [];\n\
+                       for k in m { push(seen, \"{k}={m[k]}\") };\n\
+                       let removed = remove(m, \"b\");\n\
+                       (seen, removed, keys(m), contains(m, \"b\"), count([\"x\", \"y\", \"x\"]))\n\
+                   }";
+        assert_eq!(
+            run(src).unwrap(),
+            "([a=10, b=2, c=3], 2, [a, c], false, {x: 2, y: 1})"
+        );
+        // Maps are shared like lists, but constants are copied on every use
+        assert_eq!(
+            run("const D = {1: true}\nfn main() { let d = D; let e = d; e[2] = false; (d, D) }")
+                .unwrap(),
+            "({1: true, 2: false}, {1: true})"
+        );
+    }
+
+    #[test]
+    fn map_errors() {
+        assert_eq!(
+            run("fn main() { let m = {\"a\": 1}; m[\"b\"] }").unwrap_err(),
+            "Key 'b' not found in map"
+        );
+        assert_eq!(
+            run("fn main() { let m = {\"a\": 1}; remove(m, \"q\") }").unwrap_err(),
+            "Key 'q' not found in map"
+        );
+        assert_eq!(
+            run("fn main() { let m = {[1]: 1}; 0 }").unwrap_err(),
+            "Map keys must be strings, numbers or booleans, found '[1]'"
+        );
+    }
+
+    #[test]
+    fn null_values() {
+        assert_eq!(
+            run("fn main() { let x = null; (x, x == null, [null, null], print(null)) }").unwrap(),
+            "(null, true, [null, null], null)"
+        );
+        // `if` without `else`, `push` and a conditional loop that runs out produce null
+        assert_eq!(
+            run("fn main() { let xs = []; ((if false { 1 }), push(xs, 1), loop false { 1 }) }")
+                .unwrap(),
+            "(null, null, null)"
+        );
+        assert_eq!(
+            run(
+                "fn describe(x) { match x { null => \"nothing\", _ => \"something\" } }\n\
+                 fn main() { describe(null) }"
+            )
+            .unwrap(),
+            "nothing"
+        );
+    }
+}
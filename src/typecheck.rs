@@ -0,0 +1,1117 @@
+use crate::{
+    diagnostic::Diagnostic,
+    globals::order,
+    parser::{
+        destructure_error, tuple_items, BinaryOp, Expr, Func, Pattern, Program, Span, Spanned,
+        Type, UnaryOp, Value, BUILTINS,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// A type during inference. `Var`s are placeholders that get bound as the program constrains them.
+#[derive(Clone, Debug, PartialEq)]
+enum Ty {
+    Var(usize),
+    Null,
+    Bool,
+    Num,
+    Str,
+    List(Box<Ty>),
+    Tuple(Vec<Ty>),
+    Map(Box<Ty>, Box<Ty>),
+    Fn(Vec<Ty>, Box<Ty>),
+    Struct(String),
+    Enum(String),
+}
+
+impl From<&Type> for Ty {
+    fn from(ty: &Type) -> Self {
+        match ty {
+            Type::Null => Ty::Null,
+            Type::Bool => Ty::Bool,
+            Type::Num => Ty::Num,
+            Type::Str => Ty::Str,
+            Type::List(t) => Ty::List(Box::new(t.as_ref().into())),
+            Type::Map(k, v) => Ty::Map(Box::new(k.as_ref().into()), Box::new(v.as_ref().into())),
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ty::Var(_) => write!(f, "_"),
+            Ty::Null => write!(f, "null"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Num => write!(f, "num"),
+            Ty::Str => write!(f, "str"),
+            Ty::List(t) => write!(f, "[{}]", t),
+            Ty::Tuple(items) => write!(f, "({})", tuple_items(items)),
+            Ty::Map(k, v) => write!(f, "{{{}: {}}}", k, v),
+            Ty::Struct(name) | Ty::Enum(name) => write!(f, "{}", name),
+            Ty::Fn(args, ret) => write!(
+                f,
+                "fn({}) -> {}",
+                args.iter()
+                    .map(|a| a.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                ret
+            ),
+        }
+    }
+}
+
+impl Ty {
+    fn free_vars(&self, out: &mut Vec<usize>) {
+        match self {
+            Ty::Var(v) if !out.contains(v) => out.push(*v),
+            Ty::List(t) => t.free_vars(out),
+            Ty::Tuple(items) => items.iter().for_each(|t| t.free_vars(out)),
+            Ty::Map(k, v) => {
+                k.free_vars(out);
+                v.free_vars(out)
+            }
+            Ty::Fn(args, ret) => {
+                args.iter().for_each(|a| a.free_vars(out));
+                ret.free_vars(out)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A function's type with the variables that get replaced by fresh ones on every use
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Ty,
+}
+
+struct Infer<'a> {
+    funcs: &'a HashMap<String, Func>,
+    /// Type of every field of every struct, shared by all functions
+    fields: HashMap<String, Vec<(String, Ty)>>,
+    /// Types of the payload of every variant of every enum, shared like the fields
+    variants: HashMap<String, Vec<(String, Vec<Ty>)>>,
+    /// Type of every constant and global with its value, shared like the fields
+    globals: HashMap<String, (Ty, Span)>,
+    /// Binding of every type variable, together with the expression that caused it
+    subst: Vec<Option<(Ty, Span)>>,
+    /// Functions that are fully inferred and may be used at different types
+    schemes: HashMap<String, Scheme>,
+    /// Functions of the group that is currently inferred, they are monomorphic until the group is done
+    mono: HashMap<String, Ty>,
+    /// Per function and argument, the place that decided the argument's type
+    arg_origins: HashMap<String, Vec<Option<Span>>>,
+    /// Return type of the function being inferred and where it was declared
+    ret: (Ty, Option<Span>),
+    /// Variables in scope with the place they were declared
+    vars: Vec<(String, Ty, Span)>,
+    /// Result types of the loops around the current expression, innermost last
+    loops: Vec<Ty>,
+    errs: Vec<Diagnostic>,
+}
+
+impl Infer<'_> {
+    fn fresh(&mut self) -> Ty {
+        self.subst.push(None);
+        Ty::Var(self.subst.len() - 1)
+    }
+
+    /// Follows variable bindings until the outermost constructor is known
+    fn resolve(&self, ty: &Ty) -> Ty {
+        match ty {
+            Ty::Var(v) => match &self.subst[*v] {
+                Some((ty, _)) => self.resolve(ty),
+                None => ty.clone(),
+            },
+            ty => ty.clone(),
+        }
+    }
+
+    /// Resolves all variables inside a type
+    fn zonk(&self, ty: &Ty) -> Ty {
+        match self.resolve(ty) {
+            Ty::List(t) => Ty::List(Box::new(self.zonk(&t))),
+            Ty::Tuple(items) => Ty::Tuple(items.iter().map(|t| self.zonk(t)).collect()),
+            Ty::Map(k, v) => Ty::Map(Box::new(self.zonk(&k)), Box::new(self.zonk(&v))),
+            Ty::Fn(args, ret) => Ty::Fn(
+                args.iter().map(|a| self.zonk(a)).collect(),
+                Box::new(self.zonk(&ret)),
+            ),
+            ty => ty,
+        }
+    }
+
+    /// Where a variable got its type from, if it is a variable that got bound at all
+    fn origin(&self, ty: &Ty) -> Option<Span> {
+        match ty {
+            Ty::Var(v) => match &self.subst[*v] {
+                Some((ty @ Ty::Var(_), span)) => self.origin(ty).or(Some(span.clone())),
+                Some((_, span)) => Some(span.clone()),
+                None => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn occurs(&self, v: usize, ty: &Ty) -> bool {
+        match self.resolve(ty) {
+            Ty::Var(w) => v == w,
+            Ty::List(t) => self.occurs(v, &t),
+            Ty::Tuple(items) => items.iter().any(|t| self.occurs(v, t)),
+            Ty::Map(k, val) => self.occurs(v, &k) || self.occurs(v, &val),
+            Ty::Fn(args, ret) => args.iter().any(|a| self.occurs(v, a)) || self.occurs(v, &ret),
+            _ => false,
+        }
+    }
+
+    /// Makes two types equal, binding variables at `span`. `null` is a type of its own, like any other.
+    fn unify(&mut self, a: &Ty, b: &Ty, span: &Span) -> bool {
+        match (self.resolve(a), self.resolve(b)) {
+            (Ty::Var(x), Ty::Var(y)) if x == y => true,
+            (Ty::Var(v), ty) | (ty, Ty::Var(v)) => {
+                if self.occurs(v, &ty) {
+                    return false;
+                }
+                self.subst[v] = Some((ty, span.clone()));
+                true
+            }
+            (Ty::List(a), Ty::List(b)) => self.unify(&a, &b, span),
+            (Ty::Tuple(a), Ty::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(&b).all(|(a, b)| self.unify(a, b, span))
+            }
+            (Ty::Map(a_key, a_val), Ty::Map(b_key, b_val)) => {
+                self.unify(&a_key, &b_key, span) && self.unify(&a_val, &b_val, span)
+            }
+            (Ty::Fn(a_args, a_ret), Ty::Fn(b_args, b_ret)) => {
+                a_args.len() == b_args.len()
+                    && a_args
+                        .iter()
+                        .zip(&b_args)
+                        .all(|(a, b)| self.unify(a, b, span))
+                    && self.unify(&a_ret, &b_ret, span)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Ty {
+        let fresh: HashMap<usize, Ty> = scheme.vars.iter().map(|v| (*v, self.fresh())).collect();
+        fn replace(ty: &Ty, fresh: &HashMap<usize, Ty>) -> Ty {
+            match ty {
+                Ty::Var(v) => fresh.get(v).cloned().unwrap_or(Ty::Var(*v)),
+                Ty::List(t) => Ty::List(Box::new(replace(t, fresh))),
+                Ty::Tuple(items) => Ty::Tuple(items.iter().map(|t| replace(t, fresh)).collect()),
+                Ty::Map(k, v) => Ty::Map(Box::new(replace(k, fresh)), Box::new(replace(v, fresh))),
+                Ty::Fn(args, ret) => Ty::Fn(
+                    args.iter().map(|a| replace(a, fresh)).collect(),
+                    Box::new(replace(ret, fresh)),
+                ),
+                ty => ty.clone(),
+            }
+        }
+        replace(&scheme.ty, &fresh)
+    }
+
+    /// Infers `expr` and requires it to have type `expected`. `origin` is the place the expectation comes from,
+    /// used when the expected type doesn't remember where it was decided.
+    fn expect(&mut self, expr: &Spanned<Expr>, expected: &Ty, origin: Option<Span>) -> Ty {
+        let found = self.infer(expr);
+        self.require(expr, found, expected, origin)
+    }
+
+    /// Like `expect`, for an expression whose type `found` is already inferred
+    fn require(
+        &mut self,
+        expr: &Spanned<Expr>,
+        found: Ty,
+        expected: &Ty,
+        origin: Option<Span>,
+    ) -> Ty {
+        if !self.unify(&found, expected, &expr.1) {
+            let found = self.zonk(&found);
+            let origin = self.origin(expected).or(origin);
+            let expected = self.zonk(expected);
+            let mut diag = Diagnostic::new(
+                expr.1.clone(),
+                format!("Type mismatch: expected {}, found {}", expected, found),
+            )
+            .with_label(expr.1.clone(), format!("This is {}", found));
+            if let Some(origin) = origin.filter(|origin| *origin != expr.1) {
+                diag = diag.with_label(origin, format!("Expected {} because of this", expected));
+            }
+            self.errs.push(diag);
+        }
+        found
+    }
+
+    fn var(&self, name: &str) -> Option<(Ty, Span)> {
+        self.vars
+            .iter()
+            .rev()
+            .find(|(var, _, _)| var == name)
+            .map(|(_, ty, span)| (ty.clone(), span.clone()))
+            .or_else(|| self.globals.get(name).cloned())
+    }
+
+    /// The type of a named function, instantiated if it is already generalised
+    fn func(&mut self, name: &str) -> Option<Ty> {
+        if let Some(scheme) = self.schemes.get(name).cloned() {
+            Some(self.instantiate(&scheme))
+        } else {
+            self.mono.get(name).cloned()
+        }
+    }
+
+    /// The type of `obj.field`. If `obj` isn't known to be a struct yet, the field name picks the struct
+    /// when exactly one struct has a field of that name.
+    fn field(&mut self, obj: &Spanned<Expr>, (field, field_span): &Spanned<String>) -> Ty {
+        let obj_ty = self.infer(obj);
+        let name = match self.resolve(&obj_ty) {
+            Ty::Struct(name) => name,
+            Ty::Var(_) => {
+                let mut owners = self
+                    .fields
+                    .iter()
+                    .filter(|(_, fields)| fields.iter().any(|(f, _)| f == field));
+                match (owners.next(), owners.next()) {
+                    (Some((name, _)), None) => {
+                        let name = name.clone();
+                        self.unify(&obj_ty, &Ty::Struct(name.clone()), &obj.1);
+                        name
+                    }
+                    _ => return self.fresh(),
+                }
+            }
+            found => {
+                self.errs.push(
+                    Diagnostic::new(
+                        obj.1.clone(),
+                        format!(
+                            "Type mismatch: expected a struct, found {}",
+                            self.zonk(&found)
+                        ),
+                    )
+                    .with_label(obj.1.clone(), format!("This is {}", self.zonk(&found)))
+                    .with_label(field_span.clone(), "Field accessed here"),
+                );
+                return self.fresh();
+            }
+        };
+        match self.fields[&name].iter().find(|(f, _)| f == field) {
+            Some((_, ty)) => ty.clone(),
+            None => {
+                self.errs.push(
+                    Diagnostic::new(
+                        field_span.clone(),
+                        format!("Struct '{}' has no field '{}'", name, field),
+                    )
+                    .with_label(field_span.clone(), "Unknown field")
+                    .with_label(obj.1.clone(), format!("This is {}", name)),
+                );
+                self.fresh()
+            }
+        }
+    }
+
+    /// The type of `container[index]`. Containers known to be maps are indexed by key, anything else is
+    /// taken to be a list, so arguments that are indexed as maps need a type annotation.
+    fn index(
+        &mut self,
+        container: &Spanned<Expr>,
+        index: &Spanned<Expr>,
+        origin: Option<Span>,
+    ) -> Ty {
+        let found = self.infer(container);
+        match self.resolve(&found) {
+            Ty::Map(key, val) => {
+                self.expect(index, &key, Some(container.1.clone()));
+                *val
+            }
+            _ => {
+                let item_ty = self.fresh();
+                self.require(
+                    container,
+                    found,
+                    &Ty::List(Box::new(item_ty.clone())),
+                    origin.clone(),
+                );
+                self.expect(index, &Ty::Num, origin);
+                item_ty
+            }
+        }
+    }
+
+    fn payload(&self, name: &str, variant: &str) -> Vec<Ty> {
+        self.variants[name]
+            .iter()
+            .find(|(v, _)| v == variant)
+            .map(|(_, tys)| tys.clone())
+            .expect("variants are resolved before type checking")
+    }
+
+    /// Checks that a pattern can match values of type `ty` and brings the variables it binds into scope.
+    /// `origin` is the matched expression.
+    fn pattern(&mut self, (pat, span): &Spanned<Pattern>, ty: &Ty, origin: &Span) {
+        let found = match pat {
+            Pattern::Wildcard => return,
+            Pattern::Bind(name) => {
+                self.vars.push((name.clone(), ty.clone(), span.clone()));
+                return;
+            }
+            Pattern::Value(val) => self.value(val),
+            Pattern::Variant((name, _), _, _) => Ty::Enum(name.clone()),
+        };
+        if !self.unify(&found, ty, span) {
+            let found = self.zonk(&found);
+            let expected = self.zonk(ty);
+            self.errs.push(
+                Diagnostic::new(
+                    span.clone(),
+                    format!("Type mismatch: expected {}, found {}", expected, found),
+                )
+                .with_label(span.clone(), format!("This pattern is {}", found))
+                .with_label(origin.clone(), format!("This is {}", expected)),
+            );
+        }
+        if let Pattern::Variant((name, _), (variant, _), pats) = pat {
+            for (pat, ty) in pats.iter().zip(self.payload(name, variant)) {
+                self.pattern(pat, &ty, origin);
+            }
+        }
+    }
+
+    fn infer(&mut self, expr: &Spanned<Expr>) -> Ty {
+        match &expr.0 {
+            Expr::Error => self.fresh(),
+            Expr::Value(val) => self.value(val),
+            Expr::List(items) => {
+                let item_ty = self.fresh();
+                let origin = items.first().map(|item| item.1.clone());
+                for item in items {
+                    self.expect(item, &item_ty, origin.clone());
+                }
+                Ty::List(Box::new(item_ty))
+            }
+            Expr::LocalVar(name) => match self.var(name) {
+                Some((ty, _)) => ty,
+                None => match self.func(name) {
+                    Some(ty) => ty,
+                    None => self.fresh(),
+                },
+            },
+            Expr::Let(name, val, body) => {
+                let ty = self.infer(val);
+                self.vars.push((name.clone(), ty, val.1.clone()));
+                let ty = self.infer(body);
+                self.vars.pop();
+                ty
+            }
+            Expr::Tuple(items) => Ty::Tuple(items.iter().map(|item| self.infer(item)).collect()),
+            // Like lists, every key and every value has the type of the first one
+            Expr::Map(entries) => {
+                let key_ty = self.fresh();
+                let val_ty = self.fresh();
+                let (key_origin, val_origin) = match entries.first() {
+                    Some((key, val)) => (Some(key.1.clone()), Some(val.1.clone())),
+                    None => (None, None),
+                };
+                for (key, val) in entries {
+                    self.expect(key, &key_ty, key_origin.clone());
+                    self.expect(val, &val_ty, val_origin.clone());
+                }
+                Ty::Map(Box::new(key_ty), Box::new(val_ty))
+            }
+            Expr::Destructure(names, val, body) => {
+                let found = self.infer(val);
+                let items: Vec<Ty> = names.iter().map(|_| self.fresh()).collect();
+                let expected = Ty::Tuple(items.clone());
+                if !self.unify(&found, &expected, &val.1) {
+                    let found = self.zonk(&found);
+                    let msg = match &found {
+                        Ty::Tuple(vals) => destructure_error("", Some(vals.len()), names.len()),
+                        found => format!(
+                            "Type mismatch: expected {}, found {}",
+                            self.zonk(&expected),
+                            found
+                        ),
+                    };
+                    self.errs.push(
+                        Diagnostic::new(val.1.clone(), msg)
+                            .with_label(val.1.clone(), format!("This is {}", found)),
+                    );
+                }
+                for ((name, span), ty) in names.iter().zip(items) {
+                    self.vars.push((name.clone(), ty, span.clone()));
+                }
+                let ty = self.infer(body);
+                self.vars.truncate(self.vars.len() - names.len());
+                ty
+            }
+            Expr::Assign(name, val, body) => {
+                match self.var(name) {
+                    Some((ty, decl)) => self.expect(val, &ty, Some(decl)),
+                    None => self.infer(val),
+                };
+                self.infer(body)
+            }
+            Expr::Return(val) => {
+                let (ret, origin) = self.ret.clone();
+                self.expect(val, &ret, origin);
+                // `return` never produces a value where it stands, so it fits anywhere
+                self.fresh()
+            }
+            Expr::Then(a, b) => {
+                self.infer(a);
+                self.infer(b)
+            }
+            Expr::Binary(a, op, b) => {
+                let origin = Some(expr.1.clone());
+                match op {
+                    BinaryOp::Add
+                    | BinaryOp::Sub
+                    | BinaryOp::Mul
+                    | BinaryOp::Div
+                    | BinaryOp::Mod
+                    | BinaryOp::BitAnd
+                    | BinaryOp::BitOr
+                    | BinaryOp::BitXor
+                    | BinaryOp::Shl
+                    | BinaryOp::Shr => {
+                        self.expect(a, &Ty::Num, origin.clone());
+                        self.expect(b, &Ty::Num, origin);
+                        Ty::Num
+                    }
+                    BinaryOp::LowerT
+                    | BinaryOp::GreaterT
+                    | BinaryOp::LowerEq
+                    | BinaryOp::GreaterEq => {
+                        self.expect(a, &Ty::Num, origin.clone());
+                        self.expect(b, &Ty::Num, origin);
+                        Ty::Bool
+                    }
+                    BinaryOp::And | BinaryOp::Or => {
+                        self.expect(a, &Ty::Bool, origin.clone());
+                        self.expect(b, &Ty::Bool, origin);
+                        Ty::Bool
+                    }
+                    BinaryOp::Eq | BinaryOp::NotEq => {
+                        let ty = self.infer(a);
+                        self.expect(b, &ty, Some(a.1.clone()));
+                        Ty::Bool
+                    }
+                    BinaryOp::Concat => {
+                        self.infer(a);
+                        self.infer(b);
+                        Ty::Str
+                    }
+                    BinaryOp::ListAt => self.index(a, b, origin),
+                }
+            }
+            Expr::Unary(UnaryOp::Not, a) => {
+                self.expect(a, &Ty::Bool, Some(expr.1.clone()));
+                Ty::Bool
+            }
+            Expr::Unary(UnaryOp::Neg | UnaryOp::BitNot, a) => {
+                self.expect(a, &Ty::Num, Some(expr.1.clone()));
+                Ty::Num
+            }
+            Expr::Call(func, (args, _)) => {
+                // Calls of named functions check every argument on its own, so mismatches point at the argument
+                if let Expr::LocalVar(name) = &func.0 {
+                    if self.var(name).is_none() {
+                        if let Some(Ty::Fn(params, ret)) = self.func(name) {
+                            let origins = self.arg_origins.get(name).cloned().unwrap_or_default();
+                            for (i, arg) in args.iter().enumerate() {
+                                match params.get(i) {
+                                    Some(param) => {
+                                        let origin = origins.get(i).cloned().flatten();
+                                        self.expect(arg, param, origin)
+                                    }
+                                    None => self.infer(arg),
+                                };
+                            }
+                            return *ret;
+                        }
+                    }
+                }
+                let args = args.iter().map(|arg| self.infer(arg)).collect();
+                let ret = self.fresh();
+                let expected = Ty::Fn(args, Box::new(ret.clone()));
+                self.expect(func, &expected, None);
+                ret
+            }
+            // An `if` with a null branch, like one without `else`, is only run for its effects and is null
+            Expr::If(cond, a, b) => {
+                self.expect(cond, &Ty::Bool, Some(expr.1.clone()));
+                let a_ty = self.infer(a);
+                let b_ty = self.infer(b);
+                match (self.resolve(&a_ty), self.resolve(&b_ty)) {
+                    (Ty::Null, _) | (_, Ty::Null) => Ty::Null,
+                    _ => self.require(b, b_ty, &a_ty, Some(a.1.clone())),
+                }
+            }
+            // Loops with a condition produce null when it turns false, so only a loop without one has the
+            // type of its `break` values
+            Expr::Loop(cond, body) => {
+                self.expect(cond, &Ty::Bool, Some(expr.1.clone()));
+                let ty = self.fresh();
+                self.loops.push(ty);
+                self.infer(body);
+                let ty = self.loops.pop().unwrap();
+                match cond.0 {
+                    Expr::Value(Value::Bool(true)) => ty,
+                    _ => Ty::Null,
+                }
+            }
+            Expr::For(name, items, body) => {
+                // Maps are iterated by key
+                let found = self.infer(items);
+                let item_ty = match self.resolve(&found) {
+                    Ty::Map(key, _) => *key,
+                    _ => {
+                        let item_ty = self.fresh();
+                        self.require(items, found, &Ty::List(Box::new(item_ty.clone())), None);
+                        item_ty
+                    }
+                };
+                self.vars.push((name.clone(), item_ty, items.1.clone()));
+                let ty = self.fresh();
+                self.loops.push(ty);
+                self.infer(body);
+                self.vars.pop();
+                // Like loops with a condition, `for` produces null once the items run out
+                self.loops.pop();
+                Ty::Null
+            }
+            // Ranges iterate like lists of numbers
+            Expr::Range(start, end, _) => {
+                let origin = Some(expr.1.clone());
+                self.expect(start, &Ty::Num, origin.clone());
+                self.expect(end, &Ty::Num, origin);
+                Ty::List(Box::new(Ty::Num))
+            }
+            Expr::Break(val) => {
+                let ty = self.loops.last().cloned().unwrap_or(Ty::Null);
+                self.expect(val, &ty, None);
+                self.fresh()
+            }
+            Expr::Continue => self.fresh(),
+            Expr::Path(..) => unreachable!("paths are replaced when modules are merged"),
+            Expr::Print(a) => self.infer(a),
+            Expr::SetIndex(list, index, val) => {
+                let item_ty = self.index(list, index, Some(expr.1.clone()));
+                self.expect(val, &item_ty, Some(list.1.clone()));
+                Ty::Null
+            }
+            Expr::Struct((name, _), fields) => {
+                for ((field, field_span), val) in fields {
+                    let field_ty = self.fields[name]
+                        .iter()
+                        .find(|(f, _)| f == field)
+                        .map(|(_, ty)| ty.clone())
+                        .unwrap();
+                    self.expect(val, &field_ty, Some(field_span.clone()));
+                }
+                Ty::Struct(name.clone())
+            }
+            Expr::Variant((name, _), (variant, _), vals) => {
+                for (val, ty) in vals.iter().zip(self.payload(name, variant)) {
+                    self.expect(val, &ty, None);
+                }
+                Ty::Enum(name.clone())
+            }
+            // Every arm has to produce the same type, decided by the first one
+            Expr::Match(val, arms) => {
+                let val_ty = self.infer(val);
+                let ty = self.fresh();
+                let origin = arms.first().map(|(_, body)| body.1.clone());
+                for (pat, body) in arms {
+                    let depth = self.vars.len();
+                    self.pattern(pat, &val_ty, &val.1);
+                    self.expect(body, &ty, origin.clone());
+                    self.vars.truncate(depth);
+                }
+                ty
+            }
+            // Closures are typed like functions, but aren't generalised: every use shares the same type
+            Expr::Closure(args, body) => {
+                let params: Vec<Ty> = args.iter().map(|_| self.fresh()).collect();
+                for (arg, ty) in args.iter().zip(&params) {
+                    self.vars.push((arg.clone(), ty.clone(), expr.1.clone()));
+                }
+                let ret = self.fresh();
+                let outer_ret = std::mem::replace(&mut self.ret, (ret.clone(), None));
+                let loops = std::mem::take(&mut self.loops);
+                let body_ty = self.infer(body);
+                if !always_returns(body) {
+                    self.unify(&body_ty, &ret, &body.1);
+                }
+                self.loops = loops;
+                self.ret = outer_ret;
+                self.vars.truncate(self.vars.len() - args.len());
+                Ty::Fn(params, Box::new(ret))
+            }
+            Expr::Field(obj, field) => self.field(obj, field),
+            Expr::SetField(obj, field, val) => {
+                let field_ty = self.field(obj, field);
+                self.expect(val, &field_ty, Some(field.1.clone()));
+                Ty::Null
+            }
+        }
+    }
+
+    fn value(&mut self, val: &Value) -> Ty {
+        match val {
+            Value::Null => Ty::Null,
+            Value::Bool(_) => Ty::Bool,
+            Value::Int(_) | Value::Num(_) => Ty::Num,
+            Value::Str(_) => Ty::Str,
+            Value::List(items) => {
+                let item_ty = match items.borrow().first() {
+                    Some(item) => self.value(item),
+                    None => self.fresh(),
+                };
+                Ty::List(Box::new(item_ty))
+            }
+            Value::Func(name) => self.func(name).unwrap_or_else(|| self.fresh()),
+            Value::Struct(name, _) => Ty::Struct(name.clone()),
+            Value::Variant(name, _, _) => Ty::Enum(name.clone()),
+            Value::Tuple(vals) => Ty::Tuple(vals.iter().map(|val| self.value(val)).collect()),
+            Value::Map(entries) => {
+                let (key_ty, val_ty) = match entries.borrow().iter().next() {
+                    Some((key, val)) => (self.value(&key.into()), self.value(val)),
+                    None => (self.fresh(), self.fresh()),
+                };
+                Ty::Map(Box::new(key_ty), Box::new(val_ty))
+            }
+            Value::Closure(_) => self.fresh(),
+        }
+    }
+
+    /// Infers a group of mutually recursive functions and generalises their types
+    fn infer_group(&mut self, group: &[&String]) {
+        for name in group {
+            let func = &self.funcs[*name];
+            let args = func
+                .arg_types
+                .iter()
+                .map(|ty| match ty {
+                    Some((ty, _)) => ty.into(),
+                    None => self.fresh(),
+                })
+                .collect();
+            let ret = match &func.ret_type {
+                Some((ty, _)) => ty.into(),
+                None => self.fresh(),
+            };
+            self.mono
+                .insert((*name).clone(), Ty::Fn(args, Box::new(ret)));
+        }
+        for name in group {
+            let func = &self.funcs[*name];
+            let Some(Ty::Fn(args, ret)) = self.mono.get(*name).cloned() else {
+                unreachable!()
+            };
+            self.ret = (
+                *ret.clone(),
+                func.ret_type.as_ref().map(|(_, span)| span.clone()),
+            );
+            self.vars = func
+                .args
+                .iter()
+                .zip(&args)
+                .zip(&func.arg_types)
+                .map(|((arg, ty), ann)| {
+                    let span = ann.as_ref().map_or(func.body.1.clone(), |(_, s)| s.clone());
+                    (arg.clone(), ty.clone(), span)
+                })
+                .collect();
+            let body = self.infer(&func.body);
+            // Functions produce the value of their body, unless they always leave it through `return`
+            if !always_returns(&func.body) {
+                let origin = self.ret.1.clone();
+                if !self.unify(&body, &ret, &func.body.1) {
+                    let body = self.zonk(&body);
+                    let ret = self.zonk(&ret);
+                    let mut diag = Diagnostic::new(
+                        func.body.1.clone(),
+                        format!(
+                            "Function '{}' should return {}, but its body evaluates to {}",
+                            name, ret, body
+                        ),
+                    )
+                    .with_label(func.body.1.clone(), format!("This is {}", body));
+                    if let Some(origin) = origin {
+                        diag =
+                            diag.with_label(origin, format!("Return type {} declared here", ret));
+                    }
+                    self.errs.push(diag);
+                }
+            }
+        }
+        for name in group {
+            let func = &self.funcs[*name];
+            let ty = self.zonk(&self.mono[*name]);
+            let Ty::Fn(args, _) = &self.mono[*name] else {
+                unreachable!()
+            };
+            let origins = args
+                .iter()
+                .zip(&func.arg_types)
+                .map(|(ty, ann)| match ann {
+                    Some((_, span)) => Some(span.clone()),
+                    None => self.origin(ty),
+                })
+                .collect();
+            self.arg_origins.insert((*name).clone(), origins);
+            // Field, payload and global types are shared by every function, so they must not be generalised
+            let mut shared = Vec::new();
+            let payloads = self.variants.values().flatten().flat_map(|(_, tys)| tys);
+            let globals = self.globals.values().map(|(ty, _)| ty);
+            for ty in self
+                .fields
+                .values()
+                .flatten()
+                .map(|(_, ty)| ty)
+                .chain(payloads)
+                .chain(globals)
+            {
+                self.zonk(ty).free_vars(&mut shared);
+            }
+            let mut vars = Vec::new();
+            ty.free_vars(&mut vars);
+            vars.retain(|v| !shared.contains(v));
+            self.schemes.insert((*name).clone(), Scheme { vars, ty });
+        }
+        self.mono.clear();
+    }
+}
+
+/// Whether evaluating the expression always ends in a `return`, so that its own value is never used
+fn always_returns(expr: &Spanned<Expr>) -> bool {
+    match &expr.0 {
+        Expr::Return(_) => true,
+        Expr::Closure(..) => false,
+        // Only the parts that are evaluated every time count
+        Expr::If(cond, a, b) => always_returns(cond) || (always_returns(a) && always_returns(b)),
+        Expr::Match(val, arms) => {
+            always_returns(val)
+                || (!arms.is_empty() && arms.iter().all(|(_, body)| always_returns(body)))
+        }
+        Expr::Binary(a, BinaryOp::And | BinaryOp::Or, _) => always_returns(a),
+        Expr::Loop(cond, _) => always_returns(cond),
+        Expr::For(_, items, _) => always_returns(items),
+        expr => expr.children().into_iter().any(always_returns),
+    }
+}
+
+fn referenced_funcs<'a>(
+    expr: &Spanned<Expr>,
+    funcs: &'a HashMap<String, Func>,
+    out: &mut Vec<&'a String>,
+) {
+    if let Expr::LocalVar(name) = &expr.0 {
+        if let Some((name, _)) = funcs.get_key_value(name) {
+            out.push(name);
+        }
+    }
+    for child in expr.0.children() {
+        referenced_funcs(child, funcs, out);
+    }
+}
+
+/// Groups functions into strongly connected components of the call graph (Tarjan's algorithm).
+/// Callees come before their callers, so every group can be generalised before it is used.
+fn call_groups(funcs: &HashMap<String, Func>) -> Vec<Vec<&String>> {
+    struct State<'a> {
+        edges: HashMap<&'a String, Vec<&'a String>>,
+        index: HashMap<&'a String, usize>,
+        low: HashMap<&'a String, usize>,
+        stack: Vec<&'a String>,
+        on_stack: HashSet<&'a String>,
+        groups: Vec<Vec<&'a String>>,
+    }
+
+    fn visit<'a>(state: &mut State<'a>, name: &'a String) {
+        let index = state.index.len();
+        state.index.insert(name, index);
+        state.low.insert(name, index);
+        state.stack.push(name);
+        state.on_stack.insert(name);
+        for callee in state.edges[name].clone() {
+            if !state.index.contains_key(callee) {
+                visit(state, callee);
+                let low = state.low[name].min(state.low[callee]);
+                state.low.insert(name, low);
+            } else if state.on_stack.contains(callee) {
+                let low = state.low[name].min(state.index[callee]);
+                state.low.insert(name, low);
+            }
+        }
+        if state.low[name] == state.index[name] {
+            let mut group = Vec::new();
+            while let Some(member) = state.stack.pop() {
+                state.on_stack.remove(member);
+                group.push(member);
+                if member == name {
+                    break;
+                }
+            }
+            group.sort();
+            state.groups.push(group);
+        }
+    }
+
+    let mut names: Vec<&String> = funcs.keys().collect();
+    names.sort();
+    let mut state = State {
+        edges: names
+            .iter()
+            .map(|name| {
+                let mut callees = Vec::new();
+                referenced_funcs(&funcs[*name].body, funcs, &mut callees);
+                (*name, callees)
+            })
+            .collect(),
+        index: HashMap::new(),
+        low: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        groups: Vec::new(),
+    };
+    for name in names {
+        if !state.index.contains_key(name) {
+            visit(&mut state, name);
+        }
+    }
+    state.groups
+}
+
+/// Infers the types of all functions Hindley-Milner style, using annotations where there are any.
+/// Returns one error per conflict, ordered by position.
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut infer = Infer {
+        funcs: &program.funcs,
+        fields: HashMap::new(),
+        variants: HashMap::new(),
+        globals: HashMap::new(),
+        subst: Vec::new(),
+        schemes: HashMap::new(),
+        mono: HashMap::new(),
+        arg_origins: HashMap::new(),
+        ret: (Ty::Null, None),
+        vars: Vec::new(),
+        loops: Vec::new(),
+        errs: Vec::new(),
+    };
+    for (name, _) in BUILTINS {
+        let item = infer.fresh();
+        let list = Ty::List(Box::new(item.clone()));
+        // Map builtins use the item as the key
+        let val = infer.fresh();
+        let map = Ty::Map(Box::new(item.clone()), Box::new(val.clone()));
+        let ty = match *name {
+            "len" => Ty::Fn(vec![list], Box::new(Ty::Num)),
+            "push" => Ty::Fn(vec![list, item], Box::new(Ty::Null)),
+            "pop" => Ty::Fn(vec![list], Box::new(item)),
+            "keys" => Ty::Fn(vec![map], Box::new(list)),
+            "contains" => Ty::Fn(vec![map, item], Box::new(Ty::Bool)),
+            "remove" => Ty::Fn(vec![map, item], Box::new(val)),
+            _ => unreachable!(),
+        };
+        let mut vars = Vec::new();
+        ty.free_vars(&mut vars);
+        infer.schemes.insert(name.to_string(), Scheme { vars, ty });
+    }
+    for (name, decl) in &program.structs {
+        let fields = decl
+            .fields
+            .iter()
+            .map(|(field, _)| (field.clone(), infer.fresh()))
+            .collect();
+        infer.fields.insert(name.clone(), fields);
+    }
+    for (name, decl) in &program.enums {
+        let variants = decl
+            .variants
+            .iter()
+            .map(|v| {
+                let tys = v.fields.iter().map(|_| infer.fresh()).collect();
+                (v.name.0.clone(), tys)
+            })
+            .collect();
+        infer.variants.insert(name.clone(), variants);
+    }
+    // Values only use constants declared before them in this order, and no functions
+    for name in order(&program.globals).expect("cycles are rejected by the resolver") {
+        let value = &program.globals[name].value;
+        let ty = infer.infer(value);
+        infer.globals.insert(name.clone(), (ty, value.1.clone()));
+    }
+    for group in call_groups(&program.funcs) {
+        infer.infer_group(&group);
+    }
+    let mut errs = infer.errs;
+    errs.sort_by_key(|e| e.span.start);
+    errs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{modules, resolve};
+
+    /// The messages of the type errors of a program that resolves
+    fn type_errors(src: &str) -> Vec<String> {
+        let loaded = modules::load_files(&[("main.grsp", src)]);
+        assert!(loaded.syntax_errs.is_empty(), "{:?}", loaded.syntax_errs);
+        let program = loaded.program.expect("the program loads");
+        let errs = resolve::resolve(&program);
+        assert!(errs.is_empty(), "{:?}", errs);
+        check(&program).into_iter().map(|diag| diag.msg).collect()
+    }
+
+    fn assert_checks(src: &str) {
+        assert_eq!(type_errors(src), Vec::<String>::new(), "{}", src);
+    }
+
+    #[test]
+    fn infers_unannotated_programs() {
+        // Functions are generalised, so `id` can be used at different types
+        assert_checks("fn id(x) { x }\nfn main() { print(id(1)); print(id(\"a\")); id(true) }");
+        assert_checks(
+            "fn fact(n) { if n <= 1 { 1 } else { n * fact(n - 1) } }\nfn main() { fact(5) }",
+        );
+        // Mutually recursive functions are inferred together
+        assert_checks(
+            "fn even(n) { if n == 0 { true } else { odd(n - 1) } }\n\
+             fn odd(n) { if n == 0 { false } else { even(n - 1) } }\n\
+             fn main() { even(10) }",
+        );
+        assert_checks("fn first(xs) { xs @ 0 }\nfn main() { first([1, 2]) + 1 }");
+        assert_checks(
+            "struct P { x }\n\
+             fn get(p) { p.x }\n\
+             fn main() { let p = P { x: [1, 2] }; get(p)[0] + 1 }",
+        );
+        assert_checks("fn twice(f, x) { f(f(x)) }\nfn main() { twice(|x| x * 2, 3) }");
+        assert_checks("fn main() { let m = {\"a\": 1}; m[\"b\"] = 2; len(keys(m)) + m[\"a\"] }");
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        assert_eq!(
+            type_errors("fn main() { let x = 1; x + true }"),
+            vec!["Type mismatch: expected num, found bool"]
+        );
+        assert_eq!(
+            type_errors("fn inc(x) { x + 1 }\nfn main() { inc(\"a\") }"),
+            vec!["Type mismatch: expected num, found str"]
+        );
+        assert_eq!(
+            type_errors("fn main() { [1, \"a\"] }"),
+            vec!["Type mismatch: expected num, found str"]
+        );
+        assert_eq!(
+            type_errors("fn main() { if 1 { 2 } else { 3 } }"),
+            vec!["Type mismatch: expected bool, found num"]
+        );
+        assert_eq!(
+            type_errors("struct P { x }\nfn main() { let p = P { x: 1 }; p.y }"),
+            vec!["Struct 'P' has no field 'y'"]
+        );
+    }
+
+    #[test]
+    fn annotations_are_checked() {
+        assert_checks(
+            "fn f(x: num, xs: [str]) -> {str: num} { {xs[0]: x} }\nfn main() { f(1, [\"a\"]) }",
+        );
+        assert_eq!(
+            type_errors("fn f(x: str) -> num { x }\nfn main() { f(\"a\") }"),
+            vec!["Function 'f' should return num, but its body evaluates to str"]
+        );
+        assert_eq!(
+            type_errors("fn f(x: str) { x }\nfn main() { f(1) }"),
+            vec!["Type mismatch: expected str, found num"]
+        );
+        assert_eq!(
+            type_errors("fn f() -> num { return true }\nfn main() { f() }"),
+            vec!["Type mismatch: expected num, found bool"]
+        );
+    }
+
+    #[test]
+    fn null_is_its_own_type() {
+        assert_checks("fn main() { let x = null; x == null }");
+        assert_eq!(
+            type_errors("fn main() { let x = null; x + 1 }"),
+            vec!["Type mismatch: expected num, found null"]
+        );
+        assert_eq!(
+            type_errors("fn main() { [1, null] }"),
+            vec!["Type mismatch: expected num, found null"]
+        );
+        assert_eq!(
+            type_errors("struct P { x }\nfn main() { let p = null; p.x }"),
+            vec!["Type mismatch: expected a struct, found null"]
+        );
+        // An `if` without `else` and a loop may produce null
+        assert_eq!(
+            type_errors("fn main() { (if true { 1 }) + 1 }"),
+            vec!["Type mismatch: expected num, found null"]
+        );
+        assert_eq!(
+            type_errors("fn main() { let i = 0; (loop i < 3 { break 1 }) + 1 }"),
+            vec!["Type mismatch: expected num, found null"]
+        );
+        assert_checks("fn main() { (loop { break 1 }) + 1 }");
+    }
+
+    #[test]
+    fn tuples_are_destructured_by_length() {
+        assert_checks("fn f() { (1, \"a\") }\nfn main() { let (n, s) = f(); \"{s}{n + 1}\" }");
+        assert_eq!(
+            type_errors("fn main() { let (a, b) = (1, 2, 3); a }"),
+            vec!["Can't destructure a tuple of 3 values into 2 names"]
+        );
+        assert_eq!(
+            type_errors("fn main() { let (a, b) = [1, 2]; a }"),
+            vec!["Type mismatch: expected (_, _), found [num]"]
+        );
+        assert_eq!(
+            type_errors("fn main() { let (n, s) = (1, \"a\"); n + s }"),
+            vec!["Type mismatch: expected num, found str"]
+        );
+    }
+
+    #[test]
+    fn return_only_counts_where_it_always_happens() {
+        assert_checks(
+            "fn f(x) -> num { if x { return 1 } else { return 2 } }\nfn main() { f(true) }",
+        );
+        assert_checks(
+            "fn f(x) -> num { match x { 0 => return 1, _ => return 2 } }\nfn main() { f(3) }",
+        );
+        assert_eq!(
+            type_errors("fn f(x) -> num { if x { return 1 }; true }\nfn main() { f(true) }"),
+            vec!["Function 'f' should return num, but its body evaluates to bool"]
+        );
+        assert_eq!(
+            type_errors(
+                "fn f(x) -> num { match x { 0 => return 1, _ => 2 }; true }\nfn main() { f(3) }"
+            ),
+            vec!["Function 'f' should return num, but its body evaluates to bool"]
+        );
+        assert_eq!(
+            type_errors("fn f() -> num { let g = || { return 1 }; true }\nfn main() { f() }"),
+            vec!["Function 'f' should return num, but its body evaluates to bool"]
+        );
+    }
+}